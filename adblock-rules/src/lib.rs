@@ -0,0 +1,94 @@
+//! Rule metadata shared by the Spotify adblock library and its tools
+//!
+//! The runtime library is a preload object that interposes libc symbols, so
+//! tools such as `xpui-patcher` cannot link against it. What both sides need
+//! to agree on (where the config file lives and how rules are named and
+//! categorized) lives here instead.
+
+use std::env;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// What a rule is for, so rules of one kind can be reported or disabled
+/// together whether they run in the library or in a tool
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleCategory {
+    /// Audio, video and display ads
+    #[default]
+    Ads,
+    /// Premium upsell prompts and upgrade buttons
+    Upsell,
+    /// Sponsored or promoted content mixed into regular shelves
+    Sponsored,
+    /// Analytics and tracking
+    Tracking,
+}
+
+impl RuleCategory {
+    /// Name as written in the config file
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Ads => "ads",
+            Self::Upsell => "upsell",
+            Self::Sponsored => "sponsored",
+            Self::Tracking => "tracking",
+        }
+    }
+}
+
+/// Fields every named rule carries, flattened into the rule's table
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuleMeta {
+    /// Name used in logs and reports
+    pub name: String,
+    #[serde(default)]
+    pub category: RuleCategory,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+const fn enabled_by_default() -> bool {
+    true
+}
+
+/// Config file locations, in descending order of precedence
+#[must_use]
+pub fn config_paths() -> Vec<PathBuf> {
+    vec![
+        PathBuf::from("config.toml"),
+        env::var("XDG_CONFIG_HOME").map_or_else(
+            |_| {
+                #[allow(deprecated)] // std::env::home_dir() is only broken on Windows
+                env::home_dir().unwrap_or_default().join(".config")
+            },
+            PathBuf::from
+        ).join("spotify-adblock/config.toml"),
+        PathBuf::from("/etc/spotify-adblock/config.toml"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Rules {
+        rules: Vec<RuleMeta>,
+    }
+
+    #[test]
+    fn defaults_category_and_enabled() {
+        let rules: Rules = toml::from_str(
+            "[[rules]]\nname = 'a'\n\n[[rules]]\nname = 'b'\ncategory = 'upsell'\nenabled = false\n",
+        )
+        .unwrap();
+
+        assert_eq!(rules.rules[0].category, RuleCategory::Ads);
+        assert!(rules.rules[0].enabled);
+        assert_eq!(rules.rules[1].category.as_str(), "upsell");
+        assert!(!rules.rules[1].enabled);
+    }
+}
@@ -1,38 +1,456 @@
-use regex::RegexSet;
+use adblock_rules::{config_paths, RuleMeta};
+use regex::{bytes, Regex, RegexSet};
 use serde::Deserialize;
-use std::{env, fs::read_to_string, path::PathBuf, sync::LazyLock};
+use std::{collections::HashMap, env, fs::read_to_string, path::PathBuf, sync::LazyLock};
+
+use crate::hooks::registry;
+use crate::utils::cidr::Cidr;
+use crate::utils::log_filter::{Level, Source};
+use crate::utils::logging;
+use crate::utils::process_role::ProcessRole;
 
 // Constants for fault containment
 const MAX_CONFIG_SIZE: usize = 1024 * 1024; // 1MB limit for config
 
+/// Log debug lines from every source
 pub static DEBUG_MODE: LazyLock<bool> = LazyLock::new(|| env::var("SPOTIFY_ADBLOCK_DEBUG").is_ok());
 
+/// Let every CEF request through, logging it as allowed
+pub static PASSTHROUGH_MODE: LazyLock<bool> = LazyLock::new(|| env::var("SPOTIFY_ADBLOCK_PASSTHROUGH").is_ok());
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(with = "serde_regex")]
     pub allowlist: RegexSet,
     #[serde(with = "serde_regex")]
     pub denylist: RegexSet,
+    /// Patterns matched against CEF request bodies (e.g. event names inside
+    /// gabo event batches); a match blocks the request
+    #[serde(default, with = "serde_regex")]
+    pub body_denylist: Vec<bytes::Regex>,
+    /// Address ranges that `connect` always lets through, even when they
+    /// also match `cidr_denylist`
+    #[serde(default)]
+    pub cidr_allowlist: Vec<Cidr>,
+    /// Address ranges that `connect` refuses, e.g. known ad-server networks
+    #[serde(default)]
+    pub cidr_denylist: Vec<Cidr>,
+    /// URL rewrites applied to CEF requests before they are classified
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRule>,
+    /// Header actions applied to CEF requests, in order
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRule>,
+    /// What blocked CEF requests report back to their caller
+    #[serde(default)]
+    pub blocked_response: BlockedResponse,
+    /// Per-URL overrides of `blocked_response`; the first match wins
+    #[serde(default)]
+    pub blocked_response_rules: Vec<BlockedResponseRule>,
+    /// Elements hidden in xpui frames, by CSS selector
+    #[serde(default)]
+    pub cosmetic_rules: Vec<CosmeticRule>,
+    /// Actions on messages of the dealer WebSocket, in order
+    #[serde(default)]
+    pub websocket_rules: Vec<WebSocketRule>,
+    #[serde(default)]
+    pub quic: QuicConfig,
+    /// Executable names that count as Spotify: hooks only act inside them,
+    /// and only they inherit the library through `LD_PRELOAD`
+    #[serde(default = "default_spotify_executables")]
+    pub spotify_executables: Vec<String>,
+    /// Hook groups active in each process role
+    #[serde(default)]
+    pub process_roles: ProcessRoleHooks,
+    /// What hooks do with calls they cannot evaluate
+    #[serde(default)]
+    pub failure_policy: FailurePolicies,
+    /// Hooks that only forward calls, by function name
+    #[serde(default)]
+    pub disabled_hooks: Vec<String>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Set when the config file could not be used and this is the fallback
+    #[serde(skip)]
+    pub is_fallback: bool,
+}
+
+/// An empty config: nothing allowed or denied, every other table at its
+/// default
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            allowlist: RegexSet::empty(),
+            denylist: RegexSet::empty(),
+            body_denylist: Vec::new(),
+            cidr_allowlist: Vec::new(),
+            cidr_denylist: Vec::new(),
+            rewrite_rules: Vec::new(),
+            header_rules: Vec::new(),
+            blocked_response: BlockedResponse::default(),
+            blocked_response_rules: Vec::new(),
+            cosmetic_rules: Vec::new(),
+            websocket_rules: Vec::new(),
+            quic: QuicConfig::default(),
+            spotify_executables: default_spotify_executables(),
+            process_roles: ProcessRoleHooks::default(),
+            failure_policy: FailurePolicies::default(),
+            disabled_hooks: Vec::new(),
+            logging: LoggingConfig::default(),
+            is_fallback: false,
+        }
+    }
+}
+
+fn default_spotify_executables() -> Vec<String> {
+    vec!["spotify".to_string()]
+}
+
+/// Outcome reported to the client of a blocked `cef_urlrequest_create`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockedResponse {
+    /// 204 with an empty body
+    #[default]
+    NoContent,
+    /// 200 with a `{}` JSON body
+    EmptyJson,
+    /// 200 with an empty protobuf body
+    EmptyProtobuf,
+    /// A failed request (`ERR_BLOCKED_BY_CLIENT`)
+    Failure,
+    /// Return NULL from `cef_urlrequest_create`; the client is never called
+    Null,
+}
+
+/// A `blocked_response` override for matching URLs
+#[derive(Deserialize, Debug)]
+pub struct BlockedResponseRule {
+    #[serde(with = "serde_regex")]
+    pub url: Regex,
+    pub response: BlockedResponse,
+}
+
+/// A URL rewrite, e.g. stripping a tracking redirect prefix
+#[derive(Deserialize, Debug)]
+pub struct RewriteRule {
+    /// Pattern matched against the request URL
+    #[serde(with = "serde_regex")]
+    pub url: Regex,
+    /// Replacement for each match; `$1`/`${name}` expand capture groups
+    pub replacement: String,
+}
+
+/// What a header rule does with a matching header
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderAction {
+    /// Drop every header with this name
+    Remove,
+    /// Replace every header with this name by a single `value`
+    Set,
+    /// Block the request when the header is present (and matches `matches`)
+    Block,
+}
+
+/// A request header rule, e.g. stripping a tracking header
+#[derive(Deserialize, Debug)]
+pub struct HeaderRule {
+    /// Header name, compared case-insensitively
+    pub name: String,
+    pub action: HeaderAction,
+    /// Replacement value for `set`
+    #[serde(default)]
+    pub value: String,
+    /// For `block`, only block when the header value matches this pattern
+    #[serde(default, with = "serde_regex")]
+    pub matches: Option<Regex>,
+    /// Only apply the rule to request URLs matching this pattern
+    #[serde(default, with = "serde_regex")]
+    pub url: Option<Regex>,
+}
+
+/// An element-hiding rule for xpui, e.g. the upgrade button
+#[derive(Deserialize, Debug)]
+pub struct CosmeticRule {
+    /// Name reported in the log, category and whether the rule is enabled
+    #[serde(flatten)]
+    pub meta: RuleMeta,
+    /// CSS selector of the elements to hide
+    pub selector: String,
 }
 
-pub static CONFIG: LazyLock<Config> = LazyLock::new(load_config);
+/// What a WebSocket rule does with a matching message
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebSocketAction {
+    /// Remove the message from the stream; the connection stays open
+    Drop,
+    /// Log the message and let it through
+    Log,
+}
+
+/// Which side of the connection a WebSocket rule inspects
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WebSocketDirection {
+    /// Messages pushed by the server
+    Incoming,
+    /// Messages sent by the client
+    Outgoing,
+    #[default]
+    Both,
+}
+
+/// A rule for JSON messages on the dealer WebSocket
+#[derive(Deserialize, Debug)]
+pub struct WebSocketRule {
+    /// Name reported in the log, category and whether the rule is enabled
+    #[serde(flatten)]
+    pub meta: RuleMeta,
+    pub action: WebSocketAction,
+    #[serde(default)]
+    pub direction: WebSocketDirection,
+    /// Pattern matched against the message `uri` (or `message_ident` of
+    /// requests); any message when absent
+    #[serde(default, with = "serde_regex")]
+    pub uri: Option<Regex>,
+    /// Message `type` (e.g. `message`, `request`); any type when absent
+    #[serde(default, rename = "type")]
+    pub message_type: Option<String>,
+}
+
+/// HTTP/3 suppression so Chromium falls back to TCP+TLS, where the
+/// `SSL_write` hook can see its requests
+#[derive(Deserialize, Debug, Default)]
+pub struct QuicConfig {
+    /// Fail UDP sends and UDP `connect` calls to port 443
+    #[serde(default)]
+    pub block: bool,
+    /// Hosts (or literal addresses) that may keep using QUIC
+    #[serde(default, with = "serde_regex")]
+    pub allowed_hosts: RegexSet,
+}
+
+/// Where log output goes
+#[derive(Deserialize, Debug)]
+pub struct LoggingConfig {
+    /// Write log lines to `file_path`
+    #[serde(default)]
+    pub file: bool,
+    #[serde(default = "default_log_file_path")]
+    pub file_path: PathBuf,
+    /// Size in bytes after which the log file is rotated; 0 never rotates
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Rotated log files kept
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+    /// Keep printing log lines to stdout
+    #[serde(default = "default_stdout")]
+    pub stdout: bool,
+    /// Most detailed level logged
+    #[serde(default = "default_log_level")]
+    pub level: Level,
+    /// Levels overriding `level` for single sources
+    #[serde(default)]
+    pub sources: HashMap<Source, Level>,
+    /// JSON-lines file receiving one event per allow/block decision
+    #[serde(default)]
+    pub events: Option<PathBuf>,
+    /// Mask query values, tokens and Spotify identifiers in log lines and
+    /// events
+    #[serde(default = "default_redact")]
+    pub redact: bool,
+    /// Further patterns whose matches are masked
+    #[serde(default, with = "serde_regex")]
+    pub redact_patterns: Vec<Regex>,
+    /// Seconds in which repeats of an allowed or blocked line are coalesced;
+    /// 0 logs every line
+    #[serde(default = "default_repeat_window")]
+    pub repeat_window: u64,
+    /// Copies of a line logged per `repeat_window`
+    #[serde(default = "default_repeat_limit")]
+    pub repeat_limit: u32,
+    /// `repeat_window` and `repeat_limit` overridden for single sources
+    #[serde(default)]
+    pub repeat_sources: HashMap<Source, RepeatLimits>,
+}
+
+/// Repeat coalescing of one source; unset fields keep the global value
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct RepeatLimits {
+    /// Seconds in which repeats are coalesced; 0 logs every line
+    pub window: Option<u64>,
+    /// Copies of a line logged per window
+    pub limit: Option<u32>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            file: false,
+            file_path: default_log_file_path(),
+            max_file_size: default_max_file_size(),
+            max_files: default_max_files(),
+            stdout: default_stdout(),
+            level: default_log_level(),
+            sources: HashMap::new(),
+            events: None,
+            redact: default_redact(),
+            redact_patterns: Vec::new(),
+            repeat_window: default_repeat_window(),
+            repeat_limit: default_repeat_limit(),
+            repeat_sources: HashMap::new(),
+        }
+    }
+}
+
+/// `$XDG_STATE_HOME/spotify-adblock/spotify-adblock.log`
+fn default_log_file_path() -> PathBuf {
+    env::var("XDG_STATE_HOME").map_or_else(
+        |_| {
+            #[allow(deprecated)] // std::env::home_dir() is only broken on Windows
+            env::home_dir().unwrap_or_default().join(".local/state")
+        },
+        PathBuf::from
+    ).join("spotify-adblock/spotify-adblock.log")
+}
+
+const fn default_max_file_size() -> u64 {
+    1024 * 1024
+}
+
+const fn default_max_files() -> u32 {
+    3
+}
+
+const fn default_repeat_window() -> u64 {
+    60
+}
+
+const fn default_repeat_limit() -> u32 {
+    1
+}
+
+const fn default_redact() -> bool {
+    true
+}
+
+const fn default_stdout() -> bool {
+    true
+}
+
+const fn default_log_level() -> Level {
+    Level::Info
+}
+
+/// A set of hooks that is switched on or off as a whole
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookGroup {
+    /// Resolver hooks (`getaddrinfo`, `gethostbyname`, `res_query`, ...)
+    Dns,
+    /// `connect` and the UDP send hooks, including plain-HTTP inspection
+    Socket,
+    /// `SSL_write`, and with it dealer WebSocket filtering
+    Ssl,
+    /// `cef_urlrequest_create` and the browser client hooks
+    Cef,
+}
+
+/// Hook groups per process role; a role left out keeps every group
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProcessRoleHooks {
+    pub browser: Option<Vec<HookGroup>>,
+    pub renderer: Option<Vec<HookGroup>>,
+    pub gpu: Option<Vec<HookGroup>>,
+    pub network_service: Option<Vec<HookGroup>>,
+    pub utility: Option<Vec<HookGroup>>,
+    pub zygote: Option<Vec<HookGroup>>,
+    pub crashpad_handler: Option<Vec<HookGroup>>,
+    pub other: Option<Vec<HookGroup>>,
+}
+
+impl ProcessRoleHooks {
+    /// Whether hooks of `group` act in a process of `role`
+    #[must_use]
+    pub fn is_active(&self, role: ProcessRole, group: HookGroup) -> bool {
+        let groups = match role {
+            ProcessRole::Browser => &self.browser,
+            ProcessRole::Renderer => &self.renderer,
+            ProcessRole::Gpu => &self.gpu,
+            ProcessRole::NetworkService => &self.network_service,
+            ProcessRole::Utility => &self.utility,
+            ProcessRole::Zygote => &self.zygote,
+            ProcessRole::CrashpadHandler => &self.crashpad_handler,
+            ProcessRole::Other => &self.other,
+        };
+        groups.as_ref().is_none_or(|groups| groups.contains(&group))
+    }
+}
+
+/// Whether a hook lets a call through when it cannot evaluate it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailurePolicy {
+    /// Let the call through, favouring availability
+    Open,
+    /// Refuse the call, favouring privacy
+    Closed,
+}
+
+impl FailurePolicy {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+        }
+    }
+}
+
+/// Failure policy per hook
+///
+/// The defaults keep each hook's historical behavior: CEF requests and TLS
+/// writes fail open, name lookups fail closed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct FailurePolicies {
+    /// `cef_urlrequest_create`
+    pub cef: FailurePolicy,
+    /// `SSL_write`
+    pub ssl: FailurePolicy,
+    /// The resolver hooks
+    pub dns: FailurePolicy,
+}
+
+impl Default for FailurePolicies {
+    fn default() -> Self {
+        Self { cef: FailurePolicy::Open, ssl: FailurePolicy::Open, dns: FailurePolicy::Closed }
+    }
+}
+
+/// The part of a config file read when the whole file cannot be used
+#[derive(Deserialize, Debug, Default)]
+struct FailurePolicyOnly {
+    #[serde(default)]
+    failure_policy: FailurePolicies,
+}
+
+pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
+    let config = load_config();
+    registry::disable_configured(&config.disabled_hooks);
+    logging::configure(&config.logging);
+    config
+});
 
 /// Load configuration from multiple potential locations with fault tolerance
 fn load_config() -> Config {
-    let config_paths = vec![
-        PathBuf::from("config.toml"),
-        env::var("XDG_CONFIG_HOME").map_or_else(
-            |_| {
-                #[allow(deprecated)] // std::env::home_dir() is only broken on Windows
-                env::home_dir().unwrap_or_default().join(".config")
-            },
-            PathBuf::from
-        ).join("spotify-adblock/config.toml"),
-        PathBuf::from("/etc/spotify-adblock/config.toml"),
-    ];
-
-    if let Some(path) = config_paths.into_iter().find(|path| path.exists()) {
-        println!("[*] Config file: {}", path.to_str().unwrap_or("(invalid path)"));
+    let mut failure_policy = FailurePolicies::default();
+    if let Some(path) = config_paths().into_iter().find(|path| path.exists()) {
+        logging::log_info(Source::Config, &format!("Config file: {}", path.to_str().unwrap_or("(invalid path)")));
         match read_to_string(&path) {
             Ok(config_string) if config_string.len() <= MAX_CONFIG_SIZE => {
                 match toml::from_str(&config_string) {
@@ -40,22 +458,23 @@ fn load_config() -> Config {
                         return config;
                     }
                     Err(error) => {
-                        println!("[*] Error: Parse config file ({error})");
+                        logging::log_error(Source::Config, &format!("Parse config file ({error})"));
+                        // A bad rule elsewhere must not undo a strict policy
+                        if let Ok(partial) = toml::from_str::<FailurePolicyOnly>(&config_string) {
+                            failure_policy = partial.failure_policy;
+                        }
                     }
                 }
             },
-            Ok(_) => println!("[*] Error: Config file too large (exceeds {MAX_CONFIG_SIZE} bytes)"),
+            Ok(_) => logging::log_error(Source::Config, &format!("Config file too large (exceeds {MAX_CONFIG_SIZE} bytes)")),
             Err(error) => {
-                println!("[*] Error: Read config file ({error})");
+                logging::log_error(Source::Config, &format!("Read config file ({error})"));
             }
         }
     } else {
-        println!("[*] Error: No config file found");
+        logging::log_error(Source::Config, "No config file found");
     }
 
     // Default empty configuration - safe fallback
-    Config {
-        allowlist: RegexSet::empty(),
-        denylist: RegexSet::empty(),
-    }
+    Config { failure_policy, is_fallback: true, ..Config::default() }
 }
@@ -0,0 +1,38 @@
+//! Browser creation hooks
+//!
+//! Creating a browser is the one point where Spotify hands CEF the
+//! `cef_client_t` whose handlers see xpui's resource loads and page events.
+//! The filters that need those handlers are attached to the client here.
+
+use cef_sys::{
+    _cef_browser_settings_t, _cef_browser_t, _cef_client_t, _cef_dictionary_value_t, _cef_request_context_t,
+    _cef_window_info_t, cef_string_t,
+};
+use libc::c_int;
+
+use crate::config::{HookGroup, PASSTHROUGH_MODE};
+use crate::hook;
+
+use super::{cosmetic, resource_filter};
+
+fn install_on_client(client: *mut _cef_client_t) {
+    if client.is_null() || *PASSTHROUGH_MODE || !super::is_active(HookGroup::Cef) {
+        return;
+    }
+    resource_filter::install_on_client(client);
+    cosmetic::install_on_client(client);
+}
+
+hook! {
+    cef_browser_host_create_browser(window_info: *const _cef_window_info_t, client: *mut _cef_client_t, url: *const cef_string_t, settings: *const _cef_browser_settings_t, extra_info: *mut _cef_dictionary_value_t, request_context: *mut _cef_request_context_t) -> c_int => REAL_CEF_BROWSER_HOST_CREATE_BROWSER {
+        install_on_client(client);
+        REAL_CEF_BROWSER_HOST_CREATE_BROWSER(window_info, client, url, settings, extra_info, request_context)
+    }
+}
+
+hook! {
+    cef_browser_host_create_browser_sync(window_info: *const _cef_window_info_t, client: *mut _cef_client_t, url: *const cef_string_t, settings: *const _cef_browser_settings_t, extra_info: *mut _cef_dictionary_value_t, request_context: *mut _cef_request_context_t) -> *mut _cef_browser_t => REAL_CEF_BROWSER_HOST_CREATE_BROWSER_SYNC {
+        install_on_client(client);
+        REAL_CEF_BROWSER_HOST_CREATE_BROWSER_SYNC(window_info, client, url, settings, extra_info, request_context)
+    }
+}
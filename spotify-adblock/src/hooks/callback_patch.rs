@@ -0,0 +1,196 @@
+//! Bookkeeping for callbacks patched in place on Spotify's CEF objects
+//!
+//! Wrapping a CEF object means forwarding every callback in its table, and
+//! those tables change between CEF versions. Instead, a single callback
+//! slot is overwritten with ours and the original is remembered here, keyed
+//! by object address, so our callback can delegate to it.
+//!
+//! Entries live exactly as long as their object: the first time an object is
+//! patched its `release` is wrapped as well, and the release that drops the
+//! last reference forgets every entry kept for the object before freeing it.
+//! A live object's entries are never dropped, and a new object at a freed
+//! address never sees a stale one.
+
+use std::collections::BTreeMap;
+use std::ptr::{addr_eq, fn_addr_eq};
+use std::sync::Mutex;
+
+use cef_sys::_cef_base_ref_counted_t;
+use libc::c_int;
+
+use super::cef_object::ObjectRef;
+
+type Release = unsafe extern "C" fn(*mut _cef_base_ref_counted_t) -> c_int;
+
+const TRACKED_RELEASE: Release = tracked_release;
+
+/// A table whose entries are forgotten when their object is freed
+trait ObjectTable: Sync {
+    fn forget(&self, address: usize);
+}
+
+/// A patched object whose `release` was wrapped
+struct TrackedObject {
+    /// The object's own `release`
+    release: Release,
+    /// Tables with an entry for the object
+    tables: Vec<&'static dyn ObjectTable>,
+}
+
+static TRACKED_OBJECTS: Mutex<BTreeMap<usize, TrackedObject>> = Mutex::new(BTreeMap::new());
+
+unsafe extern "C" fn tracked_release(base: *mut _cef_base_ref_counted_t) -> c_int {
+    let address = base as usize;
+    let release = TRACKED_OBJECTS.lock().ok().and_then(|objects| objects.get(&address).map(|tracked| tracked.release));
+    let Some(release) = release else {
+        return 0;
+    };
+
+    // SAFETY: Category 8 - FFI boundary. `base` is a live object that still
+    // holds the reference being released.
+    let is_last = unsafe { (*base).has_one_ref.is_some_and(|has_one_ref| has_one_ref(base) != 0) };
+    // Only the caller holds a reference, so nobody can take a new one
+    // between this check and the release
+    if is_last {
+        let tracked = TRACKED_OBJECTS.lock().ok().and_then(|mut objects| objects.remove(&address));
+        for table in tracked.into_iter().flat_map(|tracked| tracked.tables) {
+            table.forget(address);
+        }
+    }
+
+    // SAFETY: Category 8 - FFI boundary. The object's own `release`, called
+    // with the reference our caller hands over.
+    unsafe { release(base) }
+}
+
+/// Follow the lifetime of `object` for `table`; `false` if it cannot be
+/// followed
+fn track(object: *mut _cef_base_ref_counted_t, table: &'static dyn ObjectTable) -> bool {
+    let Ok(mut objects) = TRACKED_OBJECTS.lock() else {
+        return false;
+    };
+    let address = object as usize;
+    if let Some(tracked) = objects.get_mut(&address) {
+        if !tracked.tables.iter().any(|known| addr_eq(*known, table)) {
+            tracked.tables.push(table);
+        }
+        return true;
+    }
+
+    // SAFETY: Category 8 - FFI boundary. `object` is a live CEF object whose
+    // first member is its `cef_base_ref_counted_t`, writable object memory.
+    unsafe {
+        let Some(release) = (*object).release else {
+            return false;
+        };
+        if fn_addr_eq(release, TRACKED_RELEASE) || (*object).has_one_ref.is_none() {
+            return false;
+        }
+        objects.insert(address, TrackedObject { release, tables: vec![table] });
+        (*object).release = Some(TRACKED_RELEASE);
+    }
+    true
+}
+
+/// Values kept per patched object, keyed by object address, until the
+/// object is freed
+pub(super) struct PatchedObjects<V>(Mutex<BTreeMap<usize, V>>);
+
+impl<V: Send> ObjectTable for PatchedObjects<V> {
+    fn forget(&self, address: usize) {
+        // Dropped after the lock is released
+        let value = self.0.lock().ok().and_then(|mut values| values.remove(&address));
+        drop(value);
+    }
+}
+
+impl<V: Send> PatchedObjects<V> {
+    pub(super) const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    /// Keep `value` for `object` until the object is freed
+    ///
+    /// Returns `false` when the object's lifetime cannot be followed; such
+    /// an object must be left unpatched.
+    pub(super) fn remember<T>(&'static self, object: *mut T, value: V) -> bool {
+        if !track(object.cast(), self) {
+            return false;
+        }
+        let Ok(mut values) = self.0.lock() else {
+            return false;
+        };
+        values.insert(object as usize, value);
+        true
+    }
+
+    /// Run `f` on the value kept for `object`, if any
+    pub(super) fn with<T, R>(&self, object: *mut T, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.0.lock().ok()?.get_mut(&(object as usize)).map(f)
+    }
+}
+
+/// Original callbacks of one patched slot; `None` where the object had none
+pub(super) type OriginalCallbacks<F> = PatchedObjects<Option<F>>;
+
+impl<F: Copy + Send> OriginalCallbacks<F> {
+    /// The callback `object` had before patching, if it had one
+    pub(super) fn get<T>(&self, object: *mut T) -> Option<F> {
+        self.with(object, |original| *original).flatten()
+    }
+}
+
+/// Where an object's handler comes from: Spotify's getter, and the handler
+/// of ours returned whenever that gives none, created once per object
+pub(super) struct HandlerSource<F, T> {
+    getter: Option<F>,
+    fallback: Option<ObjectRef<T>>,
+}
+
+impl<F: Copy + Send, T> HandlerSource<F, T> {
+    pub(super) const fn new(getter: Option<F>) -> Self {
+        Self { getter, fallback: None }
+    }
+
+    /// Spotify's getter on `owner`, if it has one
+    pub(super) fn getter<O>(table: &PatchedObjects<Self>, owner: *mut O) -> Option<F> {
+        table.with(owner, |source| source.getter).flatten()
+    }
+
+    /// Our handler for `owner`, with a reference for the caller
+    pub(super) fn fallback<O>(table: &PatchedObjects<Self>, owner: *mut O, create: fn() -> *mut T) -> *mut T {
+        table
+            .with(owner, |source| source.fallback.get_or_insert_with(|| ObjectRef::new(create())).add_ref())
+            .unwrap_or_else(create)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cef_sys::_cef_client_t;
+
+    use super::*;
+    use crate::hooks::cef_object::{add_ref_object, CefObject};
+    use crate::hooks::cef_util::release;
+
+    static FIRST: OriginalCallbacks<usize> = OriginalCallbacks::new();
+    static SECOND: OriginalCallbacks<usize> = OriginalCallbacks::new();
+
+    #[test]
+    fn keeps_originals_while_the_object_lives() {
+        let client = CefObject::create(_cef_client_t::default(), ());
+        assert!(FIRST.remember(client, Some(1)));
+        assert!(SECOND.remember(client, Some(2)));
+        assert!(FIRST.remember(client, Some(3)));
+
+        release(add_ref_object(client));
+        assert_eq!(FIRST.get(client), Some(3));
+        assert_eq!(SECOND.get(client), Some(2));
+
+        let address = client as usize;
+        release(client);
+        assert_eq!(FIRST.get(address as *mut u8), None);
+        assert_eq!(SECOND.get(address as *mut u8), None);
+        assert!(!TRACKED_OBJECTS.lock().unwrap().contains_key(&address));
+    }
+}
@@ -0,0 +1,164 @@
+//! Rust-implemented CEF objects with CEF-compatible reference counting
+//!
+//! CEF's C API objects are structs of function pointers that start with a
+//! `cef_base_ref_counted_t`. `CefObject` places such a struct at the start
+//! of a heap allocation, followed by a reference count and Rust-side state,
+//! so callbacks can recover the state from the `self_` pointer CEF passes
+//! back. The allocation is freed (dropping the state) when the last
+//! reference is released.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cef_sys::_cef_base_ref_counted_t;
+use libc::c_int;
+
+#[repr(C)]
+pub(super) struct CefObject<T, S> {
+    // Must stay the first field: CEF sees a pointer to this as `*mut T`
+    cef: T,
+    ref_count: AtomicUsize,
+    state: S,
+}
+
+impl<T, S> CefObject<T, S> {
+    /// Allocate an object holding one reference, owned by the caller
+    ///
+    /// `cef` must be a CEF struct whose first member is its
+    /// `cef_base_ref_counted_t`; the base is filled in here.
+    pub(super) fn create(cef: T, state: S) -> *mut T {
+        let object = Box::into_raw(Box::new(Self { cef, ref_count: AtomicUsize::new(1), state }));
+        // SAFETY: Category 8 - FFI boundary. `object` was just allocated and
+        // `T` starts with its `cef_base_ref_counted_t`.
+        unsafe {
+            let base = (&raw mut (*object).cef).cast::<_cef_base_ref_counted_t>();
+            (*base).size = size_of::<T>();
+            (*base).add_ref = Some(add_ref::<T, S>);
+            (*base).release = Some(release::<T, S>);
+            (*base).has_one_ref = Some(has_one_ref::<T, S>);
+            (*base).has_at_least_one_ref = Some(has_at_least_one_ref::<T, S>);
+            (&raw mut (*object).cef)
+        }
+    }
+
+    /// Borrow the Rust-side state behind a pointer returned by `create`
+    ///
+    /// # Safety
+    ///
+    /// `cef` must come from `CefObject::<T, S>::create` and still be
+    /// referenced by the caller.
+    pub(super) unsafe fn state<'a>(cef: *mut T) -> &'a S {
+        // SAFETY: Category 8 - FFI boundary. Per the contract above, `cef` is
+        // the first field of a live `CefObject<T, S>`.
+        unsafe { &(*cef.cast::<Self>()).state }
+    }
+
+    fn from_base<'a>(base: *mut _cef_base_ref_counted_t) -> &'a Self {
+        // SAFETY: Category 8 - FFI boundary. These callbacks are only
+        // installed on objects created by `create`, whose base is at offset 0.
+        unsafe { &*base.cast::<Self>() }
+    }
+}
+
+unsafe extern "C" fn add_ref<T, S>(base: *mut _cef_base_ref_counted_t) {
+    CefObject::<T, S>::from_base(base).ref_count.fetch_add(1, Ordering::Relaxed);
+}
+
+unsafe extern "C" fn release<T, S>(base: *mut _cef_base_ref_counted_t) -> c_int {
+    if CefObject::<T, S>::from_base(base).ref_count.fetch_sub(1, Ordering::AcqRel) != 1 {
+        return 0;
+    }
+    // SAFETY: Category 8 - FFI boundary. That was the last reference, so
+    // nothing else can reach the allocation made in `create`.
+    drop(unsafe { Box::from_raw(base.cast::<CefObject<T, S>>()) });
+    1
+}
+
+unsafe extern "C" fn has_one_ref<T, S>(base: *mut _cef_base_ref_counted_t) -> c_int {
+    c_int::from(CefObject::<T, S>::from_base(base).ref_count.load(Ordering::Acquire) == 1)
+}
+
+unsafe extern "C" fn has_at_least_one_ref<T, S>(base: *mut _cef_base_ref_counted_t) -> c_int {
+    c_int::from(CefObject::<T, S>::from_base(base).ref_count.load(Ordering::Acquire) >= 1)
+}
+
+/// A reference to a CEF object kept beyond a call, released on drop
+pub(super) struct ObjectRef<T>(*mut T);
+
+// SAFETY: Category 8 - FFI boundary. CEF reference counting is thread-safe
+// and the pointer is only used to take and drop references.
+unsafe impl<T> Send for ObjectRef<T> {}
+
+impl<T> ObjectRef<T> {
+    /// Keep the reference the caller holds to `object`
+    pub(super) const fn new(object: *mut T) -> Self {
+        Self(object)
+    }
+
+    /// The object, with a new reference for the caller
+    pub(super) fn add_ref(&self) -> *mut T {
+        add_ref_object(self.0)
+    }
+}
+
+impl<T> Drop for ObjectRef<T> {
+    fn drop(&mut self) {
+        super::cef_util::release(self.0);
+    }
+}
+
+/// Take one more reference to a ref-counted CEF object
+pub(super) fn add_ref_object<T>(object: *mut T) -> *mut T {
+    if !object.is_null() {
+        let base = object.cast::<_cef_base_ref_counted_t>();
+        // SAFETY: Category 8 - FFI boundary. `object` is a non-null CEF object
+        // whose first member is its `cef_base_ref_counted_t`.
+        unsafe {
+            if let Some(add_ref) = (*base).add_ref {
+                add_ref(base);
+            }
+        }
+    }
+    object
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use cef_sys::_cef_task_t;
+
+    use super::*;
+    use crate::hooks::cef_util::release as release_object;
+
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn task() -> _cef_task_t {
+        _cef_task_t { base: _cef_base_ref_counted_t::default(), execute: None }
+    }
+
+    #[test]
+    fn frees_state_after_the_last_release() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let object = CefObject::create(task(), DropFlag(Arc::clone(&dropped)));
+        let base = object.cast::<_cef_base_ref_counted_t>();
+
+        add_ref_object(object);
+        // SAFETY: `object` was created above and holds two references.
+        unsafe {
+            assert_eq!((*base).has_one_ref.unwrap()(base), 0);
+            release_object(object);
+            assert_eq!((*base).has_one_ref.unwrap()(base), 1);
+            assert!(!CefObject::<_cef_task_t, DropFlag>::state(object).0.load(Ordering::SeqCst));
+        }
+
+        release_object(object);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
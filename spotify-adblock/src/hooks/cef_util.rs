@@ -0,0 +1,75 @@
+//! Small helpers for working with CEF's C API objects and strings
+
+use std::slice::from_raw_parts;
+
+use cef_sys::{_cef_base_ref_counted_t, cef_string_t, cef_string_userfree_utf16_t};
+
+use super::memory::cef_string_userfree_utf16_free;
+
+pub(super) fn cef_userfree_utf16_to_string(value: cef_string_userfree_utf16_t) -> Option<String> {
+    if value.is_null() {
+        return None;
+    }
+
+    // SAFETY: Category 8 - FFI boundary. `value` is a non-null CEF userfree
+    // string pointer returned by the request API for the duration of this call.
+    cef_string_to_string(unsafe { &*value })
+}
+
+/// Convert a userfree string returned by a CEF getter and free it
+pub(super) fn take_cef_string(value: cef_string_userfree_utf16_t) -> Option<String> {
+    let string = cef_userfree_utf16_to_string(value);
+    cef_string_userfree_utf16_free(value);
+    string
+}
+
+/// Convert a borrowed CEF string to an owned Rust string
+pub(super) fn cef_string_to_string(cef_string: &cef_string_t) -> Option<String> {
+    if cef_string.length == 0 {
+        return Some(String::new());
+    }
+
+    if cef_string.str_.is_null() {
+        return None;
+    }
+
+    // SAFETY: Category 10 - out-of-bounds. CEF reports `length` UTF-16 code
+    // units for the non-null `str_` pointer in this string.
+    let utf16 = unsafe { from_raw_parts(cef_string.str_, cef_string.length) };
+    Some(String::from_utf16_lossy(utf16))
+}
+
+/// A UTF-16 buffer that can be lent to CEF APIs taking `const cef_string_t*`
+///
+/// The borrowed `cef_string_t` has no destructor, so CEF copies it rather
+/// than taking ownership; the buffer must outlive the call it is passed to.
+pub(super) struct CefStringBuf(Vec<u16>);
+
+impl CefStringBuf {
+    pub(super) fn new(value: &str) -> Self {
+        Self(value.encode_utf16().collect())
+    }
+
+    pub(super) fn as_cef_string(&self) -> cef_string_t {
+        cef_string_t { str_: self.0.as_ptr().cast_mut(), length: self.0.len(), dtor: None }
+    }
+}
+
+/// Drop one reference to a ref-counted CEF object
+///
+/// Every CEF ref-counted struct starts with `cef_base_ref_counted_t`, so the
+/// object pointer doubles as a pointer to its base.
+pub(super) fn release<T>(object: *mut T) {
+    if object.is_null() {
+        return;
+    }
+
+    let base = object.cast::<_cef_base_ref_counted_t>();
+    // SAFETY: Category 8 - FFI boundary. `object` is a non-null CEF object
+    // whose first member is its `cef_base_ref_counted_t`.
+    unsafe {
+        if let Some(release) = (*base).release {
+            release(base);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Observing proxy for the `cef_urlrequest_client_t` of allowed requests
+//!
+//! After the real `cef_urlrequest_create` runs, only the caller's client
+//! learns how the request went. Allowed requests are therefore given a
+//! proxy client that forwards every callback to the original one and, on
+//! completion, records status, error, byte count and latency in
+//! `request_stats`. The caller must never see the proxy: Spotify's C++
+//! wrapper unwraps the client returned by `get_client` as its own struct, so
+//! `get_client` on the created request is patched to hand back the caller's
+//! client instead.
+
+use std::ptr::{fn_addr_eq, null_mut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use cef_sys::{_cef_auth_callback_t, _cef_urlrequest_client_t, cef_string_t, cef_urlrequest_status_t, cef_urlrequest_t};
+use libc::{c_int, c_void};
+
+use super::cef_object::{add_ref_object, CefObject};
+use super::cef_util::release;
+use super::request_stats::{self, Completion};
+
+/// Rust-side state of a proxy client
+struct ProxyClient {
+    /// The caller's client; the proxy owns the reference it was given
+    inner: *mut _cef_urlrequest_client_t,
+    method: String,
+    url: String,
+    started: Instant,
+    bytes: AtomicU64,
+}
+
+impl Drop for ProxyClient {
+    fn drop(&mut self) {
+        release(self.inner);
+    }
+}
+
+type ProxyClientObject = CefObject<_cef_urlrequest_client_t, ProxyClient>;
+
+fn proxy_state<'a>(client: *mut _cef_urlrequest_client_t) -> &'a ProxyClient {
+    // SAFETY: Category 8 - FFI boundary. These callbacks are only installed on
+    // proxies created by `wrap_client`, and CEF passes the proxy as `self_`.
+    unsafe { ProxyClientObject::state(client) }
+}
+
+/// Read the outcome of a finished request
+fn completion(urlrequest: *mut cef_urlrequest_t, proxy: &ProxyClient) -> Completion {
+    let mut completion = Completion {
+        succeeded: false,
+        http_status: 0,
+        error: 0,
+        bytes: proxy.bytes.load(Ordering::Relaxed),
+        latency: proxy.started.elapsed(),
+    };
+    if urlrequest.is_null() {
+        return completion;
+    }
+
+    // SAFETY: Category 8 - FFI boundary. `urlrequest` is the non-null request
+    // CEF passed to `on_request_complete`; `get_response` returns a reference
+    // that is released below.
+    unsafe {
+        completion.succeeded = (*urlrequest)
+            .get_request_status
+            .is_some_and(|get_status| get_status(urlrequest) == cef_urlrequest_status_t::UR_SUCCESS);
+        completion.error = (*urlrequest)
+            .get_request_error
+            .map_or(0, |get_error| get_error(urlrequest) as c_int);
+        let response = (*urlrequest).get_response.map_or(null_mut(), |get_response| get_response(urlrequest));
+        if !response.is_null() {
+            completion.http_status = (*response).get_status.map_or(0, |get_status| get_status(response));
+            release(response);
+        }
+    }
+    completion
+}
+
+unsafe extern "C" fn on_request_complete(client: *mut _cef_urlrequest_client_t, urlrequest: *mut cef_urlrequest_t) {
+    let proxy = proxy_state(client);
+    request_stats::record(&proxy.method, &proxy.url, &completion(urlrequest, proxy));
+
+    // SAFETY: Category 8 - FFI boundary. `inner` is the caller's client, kept
+    // alive by our reference; our reference to `urlrequest` is handed on.
+    unsafe {
+        match (*proxy.inner).on_request_complete {
+            Some(on_request_complete) => on_request_complete(proxy.inner, urlrequest),
+            None => release(urlrequest),
+        }
+    }
+}
+
+unsafe extern "C" fn on_upload_progress(
+    client: *mut _cef_urlrequest_client_t,
+    urlrequest: *mut cef_urlrequest_t,
+    current: i64,
+    total: i64,
+) {
+    let inner = proxy_state(client).inner;
+    // SAFETY: Category 8 - FFI boundary. As in `on_request_complete`.
+    unsafe {
+        match (*inner).on_upload_progress {
+            Some(on_upload_progress) => on_upload_progress(inner, urlrequest, current, total),
+            None => release(urlrequest),
+        }
+    }
+}
+
+unsafe extern "C" fn on_download_progress(
+    client: *mut _cef_urlrequest_client_t,
+    urlrequest: *mut cef_urlrequest_t,
+    current: i64,
+    total: i64,
+) {
+    let inner = proxy_state(client).inner;
+    // SAFETY: Category 8 - FFI boundary. As in `on_request_complete`.
+    unsafe {
+        match (*inner).on_download_progress {
+            Some(on_download_progress) => on_download_progress(inner, urlrequest, current, total),
+            None => release(urlrequest),
+        }
+    }
+}
+
+unsafe extern "C" fn on_download_data(
+    client: *mut _cef_urlrequest_client_t,
+    urlrequest: *mut cef_urlrequest_t,
+    data: *const c_void,
+    data_length: usize,
+) {
+    let proxy = proxy_state(client);
+    proxy.bytes.fetch_add(u64::try_from(data_length).unwrap_or(u64::MAX), Ordering::Relaxed);
+
+    // SAFETY: Category 8 - FFI boundary. As in `on_request_complete`; `data`
+    // is passed through untouched.
+    unsafe {
+        match (*proxy.inner).on_download_data {
+            Some(on_download_data) => on_download_data(proxy.inner, urlrequest, data, data_length),
+            None => release(urlrequest),
+        }
+    }
+}
+
+unsafe extern "C" fn get_auth_credentials(
+    client: *mut _cef_urlrequest_client_t,
+    is_proxy: c_int,
+    host: *const cef_string_t,
+    port: c_int,
+    realm: *const cef_string_t,
+    scheme: *const cef_string_t,
+    callback: *mut _cef_auth_callback_t,
+) -> c_int {
+    let inner = proxy_state(client).inner;
+    // SAFETY: Category 8 - FFI boundary. As in `on_request_complete`; our
+    // reference to `callback` is handed on.
+    unsafe {
+        (*inner).get_auth_credentials.map_or_else(
+            || {
+                release(callback);
+                0
+            },
+            |get_auth_credentials| get_auth_credentials(inner, is_proxy, host, port, realm, scheme, callback),
+        )
+    }
+}
+
+type OnRequestComplete = unsafe extern "C" fn(*mut _cef_urlrequest_client_t, *mut cef_urlrequest_t);
+type GetClient = unsafe extern "C" fn(*mut cef_urlrequest_t) -> *mut _cef_urlrequest_client_t;
+
+const PROXY_ON_REQUEST_COMPLETE: OnRequestComplete = on_request_complete;
+const UNWRAPPING_GET_CLIENT: GetClient = unwrapping_get_client;
+
+/// CEF's `get_client`, the same function for every request it creates
+static ORIGINAL_GET_CLIENT: OnceLock<GetClient> = OnceLock::new();
+
+fn is_proxy(client: *mut _cef_urlrequest_client_t) -> bool {
+    // SAFETY: Category 8 - FFI boundary. `client` is a non-null client
+    // returned by CEF with a reference for us.
+    !client.is_null()
+        && unsafe { (*client).on_request_complete }
+            .is_some_and(|callback| fn_addr_eq(callback, PROXY_ON_REQUEST_COMPLETE))
+}
+
+unsafe extern "C" fn unwrapping_get_client(urlrequest: *mut cef_urlrequest_t) -> *mut _cef_urlrequest_client_t {
+    let Some(get_client) = ORIGINAL_GET_CLIENT.get() else {
+        return null_mut();
+    };
+    // SAFETY: Category 8 - FFI boundary. CEF's own callback, called with the
+    // request CEF passed to us.
+    let client = unsafe { get_client(urlrequest) };
+    if !is_proxy(client) {
+        return client;
+    }
+    // Swap the proxy's reference for one to the caller's client
+    let inner = add_ref_object(proxy_state(client).inner);
+    release(client);
+    inner
+}
+
+/// Patch `get_client` on a request created with a proxy so it returns the
+/// caller's client
+pub(super) fn hide_proxy(urlrequest: *mut cef_urlrequest_t) {
+    if urlrequest.is_null() {
+        return;
+    }
+    // SAFETY: Category 8 - FFI boundary. `urlrequest` is the non-null request
+    // just returned by the real `cef_urlrequest_create`; its callback table
+    // belongs to this object alone.
+    unsafe {
+        let Some(current) = (*urlrequest).get_client else {
+            return;
+        };
+        if fn_addr_eq(current, UNWRAPPING_GET_CLIENT) {
+            return;
+        }
+        // Every request shares CEF's callback, so one saved copy serves all
+        if fn_addr_eq(*ORIGINAL_GET_CLIENT.get_or_init(|| current), current) {
+            (*urlrequest).get_client = Some(UNWRAPPING_GET_CLIENT);
+        }
+    }
+}
+
+/// Wrap `client` so the outcome of the request is recorded
+///
+/// Takes over the caller's reference to `client` and returns a proxy with
+/// one reference, to be passed to the real `cef_urlrequest_create`, whose
+/// result goes through `hide_proxy`. A null client (fire-and-forget
+/// request) is returned unchanged.
+pub(super) fn wrap_client(client: *mut _cef_urlrequest_client_t, method: &str, url: &str) -> *mut _cef_urlrequest_client_t {
+    if client.is_null() {
+        return client;
+    }
+
+    let proxy = _cef_urlrequest_client_t {
+        on_request_complete: Some(on_request_complete),
+        on_upload_progress: Some(on_upload_progress),
+        on_download_progress: Some(on_download_progress),
+        on_download_data: Some(on_download_data),
+        get_auth_credentials: Some(get_auth_credentials),
+        ..Default::default()
+    };
+    let state = ProxyClient {
+        inner: client,
+        method: method.to_string(),
+        url: url.to_string(),
+        started: Instant::now(),
+        bytes: AtomicU64::new(0),
+    };
+    CefObject::create(proxy, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Forwarded {
+        data: Vec<u8>,
+        completions: usize,
+    }
+
+    unsafe extern "C" fn inner_download_data(
+        client: *mut _cef_urlrequest_client_t,
+        urlrequest: *mut cef_urlrequest_t,
+        data: *const c_void,
+        data_length: usize,
+    ) {
+        // SAFETY: test client created below; `data` holds `data_length` bytes.
+        unsafe {
+            let forwarded = CefObject::<_cef_urlrequest_client_t, Mutex<Forwarded>>::state(client);
+            let data = std::slice::from_raw_parts(data.cast::<u8>(), data_length);
+            forwarded.lock().unwrap().data.extend_from_slice(data);
+        }
+        release(urlrequest);
+    }
+
+    unsafe extern "C" fn inner_request_complete(client: *mut _cef_urlrequest_client_t, urlrequest: *mut cef_urlrequest_t) {
+        // SAFETY: test client created below.
+        let forwarded = unsafe { CefObject::<_cef_urlrequest_client_t, Mutex<Forwarded>>::state(client) };
+        forwarded.lock().unwrap().completions += 1;
+        release(urlrequest);
+    }
+
+    #[test]
+    fn forwards_callbacks_and_counts_bytes() {
+        let inner = CefObject::create(
+            _cef_urlrequest_client_t {
+                on_download_data: Some(inner_download_data),
+                on_request_complete: Some(inner_request_complete),
+                ..Default::default()
+            },
+            Mutex::new(Forwarded::default()),
+        );
+        let proxy = wrap_client(add_ref_object(inner), "GET", "https://example.test/forwarded");
+
+        // SAFETY: `proxy` was created above and is still referenced.
+        unsafe {
+            (*proxy).on_download_data.unwrap()(proxy, null_mut(), b"abc".as_ptr().cast(), 3);
+            (*proxy).on_download_data.unwrap()(proxy, null_mut(), b"de".as_ptr().cast(), 2);
+            assert_eq!(proxy_state(proxy).bytes.load(Ordering::Relaxed), 5);
+            (*proxy).on_request_complete.unwrap()(proxy, null_mut());
+        }
+
+        // SAFETY: `inner` is still referenced by this test.
+        let forwarded = unsafe { CefObject::<_cef_urlrequest_client_t, Mutex<Forwarded>>::state(inner) };
+        let (data, completions) = {
+            let forwarded = forwarded.lock().unwrap();
+            (forwarded.data.clone(), forwarded.completions)
+        };
+        assert_eq!(data, b"abcde");
+        assert_eq!(completions, 1);
+
+        let stats = request_stats::get_request_stats();
+        let (_, entry) = stats.iter().find(|(endpoint, _)| endpoint == "https://example.test/forwarded").unwrap();
+        assert_eq!((entry.requests, entry.failures, entry.bytes), (1, 1, 5));
+
+        release(proxy);
+        release(inner);
+    }
+
+    unsafe extern "C" fn fake_get_client(urlrequest: *mut cef_urlrequest_t) -> *mut _cef_urlrequest_client_t {
+        // SAFETY: test request created below.
+        let client = unsafe { *CefObject::<cef_urlrequest_t, *mut _cef_urlrequest_client_t>::state(urlrequest) };
+        add_ref_object(client)
+    }
+
+    #[test]
+    fn get_client_returns_the_callers_client() {
+        let inner = CefObject::create(_cef_urlrequest_client_t::default(), ());
+        let proxy = wrap_client(add_ref_object(inner), "GET", "https://example.test/unwrapped");
+        let urlrequest =
+            CefObject::create(cef_urlrequest_t { get_client: Some(fake_get_client), ..Default::default() }, proxy);
+
+        hide_proxy(urlrequest);
+        hide_proxy(urlrequest);
+        // SAFETY: `urlrequest` was created above and is still referenced.
+        let client = unsafe { (*urlrequest).get_client.unwrap()(urlrequest) };
+        assert_eq!(client, inner);
+
+        release(client);
+        release(urlrequest);
+        release(proxy);
+        release(inner);
+    }
+}
@@ -0,0 +1,413 @@
+//! Cosmetic filtering: hiding ad elements inside xpui
+//!
+//! Some ads (upgrade buttons, sponsored shelves) are rendered from data the
+//! client needs anyway, so no request can be blocked for them. Instead the
+//! browser's load handler is patched to inject a script into the xpui main
+//! frame once it has loaded. The script adds a stylesheet hiding every
+//! enabled `cosmetic_rules` selector and a `MutationObserver` that counts
+//! newly hidden elements. Counts come back as console messages with a
+//! fixed prefix, which the patched display handler turns into log lines.
+
+use std::fmt::Write as _;
+use std::ptr::{fn_addr_eq, null_mut};
+use std::sync::LazyLock;
+
+use cef_sys::{
+    _cef_browser_t, _cef_client_t, _cef_display_handler_t, _cef_frame_t, _cef_load_handler_t, cef_log_severity_t,
+    cef_string_t,
+};
+use libc::c_int;
+
+use crate::config::{CosmeticRule, CONFIG};
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+use super::callback_patch::{HandlerSource, OriginalCallbacks, PatchedObjects};
+use super::cef_object::CefObject;
+use super::cef_util::{cef_string_to_string, release, take_cef_string, CefStringBuf};
+
+/// Prefix of the console messages the injected script reports through
+const REPORT_PREFIX: &str = "[spotify-adblock:cosmetic] ";
+
+/// Source URL the injected script is attributed to in devtools
+const SCRIPT_URL: &str = "spotify-adblock://cosmetic.js";
+
+/// Main frame URLs that get the script
+const XPUI_ORIGIN: &str = "https://xpui.app.spotify.com/";
+
+// Rescanning on every DOM mutation would be wasteful while xpui renders
+const SCAN_DELAY_MS: u32 = 250;
+
+type GetLoadHandler = unsafe extern "C" fn(*mut _cef_client_t) -> *mut _cef_load_handler_t;
+
+type GetDisplayHandler = unsafe extern "C" fn(*mut _cef_client_t) -> *mut _cef_display_handler_t;
+
+type OnLoadEnd = unsafe extern "C" fn(*mut _cef_load_handler_t, *mut _cef_browser_t, *mut _cef_frame_t, c_int);
+
+type OnConsoleMessage = unsafe extern "C" fn(
+    *mut _cef_display_handler_t,
+    *mut _cef_browser_t,
+    cef_log_severity_t,
+    *const cef_string_t,
+    *const cef_string_t,
+    c_int,
+) -> c_int;
+
+// Our callbacks as the pointer types stored in CEF's callback tables
+const FILTERED_GET_LOAD_HANDLER: GetLoadHandler = filtered_get_load_handler;
+const FILTERED_GET_DISPLAY_HANDLER: GetDisplayHandler = filtered_get_display_handler;
+const FILTERED_ON_LOAD_END: OnLoadEnd = filtered_on_load_end;
+const FILTERED_ON_CONSOLE_MESSAGE: OnConsoleMessage = filtered_on_console_message;
+
+static LOAD_HANDLER_GETTERS: PatchedObjects<HandlerSource<GetLoadHandler, _cef_load_handler_t>> =
+    PatchedObjects::new();
+static DISPLAY_HANDLER_GETTERS: PatchedObjects<HandlerSource<GetDisplayHandler, _cef_display_handler_t>> =
+    PatchedObjects::new();
+static LOAD_HANDLER_ORIGINALS: OriginalCallbacks<OnLoadEnd> = OriginalCallbacks::new();
+static DISPLAY_HANDLER_ORIGINALS: OriginalCallbacks<OnConsoleMessage> = OriginalCallbacks::new();
+
+static SCRIPT: LazyLock<Option<String>> = LazyLock::new(|| build_script(&CONFIG.cosmetic_rules));
+
+/// Quote `value` as a JavaScript string literal
+fn js_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            // Line terminators in JavaScript, and `<` so `</script>` stays inert
+            '\u{2028}' | '\u{2029}' | '<' => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(character));
+            }
+            character if character.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(character));
+            }
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Build the script injected into xpui, or `None` if no rule is enabled
+fn build_script(rules: &[CosmeticRule]) -> Option<String> {
+    let rules = rules
+        .iter()
+        .filter(|rule| rule.meta.enabled)
+        .map(|rule| {
+            format!(
+                "{{name:{},category:{},selector:{}}}",
+                js_string(&rule.meta.name),
+                js_string(rule.meta.category.as_str()),
+                js_string(&rule.selector)
+            )
+        })
+        .collect::<Vec<_>>();
+    if rules.is_empty() {
+        return None;
+    }
+
+    // One CSS rule per selector, so an invalid selector only drops itself
+    Some(format!(
+        r"(() => {{
+if (window.__spotifyAdblockCosmetic) return;
+window.__spotifyAdblockCosmetic = true;
+const rules = [{rules}];
+const style = document.createElement('style');
+style.textContent = rules.map(rule => rule.selector + '{{display:none !important}}').join('\n');
+(document.head || document.documentElement).appendChild(style);
+const hidden = new WeakSet();
+let pending = 0;
+const scan = () => {{
+  pending = 0;
+  let total = 0;
+  const counts = [];
+  for (const rule of rules) {{
+    let count = 0;
+    try {{
+      for (const element of document.querySelectorAll(rule.selector)) {{
+        if (!hidden.has(element)) {{ hidden.add(element); count++; }}
+      }}
+    }} catch (error) {{}}
+    if (count) {{ total += count; counts.push(rule.name + ' (' + rule.category + ')=' + count); }}
+  }}
+  if (total) console.log({prefix} + total + ' ' + counts.join(', '));
+}};
+new MutationObserver(() => {{ if (!pending) pending = setTimeout(scan, {SCAN_DELAY_MS}); }})
+  .observe(document.documentElement, {{childList: true, subtree: true}});
+scan();
+}})();",
+        rules = rules.join(","),
+        prefix = js_string(REPORT_PREFIX),
+    ))
+}
+
+/// Parse a report of the injected script into the hidden element count and
+/// the per-rule breakdown
+fn parse_report(message: &str) -> Option<(u64, &str)> {
+    let (total, counts) = message.strip_prefix(REPORT_PREFIX)?.split_once(' ')?;
+    Some((total.parse().ok()?, counts))
+}
+
+/// Patch `get_load_handler` and `get_display_handler` on a browser's client
+pub(super) fn install_on_client(client: *mut _cef_client_t) {
+    if client.is_null() || SCRIPT.is_none() {
+        return;
+    }
+    // SAFETY: Category 8 - FFI boundary. `client` is the non-null client
+    // passed to browser creation; its callback table is writable memory
+    // owned by the client object.
+    unsafe {
+        let current = (*client).get_load_handler;
+        if !current.is_some_and(|current| fn_addr_eq(current, FILTERED_GET_LOAD_HANDLER))
+            && LOAD_HANDLER_GETTERS.remember(client, HandlerSource::new(current))
+        {
+            (*client).get_load_handler = Some(FILTERED_GET_LOAD_HANDLER);
+        }
+
+        let current = (*client).get_display_handler;
+        if !current.is_some_and(|current| fn_addr_eq(current, FILTERED_GET_DISPLAY_HANDLER))
+            && DISPLAY_HANDLER_GETTERS.remember(client, HandlerSource::new(current))
+        {
+            (*client).get_display_handler = Some(FILTERED_GET_DISPLAY_HANDLER);
+        }
+    }
+}
+
+/// Patch `on_load_end` on a load handler
+fn install_on_load_handler(handler: *mut _cef_load_handler_t) {
+    // SAFETY: Category 8 - FFI boundary. `handler` is a non-null handler
+    // returned by the client; its callback table is writable object memory.
+    unsafe {
+        let current = (*handler).on_load_end;
+        if current.is_some_and(|current| fn_addr_eq(current, FILTERED_ON_LOAD_END)) {
+            return;
+        }
+        if LOAD_HANDLER_ORIGINALS.remember(handler, current) {
+            (*handler).on_load_end = Some(FILTERED_ON_LOAD_END);
+        }
+    }
+}
+
+/// Patch `on_console_message` on a display handler
+fn install_on_display_handler(handler: *mut _cef_display_handler_t) {
+    // SAFETY: Category 8 - FFI boundary. As in `install_on_load_handler`.
+    unsafe {
+        let current = (*handler).on_console_message;
+        if current.is_some_and(|current| fn_addr_eq(current, FILTERED_ON_CONSOLE_MESSAGE)) {
+            return;
+        }
+        if DISPLAY_HANDLER_ORIGINALS.remember(handler, current) {
+            (*handler).on_console_message = Some(FILTERED_ON_CONSOLE_MESSAGE);
+        }
+    }
+}
+
+fn create_load_handler() -> *mut _cef_load_handler_t {
+    CefObject::create(_cef_load_handler_t { on_load_end: Some(FILTERED_ON_LOAD_END), ..Default::default() }, ())
+}
+
+fn create_display_handler() -> *mut _cef_display_handler_t {
+    CefObject::create(
+        _cef_display_handler_t { on_console_message: Some(FILTERED_ON_CONSOLE_MESSAGE), ..Default::default() },
+        (),
+    )
+}
+
+unsafe extern "C" fn filtered_get_load_handler(client: *mut _cef_client_t) -> *mut _cef_load_handler_t {
+    let handler = HandlerSource::getter(&LOAD_HANDLER_GETTERS, client).map_or(null_mut(), |get_load_handler| {
+        // SAFETY: Category 8 - FFI boundary. The original callback of this
+        // client, called with the client CEF passed to us.
+        unsafe { get_load_handler(client) }
+    });
+
+    if handler.is_null() {
+        HandlerSource::fallback(&LOAD_HANDLER_GETTERS, client, create_load_handler)
+    } else {
+        install_on_load_handler(handler);
+        handler
+    }
+}
+
+unsafe extern "C" fn filtered_get_display_handler(client: *mut _cef_client_t) -> *mut _cef_display_handler_t {
+    let handler = HandlerSource::getter(&DISPLAY_HANDLER_GETTERS, client).map_or(null_mut(), |get_display_handler| {
+        // SAFETY: Category 8 - FFI boundary. As in `filtered_get_load_handler`.
+        unsafe { get_display_handler(client) }
+    });
+
+    if handler.is_null() {
+        HandlerSource::fallback(&DISPLAY_HANDLER_GETTERS, client, create_display_handler)
+    } else {
+        install_on_display_handler(handler);
+        handler
+    }
+}
+
+/// Whether `frame` is the xpui main frame
+fn is_xpui_main_frame(frame: *mut _cef_frame_t) -> bool {
+    // SAFETY: Category 8 - FFI boundary. `frame` is non-null and referenced
+    // by us for the duration of `on_load_end`.
+    unsafe {
+        (*frame).is_main.is_some_and(|is_main| is_main(frame) != 0)
+            && (*frame)
+                .get_url
+                .and_then(|get_url| take_cef_string(get_url(frame)))
+                .is_some_and(|url| url.starts_with(XPUI_ORIGIN))
+    }
+}
+
+fn inject_script(frame: *mut _cef_frame_t, script: &str) {
+    let code = CefStringBuf::new(script);
+    let script_url = CefStringBuf::new(SCRIPT_URL);
+    let (code, script_url) = (code.as_cef_string(), script_url.as_cef_string());
+    // SAFETY: Category 8 - FFI boundary. `frame` is non-null and referenced;
+    // CEF copies both strings, whose buffers outlive the call.
+    unsafe {
+        if let Some(execute_java_script) = (*frame).execute_java_script {
+            execute_java_script(frame, &raw const code, &raw const script_url, 0);
+        }
+    }
+}
+
+unsafe extern "C" fn filtered_on_load_end(
+    handler: *mut _cef_load_handler_t,
+    browser: *mut _cef_browser_t,
+    frame: *mut _cef_frame_t,
+    http_status_code: c_int,
+) {
+    if let Some(script) = SCRIPT.as_deref() {
+        if !frame.is_null() && is_xpui_main_frame(frame) {
+            inject_script(frame, script);
+            logging::log_debug(Source::Cosmetic, "COSMETIC: injected element hiding into xpui");
+        }
+    }
+
+    if let Some(on_load_end) = LOAD_HANDLER_ORIGINALS.get(handler) {
+        // SAFETY: Category 8 - FFI boundary. The original callback of this
+        // handler; our references to the arguments are handed on.
+        unsafe { on_load_end(handler, browser, frame, http_status_code) };
+    } else {
+        release(browser);
+        release(frame);
+    }
+}
+
+unsafe extern "C" fn filtered_on_console_message(
+    handler: *mut _cef_display_handler_t,
+    browser: *mut _cef_browser_t,
+    level: cef_log_severity_t,
+    message: *const cef_string_t,
+    source: *const cef_string_t,
+    line: c_int,
+) -> c_int {
+    // SAFETY: Category 8 - FFI boundary. A non-null `message` is a CEF
+    // string valid for the duration of the callback.
+    let text = if message.is_null() { None } else { cef_string_to_string(unsafe { &*message }) };
+    if let Some((total, counts)) = text.as_deref().and_then(parse_report) {
+        logging::log_info(Source::Cosmetic, &format!("COSMETIC HIDDEN: {total} elements ({counts})"));
+        release(browser);
+        // Handled: keep our reports out of Spotify's own console logging
+        return 1;
+    }
+
+    DISPLAY_HANDLER_ORIGINALS.get(handler).map_or_else(
+        || {
+            release(browser);
+            0
+        },
+        |on_console_message| {
+            // SAFETY: Category 8 - FFI boundary. The original callback of
+            // this handler; our reference to `browser` is handed on.
+            unsafe { on_console_message(handler, browser, level, message, source, line) }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use adblock_rules::{RuleCategory, RuleMeta};
+
+    use super::*;
+
+    fn rule(name: &str, selector: &str, enabled: bool) -> CosmeticRule {
+        CosmeticRule {
+            meta: RuleMeta { name: name.to_string(), category: RuleCategory::Upsell, enabled },
+            selector: selector.to_string(),
+        }
+    }
+
+    #[test]
+    fn quotes_javascript_strings() {
+        assert_eq!(js_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(js_string("line\nbreak\u{2028}"), r#""line\nbreak\u2028""#);
+        assert_eq!(js_string("</script>"), r#""\u003c/script>""#);
+    }
+
+    #[test]
+    fn builds_script_from_enabled_rules() {
+        assert_eq!(build_script(&[]), None);
+        assert_eq!(build_script(&[rule("off", ".off", false)]), None);
+
+        let script = build_script(&[
+            rule("upgrade", r#"button[data-testid="upgrade-button"]"#, true),
+            rule("off", ".off", false),
+        ])
+        .unwrap();
+        assert!(script.contains(r#"{name:"upgrade",category:"upsell",selector:"button[data-testid=\"upgrade-button\"]"}"#));
+        assert!(!script.contains(".off"));
+        assert!(script.contains(&js_string(REPORT_PREFIX)));
+    }
+
+    #[test]
+    fn parses_script_reports() {
+        assert_eq!(
+            parse_report("[spotify-adblock:cosmetic] 3 upgrade (upsell)=2, sponsored (sponsored)=1"),
+            Some((3, "upgrade (upsell)=2, sponsored (sponsored)=1"))
+        );
+        assert_eq!(parse_report("[spotify-adblock:cosmetic] many upgrade=2"), None);
+        assert_eq!(parse_report("Uncaught TypeError"), None);
+    }
+
+    #[test]
+    fn handles_only_script_reports() {
+        let handler = CefObject::create(_cef_display_handler_t::default(), ());
+        install_on_display_handler(handler);
+
+        let console = |message: &str| {
+            let message = CefStringBuf::new(message);
+            let message = message.as_cef_string();
+            // SAFETY: `handler` is a live test object; `message` outlives the call.
+            unsafe {
+                (*handler).on_console_message.unwrap()(
+                    handler,
+                    null_mut(),
+                    cef_log_severity_t::LOGSEVERITY_DEFAULT,
+                    &raw const message,
+                    std::ptr::null(),
+                    1,
+                )
+            }
+        };
+        assert_eq!(console("[spotify-adblock:cosmetic] 1 upgrade=1"), 1);
+        assert_eq!(console("Uncaught TypeError"), 0);
+        release(handler);
+    }
+
+    #[test]
+    fn reuses_one_fallback_handler_per_client() {
+        let client = CefObject::create(_cef_client_t::default(), ());
+        assert!(LOAD_HANDLER_GETTERS.remember(client, HandlerSource::new(None)));
+
+        // SAFETY: `client` is a live test object.
+        let (first, second) = unsafe { (filtered_get_load_handler(client), filtered_get_load_handler(client)) };
+        assert!(!first.is_null());
+        assert_eq!(first, second);
+
+        release(first);
+        release(second);
+        release(client);
+    }
+}
@@ -0,0 +1,379 @@
+//! Exec hooks that keep the library out of non-Spotify programs
+//!
+//! The library rides on `LD_PRELOAD`, which everything Spotify spawns
+//! inherits: `xdg-open`, the web browser opened from a link, crash
+//! reporters. There the resolver allowlist would break name resolution, so
+//! `execve`, the `execv*` variants and `posix_spawn`/`posix_spawnp` remove
+//! the library from `LD_PRELOAD` unless the new program is listed in
+//! `spotify_executables`. Processes that still end up with the library
+//! loaded (e.g. a wrapper script's shell) keep their hooks inert.
+//!
+//! Everything the hooks need is prepared while the library is loaded; the
+//! hooks build the child's environment on the stack and pass environments
+//! too large for that on unchanged.
+//!
+//! The variadic `execl*` functions cannot be interposed from Rust and are
+//! not covered.
+
+use std::env;
+use std::ffi::{c_char, c_int, c_void, CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr::null;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use libc::{pid_t, posix_spawn_file_actions_t, posix_spawnattr_t};
+
+use crate::config::CONFIG;
+use crate::hook;
+use crate::utils::process_role::EXECUTABLE;
+
+use super::guard;
+use super::registry::library_of;
+
+const LD_PRELOAD: &[u8] = b"LD_PRELOAD=";
+
+/// Chromium re-executes itself through this path
+const SELF_EXE: &[u8] = b"/proc/self/exe";
+
+/// Most environment entries rewritten; larger environments are passed on
+/// unchanged
+const MAX_ENV_ENTRIES: usize = 1024;
+
+/// Longest `LD_PRELOAD` entry rewritten; longer ones are passed on unchanged
+const MAX_PRELOAD_LEN: usize = 4096;
+
+unsafe extern "C" {
+    static environ: *const *const c_char;
+}
+
+/// What the exec hooks need, prepared while the library is loaded
+///
+/// Chromium and the zygote exec from children forked off a multithreaded
+/// parent, where a lock held by another thread at fork time stays held
+/// forever. The hooks therefore neither allocate nor initialize anything.
+#[derive(Debug)]
+struct ExecState {
+    /// Path this library was loaded from, as the dynamic linker reports it
+    library: PathBuf,
+    spotify_executables: &'static [String],
+    /// This process's `LD_PRELOAD` entry and its replacement without the
+    /// library (`None` drops the entry), which children usually inherit
+    inherited_preload: Option<(CString, Option<CString>)>,
+}
+
+static EXEC_STATE: OnceLock<ExecState> = OnceLock::new();
+
+/// Whether this process is one of `spotify_executables`; decided in
+/// `prepare`
+static IS_SPOTIFY_PROCESS: AtomicBool = AtomicBool::new(false);
+
+/// Decide whether hooks act in this process and prepare the exec hooks;
+/// runs once while the library is loaded
+pub(super) fn prepare() {
+    let spotify_executables = CONFIG.spotify_executables.as_slice();
+    IS_SPOTIFY_PROCESS.store(is_spotify_process_for(EXECUTABLE.as_deref(), spotify_executables), Ordering::Relaxed);
+    lazy_static::initialize(&REAL_EXECVE);
+    lazy_static::initialize(&REAL_EXECVPE);
+    lazy_static::initialize(&REAL_EXECV);
+    lazy_static::initialize(&REAL_EXECVP);
+    lazy_static::initialize(&REAL_POSIX_SPAWN);
+    lazy_static::initialize(&REAL_POSIX_SPAWNP);
+
+    let function: fn() -> bool = is_spotify_process;
+    let Some(library) = library_of(function as *const c_void) else {
+        return;
+    };
+    let inherited_preload = env::var_os("LD_PRELOAD").and_then(|ld_preload| {
+        let entry = CString::new([LD_PRELOAD, ld_preload.as_bytes()].concat()).ok()?;
+        let mut stripped = vec![0; entry.as_bytes().len() + 1];
+        let len = strip_library_into(ld_preload.as_bytes(), &library, &mut stripped)?;
+        let stripped = (len > 0).then(|| CStr::from_bytes_until_nul(&stripped).ok().map(CString::from)).flatten();
+        Some((entry, stripped))
+    });
+    let _ = EXEC_STATE.set(ExecState { library, spotify_executables, inherited_preload });
+}
+
+/// Whether hooks may act in this process
+pub(super) fn is_spotify_process() -> bool {
+    IS_SPOTIFY_PROCESS.load(Ordering::Relaxed)
+}
+
+/// Whether a process running `executable` is Spotify; one whose executable
+/// cannot be read is assumed to be
+fn is_spotify_process_for(executable: Option<&Path>, spotify_executables: &[String]) -> bool {
+    executable.is_none_or(|executable| is_spotify_executable(executable.as_os_str().as_bytes(), spotify_executables))
+}
+
+/// Whether the program at `path` (a path or a bare name) keeps the library
+fn is_spotify_executable(path: &[u8], spotify_executables: &[String]) -> bool {
+    let name = Path::new(OsStr::from_bytes(path)).file_name().unwrap_or_default();
+    path == SELF_EXE || spotify_executables.iter().any(|executable| name == executable.as_str())
+}
+
+/// Write the `LD_PRELOAD` entry for `ld_preload` (entries separated by
+/// colons or spaces) without `library` into `out`, NUL-terminated
+///
+/// Returns the length of the new value, or `None` if it does not fit.
+fn strip_library_into(ld_preload: &[u8], library: &Path, out: &mut [u8]) -> Option<usize> {
+    let entries = ld_preload
+        .split(|&byte| byte == b':' || byte == b' ')
+        .filter(|entry| !entry.is_empty())
+        .filter(|entry| {
+            let entry = Path::new(OsStr::from_bytes(entry));
+            entry != library && entry.file_name() != library.file_name()
+        });
+
+    out.get_mut(..LD_PRELOAD.len())?.copy_from_slice(LD_PRELOAD);
+    let mut len = LD_PRELOAD.len();
+    for entry in entries {
+        if len > LD_PRELOAD.len() {
+            *out.get_mut(len)? = b':';
+            len += 1;
+        }
+        out.get_mut(len..len + entry.len())?.copy_from_slice(entry);
+        len += entry.len();
+    }
+    *out.get_mut(len)? = 0;
+    Some(len - LD_PRELOAD.len())
+}
+
+/// Copy `envp` into `entries` with the library removed from `LD_PRELOAD`,
+/// using `preload` for a rewritten entry
+///
+/// Returns `false`, leaving the caller to pass `envp` on unchanged, when the
+/// library is not preloaded or the environment does not fit.
+///
+/// # Safety
+///
+/// `envp` must be a null-terminated array of NUL-terminated strings.
+unsafe fn stripped_environment(
+    envp: *const *const c_char,
+    state: &ExecState,
+    entries: &mut [*const c_char],
+    preload: &mut [u8],
+) -> bool {
+    let mut changed = false;
+    let mut len = 0;
+    let mut entry = envp;
+    // SAFETY: Category 8 - FFI boundary. Guaranteed by the caller; the loop
+    // stops at the terminating null pointer.
+    unsafe {
+        while !(*entry).is_null() {
+            let current = *entry;
+            entry = entry.add(1);
+            let bytes = CStr::from_ptr(current).to_bytes();
+            let Some(ld_preload) = bytes.strip_prefix(LD_PRELOAD) else {
+                let Some(slot) = entries.get_mut(len) else {
+                    return false;
+                };
+                *slot = current;
+                len += 1;
+                continue;
+            };
+
+            let replacement = match &state.inherited_preload {
+                Some((inherited, stripped)) if inherited.as_bytes() == bytes => stripped.as_deref().map(CStr::as_ptr),
+                _ => {
+                    let Some(stripped_len) = strip_library_into(ld_preload, &state.library, preload) else {
+                        return false;
+                    };
+                    if stripped_len == ld_preload.len() {
+                        Some(current)
+                    } else {
+                        (stripped_len > 0).then_some(preload.as_ptr().cast())
+                    }
+                }
+            };
+            changed |= replacement != Some(current);
+            if let Some(replacement) = replacement {
+                let Some(slot) = entries.get_mut(len) else {
+                    return false;
+                };
+                *slot = replacement;
+                len += 1;
+            }
+        }
+    }
+    let Some(terminator) = entries.get_mut(len) else {
+        return false;
+    };
+    *terminator = null();
+    changed
+}
+
+/// Run `exec` with `envp`, or with a copy lacking the library when the
+/// program at `path` should not inherit it
+fn with_child_environment<T>(
+    path: *const c_char,
+    envp: *const *const c_char,
+    exec: impl FnOnce(*const *const c_char) -> T,
+) -> T {
+    let Some(state) = EXEC_STATE.get() else {
+        return exec(envp);
+    };
+    if path.is_null() || envp.is_null() {
+        return exec(envp);
+    }
+    // SAFETY: Category 8 - FFI boundary. Exec callers pass a NUL-terminated
+    // program path.
+    let path = unsafe { CStr::from_ptr(path) };
+    if is_spotify_executable(path.to_bytes(), state.spotify_executables) {
+        return exec(envp);
+    }
+
+    let mut entries = [null(); MAX_ENV_ENTRIES];
+    let mut preload = [0; MAX_PRELOAD_LEN];
+    // SAFETY: Category 8 - FFI boundary. Exec callers pass a null-terminated
+    // array of NUL-terminated strings.
+    if unsafe { stripped_environment(envp, state, &mut entries, &mut preload) } {
+        exec(entries.as_ptr())
+    } else {
+        exec(envp)
+    }
+}
+
+/// The calling process's environment
+fn current_environment() -> *const *const c_char {
+    // SAFETY: Category 8 - FFI boundary. `environ` is the C library's global
+    // environment pointer; it is only read here.
+    unsafe { environ }
+}
+
+hook! {
+    execve(path: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int => REAL_EXECVE else { guard::unsupported() } {
+        with_child_environment(path, envp, |envp| REAL_EXECVE(path, argv, envp))
+    }
+}
+
+hook! {
+    execvpe(file: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int => REAL_EXECVPE else { guard::unsupported() } {
+        with_child_environment(file, envp, |envp| REAL_EXECVPE(file, argv, envp))
+    }
+}
+
+hook! {
+    execv(path: *const c_char, argv: *const *const c_char) -> c_int => REAL_EXECV else { guard::unsupported() } {
+        let envp = current_environment();
+        with_child_environment(path, envp, |child_envp| {
+            if child_envp == envp { REAL_EXECV(path, argv) } else { REAL_EXECVE(path, argv, child_envp) }
+        })
+    }
+}
+
+hook! {
+    execvp(file: *const c_char, argv: *const *const c_char) -> c_int => REAL_EXECVP else { guard::unsupported() } {
+        let envp = current_environment();
+        with_child_environment(file, envp, |child_envp| {
+            if child_envp == envp { REAL_EXECVP(file, argv) } else { REAL_EXECVPE(file, argv, child_envp) }
+        })
+    }
+}
+
+hook! {
+    posix_spawn(pid: *mut pid_t, path: *const c_char, file_actions: *const posix_spawn_file_actions_t, attrp: *const posix_spawnattr_t, argv: *const *mut c_char, envp: *const *mut c_char) -> c_int => REAL_POSIX_SPAWN else { libc::ENOSYS } {
+        with_child_environment(path, envp.cast(), |envp| {
+            REAL_POSIX_SPAWN(pid, path, file_actions, attrp, argv, envp.cast())
+        })
+    }
+}
+
+hook! {
+    posix_spawnp(pid: *mut pid_t, file: *const c_char, file_actions: *const posix_spawn_file_actions_t, attrp: *const posix_spawnattr_t, argv: *const *mut c_char, envp: *const *mut c_char) -> c_int => REAL_POSIX_SPAWNP else { libc::ENOSYS } {
+        with_child_environment(file, envp.cast(), |envp| {
+            REAL_POSIX_SPAWNP(pid, file, file_actions, attrp, argv, envp.cast())
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) fn act_as_spotify_process() {
+    IS_SPOTIFY_PROCESS.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY: &str = "/usr/lib/spotify-adblock.so";
+
+    fn state(inherited_preload: Option<(&CStr, Option<&CStr>)>) -> ExecState {
+        ExecState {
+            library: PathBuf::from(LIBRARY),
+            spotify_executables: &[],
+            inherited_preload: inherited_preload
+                .map(|(entry, stripped)| (CString::from(entry), stripped.map(CString::from))),
+        }
+    }
+
+    fn stripped_with(environment: &[&CStr], state: &ExecState) -> Option<Vec<String>> {
+        let envp = environment.iter().map(|entry| entry.as_ptr()).chain([null()]).collect::<Vec<_>>();
+        let mut entries = [null(); 8];
+        let mut preload = [0; 64];
+        // SAFETY: `envp` is a null-terminated array of C strings.
+        if !unsafe { stripped_environment(envp.as_ptr(), state, &mut entries, &mut preload) } {
+            return None;
+        }
+        let entries = entries.iter().take_while(|entry| !entry.is_null());
+        // SAFETY: The entries point into `environment`, `state` or `preload`.
+        Some(entries.map(|&entry| unsafe { CStr::from_ptr(entry) }.to_str().unwrap().to_string()).collect())
+    }
+
+    fn stripped(environment: &[&CStr]) -> Option<Vec<String>> {
+        stripped_with(environment, &state(None))
+    }
+
+    fn strip_library(ld_preload: &[u8]) -> Option<String> {
+        let mut out = [0; 64];
+        strip_library_into(ld_preload, Path::new(LIBRARY), &mut out)?;
+        Some(CStr::from_bytes_until_nul(&out).unwrap().to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn strips_only_this_library_from_ld_preload() {
+        assert_eq!(strip_library(b"/usr/lib/spotify-adblock.so").unwrap(), "LD_PRELOAD=");
+        assert_eq!(
+            strip_library(b"libfoo.so:/usr/lib/spotify-adblock.so libbar.so").unwrap(),
+            "LD_PRELOAD=libfoo.so:libbar.so"
+        );
+        // The dynamic linker also accepts a bare file name
+        assert_eq!(strip_library(b"spotify-adblock.so:libfoo.so").unwrap(), "LD_PRELOAD=libfoo.so");
+        // Too long for the buffer
+        assert_eq!(strip_library(&[b'a'; 64]), None);
+    }
+
+    #[test]
+    fn rewrites_only_the_ld_preload_entry() {
+        let home = c"HOME=/home/user";
+
+        assert_eq!(stripped(&[home, c"LD_PRELOAD=/usr/lib/spotify-adblock.so"]).unwrap(), ["HOME=/home/user"]);
+        assert_eq!(
+            stripped(&[c"LD_PRELOAD=libfoo.so:/usr/lib/spotify-adblock.so", home]).unwrap(),
+            ["LD_PRELOAD=libfoo.so", "HOME=/home/user"]
+        );
+        assert_eq!(stripped(&[home, c"LD_PRELOAD=libfoo.so"]), None);
+        assert_eq!(stripped(&[home]), None);
+        // More entries than fit are passed on unchanged
+        assert_eq!(stripped(&[home; 8]), None);
+    }
+
+    #[test]
+    fn reuses_the_prepared_entry_for_the_inherited_ld_preload() {
+        let inherited = c"LD_PRELOAD=/usr/lib/spotify-adblock.so:libfoo.so";
+        let state = state(Some((inherited, Some(c"LD_PRELOAD=libfoo.so"))));
+
+        assert_eq!(stripped_with(&[inherited], &state).unwrap(), ["LD_PRELOAD=libfoo.so"]);
+    }
+
+    #[test]
+    fn decides_whether_a_process_is_spotify() {
+        let spotify_executables = ["spotify".to_string()];
+        let is_spotify = |executable: Option<&str>| is_spotify_process_for(executable.map(Path::new), &spotify_executables);
+
+        assert!(is_spotify(Some("/opt/spotify/spotify")));
+        assert!(!is_spotify(Some("/usr/bin/xdg-open")));
+        assert!(!is_spotify(Some("/usr/bin/spotify-launcher")));
+        assert!(is_spotify(None));
+    }
+}
@@ -0,0 +1,119 @@
+//! Fault paths of the hooks and the policy that decides them
+//!
+//! A hook that cannot evaluate a call (a null argument, a string it cannot
+//! decode, an unusable config) reports a [`Fault`] and lets the call through
+//! or refuses it according to `failure_policy`. Every fault is logged with
+//! its own reason code.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::{FailurePolicies, FailurePolicy, CONFIG};
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Fault {
+    CefNullRequest,
+    CefMissingGetUrl,
+    CefNullUrl,
+    CefInvalidUrl,
+    CefMissingGetMethod,
+    CefInvalidMethod,
+    /// Data that starts like an HTTP/1.x request but does not parse as one
+    SslMalformedRequest,
+    DnsNullName,
+    DnsInvalidName,
+    /// The config file could not be used, so the allowlist is empty
+    DnsConfigUnavailable,
+}
+
+/// Whether the unusable config was reported already; it is the same fault
+/// for every lookup
+static CONFIG_FAULT_REPORTED: AtomicBool = AtomicBool::new(false);
+
+impl Fault {
+    /// Reason code in log lines
+    pub(super) const fn code(self) -> &'static str {
+        match self {
+            Self::CefNullRequest => "cef-null-request",
+            Self::CefMissingGetUrl => "cef-missing-get-url",
+            Self::CefNullUrl => "cef-null-url",
+            Self::CefInvalidUrl => "cef-invalid-url",
+            Self::CefMissingGetMethod => "cef-missing-get-method",
+            Self::CefInvalidMethod => "cef-invalid-method",
+            Self::SslMalformedRequest => "ssl-malformed-request",
+            Self::DnsNullName => "dns-null-name",
+            Self::DnsInvalidName => "dns-invalid-name",
+            Self::DnsConfigUnavailable => "dns-config-unavailable",
+        }
+    }
+
+    const fn description(self) -> &'static str {
+        match self {
+            Self::CefNullRequest => "Null request pointer in cef_urlrequest_create",
+            Self::CefMissingGetUrl => "Missing get_url function in request",
+            Self::CefNullUrl => "Request has no URL",
+            Self::CefInvalidUrl => "Request URL is not valid UTF-16",
+            Self::CefMissingGetMethod => "Missing get_method function in request",
+            Self::CefInvalidMethod => "Request method is not valid UTF-16",
+            Self::SslMalformedRequest => "Unparsable HTTP request in SSL_write",
+            Self::DnsNullName => "Name lookup without a hostname",
+            Self::DnsInvalidName => "Hostname is not valid UTF-8",
+            Self::DnsConfigUnavailable => "No usable config file; the domain allowlist is empty",
+        }
+    }
+
+    /// Log source of the hook this fault occurs in
+    const fn source(self) -> Source {
+        match self {
+            Self::CefNullRequest
+            | Self::CefMissingGetUrl
+            | Self::CefNullUrl
+            | Self::CefInvalidUrl
+            | Self::CefMissingGetMethod
+            | Self::CefInvalidMethod => Source::Cef,
+            Self::SslMalformedRequest => Source::Ssl,
+            Self::DnsNullName | Self::DnsInvalidName | Self::DnsConfigUnavailable => Source::Dns,
+        }
+    }
+
+    /// Policy of the hook this fault occurs in
+    const fn policy(self, policies: FailurePolicies) -> FailurePolicy {
+        match self {
+            Self::CefNullRequest
+            | Self::CefMissingGetUrl
+            | Self::CefNullUrl
+            | Self::CefInvalidUrl
+            | Self::CefMissingGetMethod
+            | Self::CefInvalidMethod => policies.cef,
+            Self::SslMalformedRequest => policies.ssl,
+            Self::DnsNullName | Self::DnsInvalidName | Self::DnsConfigUnavailable => policies.dns,
+        }
+    }
+}
+
+/// Log `fault` and tell whether the call it occurred in goes through
+pub(super) fn fails_open(fault: Fault) -> bool {
+    let policy = fault.policy(CONFIG.failure_policy);
+    let is_repeat = fault == Fault::DnsConfigUnavailable && CONFIG_FAULT_REPORTED.swap(true, Ordering::Relaxed);
+    if !is_repeat {
+        let message = format!("[{}] {} (failing {})", fault.code(), fault.description(), policy.as_str());
+        logging::log_warn(fault.source(), &message);
+    }
+    policy == FailurePolicy::Open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_fault_follows_the_policy_of_its_hook() {
+        let policies = FailurePolicies { cef: FailurePolicy::Closed, ssl: FailurePolicy::Open, dns: FailurePolicy::Open };
+
+        assert_eq!(Fault::CefMissingGetUrl.policy(policies), FailurePolicy::Closed);
+        assert_eq!(Fault::SslMalformedRequest.policy(policies), FailurePolicy::Open);
+        assert_eq!(Fault::DnsInvalidName.policy(policies), FailurePolicy::Open);
+        assert_eq!(Fault::DnsConfigUnavailable.policy(FailurePolicies::default()), FailurePolicy::Closed);
+    }
+}
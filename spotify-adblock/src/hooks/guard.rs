@@ -0,0 +1,156 @@
+//! Fault containment for hook bodies
+//!
+//! Every hook runs inside the process it is preloaded into, so a panic in
+//! rule code must never take Spotify down with it. The `hook!` macro runs
+//! each body through [`run`]: a body that panics is abandoned for the real
+//! function, and its hook passes every later call straight through. A
+//! thread-local marker keeps hooked functions that our own code calls (e.g.
+//! `write` from logging) from re-entering hook bodies.
+
+use std::cell::Cell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::{null, null_mut};
+use std::time::{Duration, Instant};
+
+use super::registry::HookState;
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+thread_local! {
+    /// Hook whose body this thread is running, and when the body started
+    static CURRENT_HOOK: Cell<Option<(&'static HookState, Instant)>> = const { Cell::new(None) };
+}
+
+/// Value a hook returns when it cannot call the real function
+pub trait FaultValue {
+    fn fault_value() -> Self;
+}
+
+impl FaultValue for () {
+    fn fault_value() -> Self {}
+}
+
+impl FaultValue for i32 {
+    fn fault_value() -> Self {
+        -1
+    }
+}
+
+impl FaultValue for isize {
+    fn fault_value() -> Self {
+        -1
+    }
+}
+
+impl<T> FaultValue for *mut T {
+    fn fault_value() -> Self {
+        null_mut()
+    }
+}
+
+impl<T> FaultValue for *const T {
+    fn fault_value() -> Self {
+        null()
+    }
+}
+
+/// Fault value for functions that report errors through `errno`, which is
+/// set to `ENOSYS`
+pub fn unsupported<T: FaultValue>() -> T {
+    super::socket::set_errno(libc::ENOSYS);
+    T::fault_value()
+}
+
+/// Marks the current thread as inside a hook body until dropped
+struct Reentry;
+
+impl Reentry {
+    /// `None` if the thread already is inside a hook body (or is being torn
+    /// down, when hooks should stay out of the way as well)
+    fn enter(state: &'static HookState, start: Instant) -> Option<Self> {
+        let entered = CURRENT_HOOK.try_with(|current| {
+            let is_nested = current.get().is_some();
+            if !is_nested {
+                current.set(Some((state, start)));
+            }
+            !is_nested
+        });
+        // Only construct the guard when entering: dropping one resets the marker
+        if entered.unwrap_or(false) { Some(Self) } else { None }
+    }
+}
+
+impl Drop for Reentry {
+    fn drop(&mut self) {
+        let _ = CURRENT_HOOK.try_with(|current| current.set(None));
+    }
+}
+
+/// Count a block against the hook this thread is running, if any
+pub fn count_block() {
+    if let Ok(Some((state, _))) = CURRENT_HOOK.try_with(Cell::get) {
+        state.record_block();
+    }
+}
+
+/// Name of the hook this thread is running and how long its body has run
+pub fn current_hook() -> Option<(&'static str, Duration)> {
+    let (state, start) = CURRENT_HOOK.try_with(Cell::get).ok().flatten()?;
+    Some((state.name(), start.elapsed()))
+}
+
+/// Run a hook `body`, falling back to `real` when it cannot or must not run
+///
+/// `real` is called instead of `body` for nested calls from hook code, for
+/// hooks that are disabled or faulted, and after `body` panics.
+pub fn run<T>(state: &'static HookState, body: impl FnOnce() -> T, real: impl FnOnce() -> T) -> T {
+    if !state.is_enabled() {
+        return real();
+    }
+    let start = Instant::now();
+    let Some(reentry) = Reentry::enter(state, start) else {
+        return real();
+    };
+
+    let result = catch_unwind(AssertUnwindSafe(body));
+    state.record_call(start.elapsed());
+    if result.is_err() && state.mark_faulted() {
+        logging::log_error(Source::Hooks, &format!("Hook {} panicked and now only forwards calls", state.name()));
+    }
+    drop(reentry);
+    result.unwrap_or_else(|_| real())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_and_disables_the_hook_after_a_panic() {
+        static STATE: HookState = HookState::new("test_panicking_hook");
+
+        assert_eq!(run(&STATE, || 1, || 2), 1);
+        assert_eq!(run(&STATE, || panic!("rule bug"), || 2), 2);
+        assert!(!STATE.is_enabled());
+        assert_eq!(run(&STATE, || 1, || 2), 2);
+    }
+
+    #[test]
+    fn nested_calls_go_to_the_real_function() {
+        static OUTER: HookState = HookState::new("test_outer_hook");
+        static INNER: HookState = HookState::new("test_inner_hook");
+
+        let nested = run(&OUTER, || [run(&INNER, || "body", || "real"), run(&INNER, || "body", || "real")], || ["outer real"; 2]);
+        assert_eq!(nested, ["real", "real"]);
+        assert_eq!(run(&INNER, || "body", || "real"), "body");
+    }
+
+    #[test]
+    fn unsupported_functions_fail_with_enosys() {
+        super::super::socket::set_errno(0);
+
+        assert_eq!(unsupported::<isize>(), -1);
+        // SAFETY: `__errno_location` always returns a valid pointer.
+        assert_eq!(unsafe { *libc::__errno_location() }, libc::ENOSYS);
+    }
+}
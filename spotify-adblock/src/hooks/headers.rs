@@ -0,0 +1,224 @@
+//! Request header access and header rules for `cef_urlrequest_create`
+//!
+//! Headers are copied out of the request's `cef_string_multimap_t` into
+//! owned pairs so rules can inspect them. If a `remove` or `set` rule
+//! changes anything, the whole map is written back with `set_header_map`
+//! before the real `cef_urlrequest_create` runs.
+
+use std::ptr::null_mut;
+
+use cef_sys::{
+    _cef_request_t, cef_string_multimap_alloc, cef_string_multimap_append, cef_string_multimap_free,
+    cef_string_multimap_key, cef_string_multimap_size, cef_string_multimap_value, cef_string_t,
+    cef_string_utf16_clear,
+};
+
+use crate::config::{HeaderAction, HeaderRule};
+
+use super::cef_util::{cef_string_to_string, CefStringBuf};
+
+// Requests carry a few dozen headers at most; the rest are not inspected
+const MAX_HEADERS: usize = 256;
+
+/// Owned copy of a request's header map, in the order CEF reported it
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// First value of the header `name`, compared case-insensitively
+    pub(super) fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        let before = self.0.len();
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.0.len() != before
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> bool {
+        let mut existing = self.0.iter().filter(|(key, _)| key.eq_ignore_ascii_case(name));
+        if existing.next().is_some_and(|(_, current)| current == value) && existing.next().is_none() {
+            return false;
+        }
+        self.remove(name);
+        self.0.push((name.to_string(), value.to_string()));
+        true
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(iter.into_iter().map(|(key, value)| (key.into(), value.into())).collect())
+    }
+}
+
+/// Result of running the header rules against one request
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct HeaderOutcome {
+    /// Name of the header whose `block` rule matched
+    pub(super) blocked_by: Option<String>,
+    /// Whether `remove`/`set` rules changed the headers
+    pub(super) modified: bool,
+}
+
+/// Apply `rules` in order to the headers of a request for `url`
+///
+/// Evaluation stops at the first `block` match.
+pub(super) fn apply_header_rules(rules: &[HeaderRule], url: &str, headers: &mut Headers) -> HeaderOutcome {
+    let mut outcome = HeaderOutcome::default();
+
+    for rule in rules.iter().filter(|rule| rule.url.as_ref().is_none_or(|pattern| pattern.is_match(url))) {
+        match rule.action {
+            HeaderAction::Remove => outcome.modified |= headers.remove(&rule.name),
+            HeaderAction::Set => outcome.modified |= headers.set(&rule.name, &rule.value),
+            HeaderAction::Block => {
+                let matched = headers
+                    .get(&rule.name)
+                    .is_some_and(|value| rule.matches.as_ref().is_none_or(|pattern| pattern.is_match(value)));
+                if matched {
+                    outcome.blocked_by = Some(rule.name.clone());
+                    return outcome;
+                }
+            }
+        }
+    }
+
+    outcome
+}
+
+/// Copy a request's headers out of CEF
+pub(super) fn read_headers(request: *mut _cef_request_t) -> Option<Headers> {
+    // SAFETY: Category 8 - FFI boundary. `request` is non-null and CEF owns
+    // the callback table for the duration of this hook call.
+    let get_header_map = unsafe { (*request).get_header_map }?;
+
+    // SAFETY: Category 8 - FFI boundary. The map is allocated here, filled by
+    // `get_header_map` and freed before returning.
+    unsafe {
+        let map = cef_string_multimap_alloc();
+        if map.is_null() {
+            return None;
+        }
+        get_header_map(request, map);
+
+        let count = cef_string_multimap_size(map).min(MAX_HEADERS);
+        let mut headers = Vec::with_capacity(count);
+        for index in 0..count {
+            let mut key = cef_string_t { str_: null_mut(), length: 0, dtor: None };
+            let mut value = cef_string_t { str_: null_mut(), length: 0, dtor: None };
+            if cef_string_multimap_key(map, index, &raw mut key) != 0
+                && cef_string_multimap_value(map, index, &raw mut value) != 0
+            {
+                if let (Some(key), Some(value)) = (cef_string_to_string(&key), cef_string_to_string(&value)) {
+                    headers.push((key, value));
+                }
+            }
+            cef_string_utf16_clear(&raw mut key);
+            cef_string_utf16_clear(&raw mut value);
+        }
+
+        cef_string_multimap_free(map);
+        Some(Headers(headers))
+    }
+}
+
+/// Replace a request's headers with `headers`
+pub(super) fn write_headers(request: *mut _cef_request_t, headers: &Headers) {
+    // SAFETY: Category 8 - FFI boundary. `request` is non-null and CEF owns
+    // the callback table for the duration of this hook call.
+    let Some(set_header_map) = (unsafe { (*request).set_header_map }) else {
+        return;
+    };
+
+    // SAFETY: Category 8 - FFI boundary. The map is allocated and freed here;
+    // `cef_string_multimap_append` copies the borrowed key and value strings.
+    unsafe {
+        let map = cef_string_multimap_alloc();
+        if map.is_null() {
+            return;
+        }
+        for (key, value) in &headers.0 {
+            let (key_buf, value_buf) = (CefStringBuf::new(key), CefStringBuf::new(value));
+            let (key, value) = (key_buf.as_cef_string(), value_buf.as_cef_string());
+            cef_string_multimap_append(map, &raw const key, &raw const value);
+        }
+        set_header_map(request, map);
+        cef_string_multimap_free(map);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    fn rule(name: &str, action: HeaderAction, value: &str) -> HeaderRule {
+        HeaderRule { name: name.to_string(), action, value: value.to_string(), matches: None, url: None }
+    }
+
+    fn headers() -> Headers {
+        [
+            ("Accept", "application/json"),
+            ("Content-Type", "application/x-protobuf"),
+            ("X-Client-Id", "abc"),
+            ("x-client-id", "def"),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn removes_and_sets_headers_case_insensitively() {
+        let rules = [rule("X-CLIENT-ID", HeaderAction::Remove, ""), rule("accept", HeaderAction::Set, "*/*")];
+        let mut headers = headers();
+
+        let outcome = apply_header_rules(&rules, "https://spclient.wg.spotify.com/", &mut headers);
+
+        assert_eq!(outcome, HeaderOutcome { blocked_by: None, modified: true });
+        assert_eq!(headers.get("x-client-id"), None);
+        assert_eq!(headers.get("Accept"), Some("*/*"));
+        assert_eq!(headers.get("content-type"), Some("application/x-protobuf"));
+    }
+
+    #[test]
+    fn leaves_headers_untouched_when_nothing_changes() {
+        let rules = [rule("Cookie", HeaderAction::Remove, ""), rule("Accept", HeaderAction::Set, "application/json")];
+        let mut headers = headers();
+
+        assert!(!apply_header_rules(&rules, "https://example.com/", &mut headers).modified);
+        assert_eq!(headers, self::headers());
+    }
+
+    #[test]
+    fn blocks_on_present_or_matching_headers() {
+        let mut protobuf_only = rule("content-type", HeaderAction::Block, "");
+        protobuf_only.matches = Some(Regex::new("x-proto(buf)?$").unwrap());
+        let mut json_only = rule("content-type", HeaderAction::Block, "");
+        json_only.matches = Some(Regex::new("(json|text)$").unwrap());
+
+        assert_eq!(
+            apply_header_rules(&[protobuf_only], "https://example.com/", &mut headers()).blocked_by.as_deref(),
+            Some("content-type")
+        );
+        assert_eq!(apply_header_rules(&[json_only], "https://example.com/", &mut headers()).blocked_by, None);
+        assert_eq!(
+            apply_header_rules(&[rule("Cookie", HeaderAction::Block, "")], "https://example.com/", &mut headers()),
+            HeaderOutcome::default()
+        );
+    }
+
+    #[test]
+    fn scopes_rules_to_matching_urls() {
+        let mut scoped = rule("X-Client-Id", HeaderAction::Remove, "");
+        scoped.url = Some(Regex::new(r"^https://ads\.example\.test/(ads|slot)").unwrap());
+        let rules = [scoped];
+
+        assert!(!apply_header_rules(&rules, "https://example.com/", &mut headers()).modified);
+        assert!(apply_header_rules(&rules, "https://ads.example.test/slot", &mut headers()).modified);
+    }
+}
@@ -0,0 +1,169 @@
+//! HTTP/1.x request-head parsing shared by the TLS and plain-socket hooks
+//!
+//! Both `SSL_write` and the plain-HTTP write hooks see raw request bytes.
+//! They parse them the same way and feed method, host and path into the
+//! same rule evaluation, so a rule cannot be bypassed by switching schemes.
+
+use super::rules;
+
+const REQUEST_METHODS: [&str; 7] = ["GET ", "POST ", "PUT ", "DELETE ", "PATCH ", "HEAD ", "OPTIONS "];
+
+/// Method, target and `Host` header of an HTTP/1.x request head, with
+/// whatever part of the body came along with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct HttpRequest<'a> {
+    pub(super) method: &'a str,
+    pub(super) path: &'a str,
+    pub(super) version: Option<&'a str>,
+    pub(super) host: Option<&'a str>,
+    pub(super) body: &'a [u8],
+}
+
+/// Whether `data` starts with a request method, i.e. is meant to be a
+/// request head whether or not it parses
+pub(super) fn starts_like_request(data: &[u8]) -> bool {
+    REQUEST_METHODS.iter().any(|method| data.starts_with(method.as_bytes()))
+}
+
+impl<'a> HttpRequest<'a> {
+    /// Parse the request head at the start of `data`, if it looks like one
+    pub(super) fn parse(data: &'a [u8]) -> Option<Self> {
+        let header_len = data
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(data.len(), |header_end| header_end + 4);
+        let data_str = std::str::from_utf8(&data[..header_len]).ok()?;
+
+        if !REQUEST_METHODS.iter().any(|method| data_str.starts_with(method)) {
+            return None;
+        }
+
+        let mut request_parts = data_str.lines().next()?.split_whitespace();
+        let method = request_parts.next()?;
+        let path = request_parts.next()?;
+        let version = request_parts.next();
+
+        let host = data_str
+            .lines()
+            .find(|line| line.get(..5).is_some_and(|prefix| prefix.eq_ignore_ascii_case("host:")))
+            .map(|line| line[5..].trim());
+
+        Some(Self {
+            method,
+            path,
+            version,
+            host,
+            body: &data[header_len..],
+        })
+    }
+
+    /// Whether the request line carries an `HTTP/1.0` or `HTTP/1.1` version
+    pub(super) fn is_http1(&self) -> bool {
+        self.version.is_some_and(|version| version == "HTTP/1.0" || version == "HTTP/1.1")
+    }
+
+    pub(super) fn url(&self, scheme: &str) -> String {
+        format!("{scheme}://{}{}", self.host.unwrap_or("unknown"), self.path)
+    }
+}
+
+fn is_spotify_client_host(host: &str) -> bool {
+    let host = host.strip_suffix(":443").unwrap_or(host);
+    host.eq_ignore_ascii_case("spclient.wg.spotify.com")
+        || host
+            .get(host.len().saturating_sub("-spclient.spotify.com".len())..)
+            .is_some_and(|tail| tail.eq_ignore_ascii_case("-spclient.spotify.com"))
+}
+
+/// Evaluate a parsed request against the ad rules
+pub(super) fn is_ad_related_request(request: &HttpRequest<'_>, url: &str) -> bool {
+    let path = request.path;
+    let host = request.host.unwrap_or("unknown");
+    let is_spotify_client_host = is_spotify_client_host(host);
+
+    // Ad blocking patterns - endpoints that bypass CEF
+    // Leavebehind ads
+    path.contains("leavebehind") ||
+    path.contains("leave-behind") ||
+    path.contains("podcast-ap4p/leavebehind") ||
+    path.contains("/ap4p/") ||
+    // Sponsored content
+    path.contains("sponsoredplaylist") ||
+    path.contains("/sponsored") ||
+    path.contains("/sponsor") ||
+    // Ad tracking
+    host.contains("aet.spotify.com") ||
+    path.contains("/ads/") ||
+    path.contains("/ad-logic") ||
+    path.contains("/adlogic") ||
+    // Gabo ad events
+    (path.contains("gabo-receiver-service") && (
+        path.contains("/advertisement") ||
+        path.contains("/ad-opportunity") ||
+        path.contains("/ads") ||
+        path.contains("/v3/events/") ||
+        path.contains("/public/v3/events/")
+    )) ||
+    // Ad creative delivery
+    path.contains("ad-creative") ||
+    path.contains("ad_creative") ||
+    path.contains("/promotion/") ||
+    // Partner/attribution tracking
+    host.contains("branch.io") ||
+    host.contains("adjust.com") ||
+    (is_spotify_client_host && (
+        path.contains("/partner_user_id") ||
+        path.contains("partner-userid")
+    )) ||
+    // Podcast ad networks
+    host.contains("megaphone.fm") ||
+    host.contains("art19.com") ||
+    host.contains("chartable.com") ||
+    host.contains("podsights.com") ||
+    (is_spotify_client_host && path.contains("nextAdSegment")) ||
+    // Display ad segments
+    (path.contains("display-segments") && path.contains("sponsor")) ||
+    // Ad event reporting
+    path.contains("/AdEvent") ||
+    path.contains("/EndAd") ||
+    path.contains("/AdDecision") ||
+    // Skip limits
+    (is_spotify_client_host && (
+        path.contains("skip-limit") ||
+        path.contains("skip_limit") ||
+        path.contains("/playback/restrictions")
+    )) ||
+    rules::is_ad_related_url(url) ||
+    // Ad service names inside protobuf bodies
+    rules::is_ad_related_payload(url, request.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_line_and_host_header() {
+        let request =
+            HttpRequest::parse(b"POST /v1/events HTTP/1.1\r\nContent-Length: 0\r\nhost: example.com\r\n\r\nbody")
+                .unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/events");
+        assert_eq!(request.host, Some("example.com"));
+        assert_eq!(request.body, b"body");
+        assert!(request.is_http1());
+        assert_eq!(request.url("http"), "http://example.com/v1/events");
+    }
+
+    #[test]
+    fn ignores_non_http_data() {
+        assert!(HttpRequest::parse(b"\x16\x03\x01\x02\x00").is_none());
+        assert!(HttpRequest::parse(b"SSH-2.0-OpenSSH_9.6\r\n").is_none());
+        assert!(!HttpRequest::parse(b"GET / HTTP/2\r\n\r\n").unwrap().is_http1());
+
+        assert!(HttpRequest::parse(b"GET /\xff HTTP/1.1\r\n\r\n").is_none());
+        assert!(starts_like_request(b"GET /\xff HTTP/1.1\r\n\r\n"));
+        assert!(!starts_like_request(b"\x16\x03\x01\x02\x00"));
+    }
+}
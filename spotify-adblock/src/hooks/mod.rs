@@ -1,11 +1,65 @@
 pub mod memory;
+pub mod browser;
+mod callback_patch;
+mod cef_object;
+mod cef_util;
+mod client_proxy;
+mod cosmetic;
+pub mod exec;
+mod fault;
+mod guard;
+mod headers;
+mod http_request;
 pub mod network;
+pub mod plain_http;
+mod post_data;
+pub mod registry;
 mod request_classification;
+pub mod request_stats;
 pub mod requests;
+mod resource_filter;
+mod resolved_hosts;
+mod rewrite;
 mod rules;
+pub mod socket;
 pub mod ssl;
+mod synthetic_response;
+mod websocket;
 
+use std::sync::LazyLock;
+
+use crate::config::{HookGroup, CONFIG};
+use crate::utils::process_role::PROCESS_ROLE;
+
+pub(crate) use guard::{count_block, current_hook};
+
+pub use browser::*;
+pub use exec::*;
 pub use memory::*;
 pub use network::*;
+pub use plain_http::*;
+pub use registry::{get_hook_stats, set_hook_enabled, HookStats};
+pub use request_stats::get_request_stats;
 pub use requests::*;
+pub use socket::*;
 pub use ssl::*;
+
+/// Whether hooks of `group` act in this process, per `spotify_executables`
+/// and `process_roles`
+fn is_active(group: HookGroup) -> bool {
+    is_spotify_process() && CONFIG.process_roles.is_active(*PROCESS_ROLE, group)
+}
+
+/// Prepares the hooks while the library is loaded, before any call and
+/// after every hook has registered
+#[used]
+#[unsafe(link_section = ".init_array")]
+static PREPARE_HOOKS: extern "C" fn() = {
+    extern "C" fn prepare() {
+        // Loading the configuration applies `disabled_hooks`, so disabled
+        // hooks stay inert from their first call
+        LazyLock::force(&CONFIG);
+        exec::prepare();
+    }
+    prepare
+};
@@ -1,12 +1,64 @@
-use crate::config::CONFIG;
-use libc::{addrinfo, c_char, EAI_FAIL};
+use crate::config::{HookGroup, CONFIG};
+use libc::{addrinfo, c_char, c_int, c_uchar, c_void, hostent, sigevent, size_t, EAI_FAIL};
+use std::collections::BTreeSet;
 use std::ffi::CStr;
+use std::ptr::null_mut;
+use std::sync::Mutex;
 
 use crate::hook;
+use crate::utils::logging;
+
+use super::fault::{self, Fault};
+use super::guard;
+use super::resolved_hosts;
+
+/// `h_errno` value for a name that does not exist
+const HOST_NOT_FOUND: c_int = 1;
+
+/// `getaddrinfo_a` mode that blocks until every request has completed
+const GAI_WAIT: c_int = 0;
+
+/// `gai_error` status of a request that is still being resolved
+const EAI_INPROGRESS: c_int = -100;
+
+/// Asynchronous name lookup request passed to `getaddrinfo_a` (glibc layout)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub struct gaicb {
+    ar_name: *const c_char,
+    ar_service: *const c_char,
+    ar_request: *const addrinfo,
+    ar_result: *mut addrinfo,
+    // Private glibc status read back by `gai_error`
+    __return: c_int,
+    __glibc_reserved: [c_int; 5],
+}
+
+unsafe extern "C" {
+    fn __h_errno_location() -> *mut c_int;
+}
+
+fn set_h_errno(value: c_int) {
+    // SAFETY: Category 8 - FFI boundary. `__h_errno_location` always returns
+    // a valid pointer to the calling thread's `h_errno`.
+    unsafe { *__h_errno_location() = value };
+}
+
+/// Bound-checked extraction of a hostname argument
+fn domain_from_ptr<'a>(name: *const c_char) -> Result<&'a str, Fault> {
+    if name.is_null() {
+        Err(Fault::DnsNullName)
+    } else {
+        // SAFETY: Category 8 - FFI boundary. Resolver entry points receive a
+        // NUL-terminated hostname pointer from libc callers when non-null.
+        unsafe { CStr::from_ptr(name) }.to_str().map_err(|_| Fault::DnsInvalidName)
+    }
+}
 
 /// Triple-modular redundancy approach for domain verification
 /// This implementation follows JPL safety standards for radiation hardening
-fn is_allowed_domain(domain: &str) -> bool {
+pub(super) fn is_allowed_domain(domain: &str) -> bool {
     // First implementation
     let check1 = domain.contains("dealer") || domain.contains("spotify.com") || CONFIG.allowlist.is_match(domain);
 
@@ -26,23 +78,331 @@ fn is_allowed_domain(domain: &str) -> bool {
     !(!check2 || !check1 && !check3) || (check1 && check3)
 }
 
+/// Apply the allowlist decision for one resolver entry point and log it
+fn filter_domain(function: &str, domain: Result<&str, Fault>) -> bool {
+    if !super::is_active(HookGroup::Dns) {
+        return true;
+    }
+    let domain = match domain {
+        Ok(domain) => domain,
+        Err(fault) => return fault::fails_open(fault),
+    };
+    if CONFIG.is_fallback && fault::fails_open(Fault::DnsConfigUnavailable) {
+        return true;
+    }
+    let is_allowed = is_allowed_domain(domain);
+    logging::log_resolved(function, domain, is_allowed);
+    is_allowed
+}
+
 hook! {
-    getaddrinfo(node: *const c_char, service: *const c_char, hints: *const addrinfo, res: *mut *mut addrinfo) -> i32 => REAL_GETADDRINFO {
-        // Bound-checked extraction of domain string
-        let domain = if node.is_null() {
-            ""
+    getaddrinfo(node: *const c_char, service: *const c_char, hints: *const addrinfo, res: *mut *mut addrinfo) -> i32 => REAL_GETADDRINFO else { EAI_FAIL } {
+        let domain = domain_from_ptr(node);
+
+        if filter_domain("getaddrinfo", domain) {
+            let result = REAL_GETADDRINFO(node, service, hints, res);
+            if result == 0 && !res.is_null() {
+                // SAFETY: Category 8 - FFI boundary. A successful call stores
+                // the head of the result list through the non-null `res`.
+                resolved_hosts::record_addrinfo(domain.unwrap_or_default(), unsafe { *res });
+            }
+            result
         } else {
-            // SAFETY: Category 8 - FFI boundary. `getaddrinfo` receives a
-            // NUL-terminated hostname pointer from libc callers when non-null.
-            unsafe { CStr::from_ptr(node) }.to_str().unwrap_or("")
+            EAI_FAIL
+        }
+    }
+}
+
+/// Requests handed to glibc by a `GAI_NOWAIT` call whose results are
+/// recorded once `gai_error` reports them complete, by address
+static PENDING_REQUESTS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+/// Remember the addresses a completed request resolved to
+fn record_completed(request: &gaicb) {
+    if request.__return == 0 {
+        resolved_hosts::record_addrinfo(domain_from_ptr(request.ar_name).unwrap_or_default(), request.ar_result);
+    }
+}
+
+hook! {
+    getaddrinfo_a(mode: c_int, list: *mut *mut gaicb, nitems: c_int, sevp: *mut sigevent) -> c_int => REAL_GETADDRINFO_A else { EAI_FAIL } {
+        let Ok(count) = usize::try_from(nitems) else {
+            return REAL_GETADDRINFO_A(mode, list, nitems, sevp);
         };
+        if list.is_null() || count == 0 {
+            return REAL_GETADDRINFO_A(mode, list, nitems, sevp);
+        }
+
+        // SAFETY: Category 10 - out-of-bounds. `getaddrinfo_a` receives an
+        // array of `nitems` request pointers from its caller.
+        let requests = unsafe { std::slice::from_raw_parts(list, count) };
+
+        // glibc gets a copy of the list without the blocked requests, which
+        // are completed here as failed; it skips null entries and still
+        // sends the notification when none are left.
+        let mut allowed = requests.to_vec();
+        for request in allowed.iter_mut().filter(|request| !request.is_null()) {
+            // SAFETY: Category 8 - FFI boundary. Non-null entries point to
+            // caller-owned `gaicb` structures.
+            let entry = unsafe { &mut **request };
+            if !filter_domain("getaddrinfo_a", domain_from_ptr(entry.ar_name)) {
+                entry.ar_result = null_mut();
+                entry.__return = EAI_FAIL;
+                *request = null_mut();
+            }
+        }
 
-        if is_allowed_domain(domain) {
-            println!("[+] getaddrinfo:\t\t {domain}");
-            REAL_GETADDRINFO(node, service, hints, res)
+        if mode != GAI_WAIT {
+            if let Ok(mut pending) = PENDING_REQUESTS.lock() {
+                pending.extend(allowed.iter().filter(|request| !request.is_null()).map(|&request| request as usize));
+            }
+        }
+
+        let result = REAL_GETADDRINFO_A(mode, allowed.as_mut_ptr(), nitems, sevp);
+
+        if mode == GAI_WAIT {
+            for &request in allowed.iter().filter(|request| !request.is_null()) {
+                // SAFETY: Category 8 - FFI boundary. Completed entries own a
+                // result list that is either null or valid.
+                record_completed(unsafe { &*request });
+            }
+        }
+
+        result
+    }
+}
+
+hook! {
+    gai_error(request: *mut gaicb) -> c_int => REAL_GAI_ERROR else { EAI_FAIL } {
+        let status = REAL_GAI_ERROR(request);
+        if status != EAI_INPROGRESS && !request.is_null() {
+            let was_pending = PENDING_REQUESTS.lock().is_ok_and(|mut pending| pending.remove(&(request as usize)));
+            if was_pending {
+                // SAFETY: Category 8 - FFI boundary. glibc has finished with
+                // this caller-owned request and filled in its result.
+                record_completed(unsafe { &*request });
+            }
+        }
+        status
+    }
+}
+
+hook! {
+    gethostbyname(name: *const c_char) -> *mut hostent => REAL_GETHOSTBYNAME {
+        let domain = domain_from_ptr(name);
+
+        if filter_domain("gethostbyname", domain) {
+            let result = REAL_GETHOSTBYNAME(name);
+            resolved_hosts::record_hostent(domain.unwrap_or_default(), result);
+            result
         } else {
-            println!("[-] getaddrinfo:\t\t {domain}");
-            EAI_FAIL
+            set_h_errno(HOST_NOT_FOUND);
+            null_mut()
+        }
+    }
+}
+
+hook! {
+    gethostbyname2(name: *const c_char, af: c_int) -> *mut hostent => REAL_GETHOSTBYNAME2 {
+        let domain = domain_from_ptr(name);
+
+        if filter_domain("gethostbyname2", domain) {
+            let result = REAL_GETHOSTBYNAME2(name, af);
+            resolved_hosts::record_hostent(domain.unwrap_or_default(), result);
+            result
+        } else {
+            set_h_errno(HOST_NOT_FOUND);
+            null_mut()
         }
     }
 }
+
+hook! {
+    gethostbyname_r(name: *const c_char, ret: *mut hostent, buf: *mut c_char, buflen: size_t, result: *mut *mut hostent, h_errnop: *mut c_int) -> c_int => REAL_GETHOSTBYNAME_R else { libc::ENOSYS } {
+        let domain = domain_from_ptr(name);
+
+        if filter_domain("gethostbyname_r", domain) {
+            let status = REAL_GETHOSTBYNAME_R(name, ret, buf, buflen, result, h_errnop);
+            if status == 0 && !result.is_null() {
+                // SAFETY: Category 8 - FFI boundary. `result` is the caller's
+                // non-null out-pointer that the real call just filled in.
+                resolved_hosts::record_hostent(domain.unwrap_or_default(), unsafe { *result });
+            }
+            status
+        } else {
+            // glibc reports a missing host as success with a null result
+            // and the reason in `*h_errnop`
+            if !result.is_null() {
+                // SAFETY: Category 8 - FFI boundary. Non-null out-pointer
+                // supplied by the caller.
+                unsafe { *result = null_mut() };
+            }
+            if !h_errnop.is_null() {
+                // SAFETY: Category 8 - FFI boundary. Non-null out-pointer
+                // supplied by the caller.
+                unsafe { *h_errnop = HOST_NOT_FOUND };
+            }
+            0
+        }
+    }
+}
+
+hook! {
+    res_query(dname: *const c_char, class: c_int, kind: c_int, answer: *mut c_uchar, anslen: c_int) -> c_int => REAL_RES_QUERY else { guard::unsupported() } {
+        if filter_domain("res_query", domain_from_ptr(dname)) {
+            REAL_RES_QUERY(dname, class, kind, answer, anslen)
+        } else {
+            set_h_errno(HOST_NOT_FOUND);
+            -1
+        }
+    }
+}
+
+// `<resolv.h>` renames `res_query`/`res_nquery` to these symbols, so most
+// callers link against the prefixed names.
+hook! {
+    __res_query(dname: *const c_char, class: c_int, kind: c_int, answer: *mut c_uchar, anslen: c_int) -> c_int => REAL___RES_QUERY else { guard::unsupported() } {
+        if filter_domain("res_query", domain_from_ptr(dname)) {
+            REAL___RES_QUERY(dname, class, kind, answer, anslen)
+        } else {
+            set_h_errno(HOST_NOT_FOUND);
+            -1
+        }
+    }
+}
+
+// The resolver state's own `res_h_errno` field is not part of a stable
+// layout, so blocked queries report through the thread's `h_errno` only.
+hook! {
+    res_nquery(statp: *mut c_void, dname: *const c_char, class: c_int, kind: c_int, answer: *mut c_uchar, anslen: c_int) -> c_int => REAL_RES_NQUERY else { guard::unsupported() } {
+        if filter_domain("res_nquery", domain_from_ptr(dname)) {
+            REAL_RES_NQUERY(statp, dname, class, kind, answer, anslen)
+        } else {
+            set_h_errno(HOST_NOT_FOUND);
+            -1
+        }
+    }
+}
+
+hook! {
+    __res_nquery(statp: *mut c_void, dname: *const c_char, class: c_int, kind: c_int, answer: *mut c_uchar, anslen: c_int) -> c_int => REAL___RES_NQUERY else { guard::unsupported() } {
+        if filter_domain("res_nquery", domain_from_ptr(dname)) {
+            REAL___RES_NQUERY(statp, dname, class, kind, answer, anslen)
+        } else {
+            set_h_errno(HOST_NOT_FOUND);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::super::exec::act_as_spotify_process;
+    use super::*;
+
+    const BLOCKED_HOST: &str = "ads.doubleclick.net";
+
+    fn h_errno() -> c_int {
+        // SAFETY: `__h_errno_location` always returns a valid pointer.
+        unsafe { *__h_errno_location() }
+    }
+
+    #[test]
+    fn getaddrinfo_fails_blocked_hosts_with_eai_fail() {
+        act_as_spotify_process();
+        let name = CString::new(BLOCKED_HOST).unwrap();
+        let mut result = null_mut();
+
+        assert_eq!(getaddrinfo(name.as_ptr(), std::ptr::null(), std::ptr::null(), &raw mut result), EAI_FAIL);
+        assert!(result.is_null());
+    }
+
+    #[test]
+    fn getaddrinfo_a_fails_blocked_requests_in_place() {
+        act_as_spotify_process();
+        let name = CString::new(BLOCKED_HOST).unwrap();
+        let mut request = gaicb {
+            ar_name: name.as_ptr(),
+            ar_service: std::ptr::null(),
+            ar_request: std::ptr::null(),
+            ar_result: null_mut(),
+            __return: 0,
+            __glibc_reserved: [0; 5],
+        };
+        let mut list = [&raw mut request];
+
+        assert_eq!(getaddrinfo_a(GAI_WAIT, list.as_mut_ptr(), 1, null_mut()), 0);
+        assert_eq!(request.__return, EAI_FAIL);
+        assert!(request.ar_result.is_null());
+        assert_eq!(request.ar_name, name.as_ptr());
+
+        // Asynchronous requests are never handed to glibc either
+        request.__return = 0;
+        assert_eq!(getaddrinfo_a(1, list.as_mut_ptr(), 1, null_mut()), 0);
+        assert_eq!(gai_error(&raw mut request), EAI_FAIL);
+        assert_eq!(request.ar_name, name.as_ptr());
+        assert!(PENDING_REQUESTS.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn gethostbyname_variants_set_h_errno_for_blocked_hosts() {
+        let name = CString::new(BLOCKED_HOST).unwrap();
+
+        set_h_errno(0);
+        assert!(gethostbyname(name.as_ptr()).is_null());
+        assert_eq!(h_errno(), HOST_NOT_FOUND);
+
+        set_h_errno(0);
+        assert!(gethostbyname2(name.as_ptr(), libc::AF_INET6).is_null());
+        assert_eq!(h_errno(), HOST_NOT_FOUND);
+    }
+
+    #[test]
+    fn gethostbyname_r_reports_blocked_hosts_through_out_parameters() {
+        let name = CString::new(BLOCKED_HOST).unwrap();
+        // SAFETY: all-zero bytes are a valid `hostent`.
+        let mut entry: hostent = unsafe { std::mem::zeroed() };
+        let mut buffer: [c_char; 1024] = [0; 1024];
+        let mut result = &raw mut entry;
+        let mut error = 0;
+
+        let status = gethostbyname_r(
+            name.as_ptr(),
+            &raw mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &raw mut result,
+            &raw mut error,
+        );
+
+        assert_eq!(status, 0);
+        assert!(result.is_null());
+        assert_eq!(error, HOST_NOT_FOUND);
+    }
+
+    #[test]
+    fn res_query_variants_return_negative_for_blocked_hosts() {
+        act_as_spotify_process();
+        let name = CString::new(BLOCKED_HOST).unwrap();
+        let mut answer = [0; 512];
+        let length = c_int::try_from(answer.len()).unwrap();
+
+        set_h_errno(0);
+        assert_eq!(res_query(name.as_ptr(), 1, 1, answer.as_mut_ptr(), length), -1);
+        assert_eq!(h_errno(), HOST_NOT_FOUND);
+
+        set_h_errno(0);
+        assert_eq!(__res_query(name.as_ptr(), 1, 1, answer.as_mut_ptr(), length), -1);
+        assert_eq!(h_errno(), HOST_NOT_FOUND);
+
+        set_h_errno(0);
+        assert_eq!(res_nquery(null_mut(), name.as_ptr(), 1, 1, answer.as_mut_ptr(), length), -1);
+        assert_eq!(h_errno(), HOST_NOT_FOUND);
+
+        set_h_errno(0);
+        assert_eq!(__res_nquery(null_mut(), name.as_ptr(), 1, 1, answer.as_mut_ptr(), length), -1);
+        assert_eq!(h_errno(), HOST_NOT_FOUND);
+    }
+}
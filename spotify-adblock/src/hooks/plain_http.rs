@@ -0,0 +1,294 @@
+//! Plain-HTTP request inspection for unencrypted TCP connections
+//!
+//! Podcast enclosures and tracking redirects are sometimes fetched over
+//! `http://`, which neither `SSL_write` nor the hostname check can classify
+//! by path. `connect` registers outgoing TCP sockets that do not use the
+//! HTTPS port. The first write on such a socket decides whether it speaks
+//! HTTP/1.x; sockets that do stay watched, and every write starting a new
+//! request head is evaluated with the same rules as TLS traffic, so a
+//! keep-alive connection cannot carry an ad request after an allowed one.
+//! Blocked writes fail with `ECONNRESET`. A descriptor is forgotten when it
+//! is closed or connected again, so a reused number is never inspected under
+//! the previous connection's host.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use libc::{c_int, c_void, iovec, size_t, ssize_t, ECONNRESET, SOCK_STREAM};
+
+use crate::hook;
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+use super::guard;
+use super::http_request::{is_ad_related_request, starts_like_request, HttpRequest};
+use super::socket::{set_errno, socket_type};
+
+// Enough for a request line and the headers that identify it
+const MAX_INSPECT_LEN: usize = 4096;
+
+// Bounded so sockets that are never closed cannot grow the table forever
+const MAX_WATCHED_SOCKETS: usize = 1024;
+
+/// A connected socket whose writes are inspected
+struct WatchedSocket {
+    /// Hostname the peer address was resolved from, if known
+    resolved_host: Option<String>,
+    /// Whether its first write was an HTTP/1.x request head
+    is_http: bool,
+}
+
+static WATCHED_SOCKETS: LazyLock<Mutex<HashMap<c_int, WatchedSocket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Mirror of the table size so unrelated writes skip the lock entirely
+static WATCHED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Register a freshly connected TCP socket for request inspection
+pub(super) fn watch_socket(socket: c_int, host: Option<String>) {
+    if !is_tcp_socket(socket) {
+        return;
+    }
+    let Ok(mut watched) = WATCHED_SOCKETS.lock() else {
+        return;
+    };
+    if watched.len() >= MAX_WATCHED_SOCKETS && !watched.contains_key(&socket) {
+        return;
+    }
+    watched.insert(socket, WatchedSocket { resolved_host: host, is_http: false });
+    WATCHED_COUNT.store(watched.len(), Ordering::Relaxed);
+}
+
+/// Stop watching a descriptor that is reconnected or closed, so its entry
+/// cannot apply to a later socket with the same number
+pub(super) fn forget_socket(socket: c_int) {
+    let _ = take_watched(socket);
+}
+
+/// Remove a socket from the table, returning it if it was being watched
+fn take_watched(socket: c_int) -> Option<WatchedSocket> {
+    if WATCHED_COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let mut watched = WATCHED_SOCKETS.lock().ok()?;
+    let socket = watched.remove(&socket);
+    WATCHED_COUNT.store(watched.len(), Ordering::Relaxed);
+    socket
+}
+
+fn is_tcp_socket(socket: c_int) -> bool {
+    socket_type(socket) == Some(SOCK_STREAM)
+}
+
+/// The HTTP/1.x request head at the start of `data`, taking the host from
+/// `resolved_host` when it has no `Host` header
+fn parse_plain_request<'a>(data: &'a [u8], resolved_host: Option<&'a str>) -> Option<HttpRequest<'a>> {
+    if !starts_like_request(data) {
+        return None;
+    }
+    let mut request = HttpRequest::parse(data)?;
+    if !request.is_http1() {
+        return None;
+    }
+    if request.host.is_none() {
+        request.host = resolved_host;
+    }
+    Some(request)
+}
+
+/// Evaluate a plain request, returning it if blocked
+fn should_block_plain_request(request: &HttpRequest<'_>) -> Option<String> {
+    let url = request.url("http");
+    is_ad_related_request(request, &url).then(|| format!("{} {url}", request.method))
+}
+
+/// Inspect a write on a watched socket; `true` means fail the write
+fn inspect_write(socket: c_int, data: &[u8]) -> bool {
+    let Some((resolved_host, is_http)) = WATCHED_SOCKETS
+        .lock()
+        .ok()
+        .and_then(|watched| watched.get(&socket).map(|watched| (watched.resolved_host.clone(), watched.is_http)))
+    else {
+        return false;
+    };
+
+    let Some(request) = parse_plain_request(data, resolved_host.as_deref()) else {
+        // Bodies and the rest of requests already seen pass; a socket whose
+        // first write is not HTTP/1.x is not watched any longer
+        if !is_http {
+            forget_socket(socket);
+        }
+        return false;
+    };
+    if !is_http {
+        // The descriptor may have been closed and reused since `connect`
+        if !is_tcp_socket(socket) {
+            forget_socket(socket);
+            return false;
+        }
+        if let Ok(mut watched) = WATCHED_SOCKETS.lock() {
+            if let Some(watched) = watched.get_mut(&socket) {
+                watched.is_http = true;
+            }
+        }
+    }
+
+    should_block_plain_request(&request).is_some_and(|blocked_url| {
+        logging::log_blocked(Source::Http, "BLOCKED HTTP", "HTTP", &blocked_url);
+        true
+    })
+}
+
+/// View at most `MAX_INSPECT_LEN` bytes of a caller buffer
+fn inspect_slice<'a>(buf: *const c_void, len: size_t) -> Option<&'a [u8]> {
+    if buf.is_null() || len == 0 {
+        return None;
+    }
+    // SAFETY: Category 10 - out-of-bounds. Write calls receive a buffer of at
+    // least `len` bytes; the view is capped to that length.
+    Some(unsafe { std::slice::from_raw_parts(buf.cast::<u8>(), len.min(MAX_INSPECT_LEN)) })
+}
+
+hook! {
+    send(socket: c_int, buf: *const c_void, len: size_t, flags: c_int) -> ssize_t => REAL_SEND else { guard::unsupported() } {
+        if WATCHED_COUNT.load(Ordering::Relaxed) != 0
+            && inspect_slice(buf, len).is_some_and(|data| inspect_write(socket, data))
+        {
+            set_errno(ECONNRESET);
+            return -1;
+        }
+
+        REAL_SEND(socket, buf, len, flags)
+    }
+}
+
+hook! {
+    write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t => REAL_WRITE else { guard::unsupported() } {
+        // Every write in the process lands here, so stay on the atomic fast
+        // path unless a connected socket is watched
+        if WATCHED_COUNT.load(Ordering::Relaxed) != 0
+            && inspect_slice(buf, count).is_some_and(|data| inspect_write(fd, data))
+        {
+            set_errno(ECONNRESET);
+            return -1;
+        }
+
+        REAL_WRITE(fd, buf, count)
+    }
+}
+
+hook! {
+    writev(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t => REAL_WRITEV else { guard::unsupported() } {
+        if WATCHED_COUNT.load(Ordering::Relaxed) != 0 && !iov.is_null() && iovcnt > 0 {
+            let count = usize::try_from(iovcnt).unwrap_or(0);
+            // SAFETY: Category 10 - out-of-bounds. `writev` receives `iovcnt`
+            // caller-owned vectors.
+            let vectors = unsafe { std::slice::from_raw_parts(iov, count) };
+
+            // The request head may be split across vectors
+            let mut head = Vec::with_capacity(MAX_INSPECT_LEN);
+            for vector in vectors {
+                if let Some(data) = inspect_slice(vector.iov_base, vector.iov_len) {
+                    let remaining = MAX_INSPECT_LEN - head.len();
+                    head.extend_from_slice(&data[..data.len().min(remaining)]);
+                }
+                if head.len() == MAX_INSPECT_LEN {
+                    break;
+                }
+            }
+
+            if !head.is_empty() && inspect_write(fd, &head) {
+                set_errno(ECONNRESET);
+                return -1;
+            }
+        }
+
+        REAL_WRITEV(fd, iov, iovcnt)
+    }
+}
+
+hook! {
+    close(fd: c_int) -> c_int => REAL_CLOSE else { guard::unsupported() } {
+        forget_socket(fd);
+        REAL_CLOSE(fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::{AsRawFd, IntoRawFd};
+
+    use super::*;
+
+    fn write_request(fd: c_int, request: &[u8]) -> ssize_t {
+        write(fd, request.as_ptr().cast(), request.len())
+    }
+
+    fn blocked_request(data: &[u8], resolved_host: Option<&str>) -> Option<String> {
+        parse_plain_request(data, resolved_host).as_ref().and_then(should_block_plain_request)
+    }
+
+    #[test]
+    fn classifies_plain_requests_with_resolved_host_fallback() {
+        assert!(
+            blocked_request(b"GET /v1/podcast/nextAdSegment HTTP/1.1\r\n\r\n", Some("spclient.wg.spotify.com"))
+                .is_some()
+        );
+        assert!(blocked_request(b"GET /episode.mp3 HTTP/1.1\r\nHost: traffic.megaphone.fm\r\n\r\n", None).is_some());
+        assert!(blocked_request(b"GET /episode.mp3 HTTP/1.1\r\nHost: example.com\r\n\r\n", None).is_none());
+        assert!(blocked_request(b"GET /ads/ HTTP/2\r\nHost: example.com\r\n\r\n", None).is_none());
+    }
+
+    #[test]
+    fn resets_blocked_first_writes_on_connected_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let fd = stream.as_raw_fd();
+        watch_socket(fd, None);
+
+        let result = write_request(fd, b"GET /ads/v1/slot HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+        assert_eq!(result, -1);
+        assert_eq!(std::io::Error::last_os_error().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn inspects_every_request_on_a_keep_alive_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let fd = stream.as_raw_fd();
+        watch_socket(fd, None);
+
+        let allowed = b"POST /tracks HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\n";
+        assert_eq!(write_request(fd, allowed), ssize_t::try_from(allowed.len()).unwrap());
+        assert_eq!(write_request(fd, b"body"), 4);
+
+        let later = b"GET /ads/v1/slot HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(write_request(fd, later), -1);
+        assert_eq!(std::io::Error::last_os_error().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn stops_watching_sockets_that_do_not_speak_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let fd = stream.as_raw_fd();
+        watch_socket(fd, None);
+
+        assert_eq!(write_request(fd, b"\x16\x03\x01"), 3);
+        assert!(take_watched(fd).is_none());
+    }
+
+    #[test]
+    fn forgets_sockets_when_they_are_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = TcpStream::connect(listener.local_addr().unwrap()).unwrap().into_raw_fd();
+        watch_socket(fd, Some("example.com".to_string()));
+
+        assert_eq!(close(fd), 0);
+        assert!(take_watched(fd).is_none());
+    }
+}
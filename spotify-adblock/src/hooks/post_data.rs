@@ -0,0 +1,101 @@
+//! Request body extraction from CEF `cef_post_data_t` objects
+//!
+//! `cef_urlrequest_create` receives the upload body as a list of post data
+//! elements, each holding either bytes or a file path. The rule engine only
+//! needs enough of it to recognise event names, so reading is size-capped.
+
+use std::fs::File;
+use std::io::Read;
+use std::ptr::null_mut;
+
+use cef_sys::{_cef_post_data_element_t, _cef_request_t, cef_postdataelement_type_t};
+
+use crate::hooks::memory::cef_string_userfree_utf16_free;
+
+use super::cef_util::{cef_userfree_utf16_to_string, release};
+
+/// Upper bound on body bytes copied out of a request
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+// Event batches use a handful of elements; anything larger is not inspected
+const MAX_ELEMENTS: usize = 64;
+
+/// Copy up to `MAX_BODY_LEN` bytes of a request's upload body
+///
+/// Returns `None` when the request carries no post data at all.
+pub(super) fn read_post_data(request: *mut _cef_request_t) -> Option<Vec<u8>> {
+    // SAFETY: Category 8 - FFI boundary. `request` is non-null and CEF owns
+    // the callback table for the duration of this hook call.
+    let post_data = unsafe { (*request).get_post_data.map(|get_post_data| get_post_data(request)) }?;
+    if post_data.is_null() {
+        return None;
+    }
+
+    let mut body = Vec::new();
+    // SAFETY: Category 8 - FFI boundary. `post_data` is a non-null object that
+    // we hold a reference to until the `release` below.
+    unsafe {
+        let element_count = (*post_data).get_element_count.map_or(0, |get_count| get_count(post_data));
+        let mut count = element_count.min(MAX_ELEMENTS);
+        if let Some(get_elements) = (*post_data).get_elements.filter(|_| count > 0) {
+            let mut elements: Vec<*mut _cef_post_data_element_t> = vec![null_mut(); count];
+            get_elements(post_data, &raw mut count, elements.as_mut_ptr());
+
+            for &element in elements.iter().take(count.min(elements.len())) {
+                if !element.is_null() {
+                    read_element(element, &mut body);
+                    release(element);
+                }
+            }
+        }
+    }
+    release(post_data);
+
+    Some(body)
+}
+
+/// Append one element's bytes (or the start of its file) to `body`
+fn read_element(element: *mut _cef_post_data_element_t, body: &mut Vec<u8>) {
+    let remaining = MAX_BODY_LEN.saturating_sub(body.len());
+    if remaining == 0 {
+        return;
+    }
+
+    // SAFETY: Category 8 - FFI boundary. `element` is a non-null post data
+    // element returned by `get_elements` and still referenced by us.
+    let element_type = unsafe { (*element).get_type.map(|get_type| get_type(element)) };
+    match element_type {
+        Some(cef_postdataelement_type_t::PDE_TYPE_BYTES) => {
+            // SAFETY: Category 10 - out-of-bounds. `get_bytes` writes at most
+            // `size` bytes into a buffer allocated with exactly that length.
+            unsafe {
+                let available = (*element).get_bytes_count.map_or(0, |get_count| get_count(element));
+                let size = available.min(remaining);
+                let Some(get_bytes) = (*element).get_bytes.filter(|_| size > 0) else {
+                    return;
+                };
+                let mut chunk = vec![0u8; size];
+                let copied = get_bytes(element, size, chunk.as_mut_ptr().cast());
+                chunk.truncate(copied);
+                body.extend_from_slice(&chunk);
+            }
+        }
+        Some(cef_postdataelement_type_t::PDE_TYPE_FILE) => {
+            // SAFETY: Category 8 - FFI boundary. `get_file` returns a userfree
+            // string that we free after converting it.
+            let file_name = unsafe { (*element).get_file.map(|get_file| get_file(element)) };
+            let Some(file_name) = file_name else {
+                return;
+            };
+            let path = cef_userfree_utf16_to_string(file_name);
+            cef_string_userfree_utf16_free(file_name);
+
+            if let Some(file) = path.and_then(|path| File::open(path).ok()) {
+                let limit = u64::try_from(remaining).unwrap_or(u64::MAX);
+                // A short or failed read simply leaves less to inspect
+                let _ = file.take(limit).read_to_end(body);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,256 @@
+//! Registry of every hook, with its state and counters
+//!
+//! Each `hook!` registers a [`HookState`] while the library is being loaded,
+//! so every interposed function is known before it is first called. The
+//! registry records which library a hook's real symbol resolved to and
+//! counts calls, blocks and time spent per hook. A misbehaving hook can be
+//! switched off with `disabled_hooks` in the config, or at runtime through
+//! [`set_hook_enabled`] and the exported `spotify_adblock_set_hook_enabled`
+//! (e.g. from a debugger); a disabled hook only forwards calls.
+
+use std::ffi::{c_char, c_int, c_void, CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+/// State and counters of one hook
+#[derive(Debug)]
+pub struct HookState {
+    name: &'static str,
+    /// Next registered hook; the registry is an intrusive list so that
+    /// registering never allocates
+    next: AtomicPtr<Self>,
+    enabled: AtomicBool,
+    /// Set once the body panicked; the hook only forwards from then on
+    faulted: AtomicBool,
+    calls: AtomicU64,
+    blocks: AtomicU64,
+    nanos: AtomicU64,
+    /// Library the real symbol was found in, set on first call
+    library: OnceLock<Option<String>>,
+}
+
+impl HookState {
+    #[must_use]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            next: AtomicPtr::new(null_mut()),
+            enabled: AtomicBool::new(true),
+            faulted: AtomicBool::new(false),
+            calls: AtomicU64::new(0),
+            blocks: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+            library: OnceLock::new(),
+        }
+    }
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Whether calls run the hook body at all
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed) && !self.faulted.load(Ordering::Relaxed)
+    }
+
+    /// Disable the hook after its body panicked; `true` the first time
+    pub fn mark_faulted(&self) -> bool {
+        !self.faulted.swap(true, Ordering::Relaxed)
+    }
+
+    /// Account for one run of the body
+    pub fn record_call(&self, elapsed: Duration) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn record_block(&self) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of one hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookStats {
+    pub name: &'static str,
+    /// Library the real function resolved to; `None` until the hook is
+    /// first called, or when the symbol is missing
+    pub library: Option<String>,
+    pub enabled: bool,
+    /// Whether the hook was disabled because its body panicked
+    pub faulted: bool,
+    pub calls: u64,
+    pub blocks: u64,
+    /// Time spent in the hook body, including the real function it calls
+    pub time: Duration,
+}
+
+/// Most recently registered hook
+static HOOKS: AtomicPtr<HookState> = AtomicPtr::new(null_mut());
+
+/// Add a hook to the registry; called once per hook at load time
+pub fn register(state: &'static HookState) {
+    let state_ptr = std::ptr::from_ref(state).cast_mut();
+    let mut head = HOOKS.load(Ordering::Acquire);
+    loop {
+        state.next.store(head, Ordering::Relaxed);
+        match HOOKS.compare_exchange_weak(head, state_ptr, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return,
+            Err(current) => head = current,
+        }
+    }
+}
+
+fn registered() -> impl Iterator<Item = &'static HookState> {
+    let mut next = HOOKS.load(Ordering::Acquire);
+    std::iter::from_fn(move || {
+        // SAFETY: Category 8 - FFI boundary. The list only ever links
+        // `'static` hook states and is never unlinked.
+        let state = unsafe { next.as_ref() }?;
+        next = state.next.load(Ordering::Relaxed);
+        Some(state)
+    })
+}
+
+fn find(name: &str) -> Option<&'static HookState> {
+    registered().find(|state| state.name == name)
+}
+
+/// Look up the real function behind hook `name` and record where it lives
+///
+/// Returns null, after logging it, when no later object defines the symbol.
+pub fn resolve(name: &CStr) -> *mut c_void {
+    // SAFETY: `dlsym` is called with `RTLD_NEXT` and a valid NUL-terminated
+    // symbol name.
+    let function_pointer = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr()) };
+    let name = name.to_str().unwrap_or_default();
+    let library = if function_pointer.is_null() {
+        logging::log_error(Source::Hooks, &format!("Unable to find function \"{name}\""));
+        None
+    } else {
+        library_of(function_pointer).map(|library| library.to_string_lossy().into_owned())
+    };
+    if let Some(state) = find(name) {
+        let _ = state.library.set(library);
+    }
+    function_pointer
+}
+
+/// Path of the shared object containing `address`, as the dynamic linker
+/// reports it
+pub(super) fn library_of(address: *const c_void) -> Option<PathBuf> {
+    // SAFETY: Category 8 - FFI boundary. `dladdr` fills the zeroed `Dl_info`
+    // for any address; `dli_fname` is checked for null.
+    unsafe {
+        let mut info: libc::Dl_info = std::mem::zeroed();
+        if libc::dladdr(address, &raw mut info) == 0 || info.dli_fname.is_null() {
+            return None;
+        }
+        Some(PathBuf::from(OsStr::from_bytes(CStr::from_ptr(info.dli_fname).to_bytes())))
+    }
+}
+
+/// Snapshot of every registered hook, sorted by name
+#[must_use]
+pub fn get_hook_stats() -> Vec<HookStats> {
+    let mut stats = registered()
+        .map(|state| HookStats {
+            name: state.name,
+            library: state.library.get().cloned().flatten(),
+            enabled: state.enabled.load(Ordering::Relaxed),
+            faulted: state.faulted.load(Ordering::Relaxed),
+            calls: state.calls.load(Ordering::Relaxed),
+            blocks: state.blocks.load(Ordering::Relaxed),
+            time: Duration::from_nanos(state.nanos.load(Ordering::Relaxed)),
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by_key(|stats| stats.name);
+    stats
+}
+
+/// Switch hook `name` on or off, returning `false` for an unknown hook
+#[must_use]
+pub fn set_hook_enabled(name: &str, enabled: bool) -> bool {
+    find(name).is_some_and(|state| {
+        state.enabled.store(enabled, Ordering::Relaxed);
+        true
+    })
+}
+
+/// Apply the config's `disabled_hooks`
+pub fn disable_configured(names: &[String]) {
+    for name in names {
+        if set_hook_enabled(name, false) {
+            logging::log_info(Source::Hooks, &format!("Hook {name} disabled by config"));
+        } else {
+            logging::log_warn(Source::Hooks, &format!("Unknown hook in disabled_hooks: {name}"));
+        }
+    }
+}
+
+/// C entry point of [`set_hook_enabled`]; returns 0, or -1 for an unknown hook
+#[unsafe(no_mangle)]
+pub extern "C" fn spotify_adblock_set_hook_enabled(name: *const c_char, enabled: c_int) -> c_int {
+    if name.is_null() {
+        return -1;
+    }
+    // SAFETY: Category 8 - FFI boundary. Callers pass a NUL-terminated name.
+    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap_or_default();
+    if set_hook_enabled(name, enabled != 0) { 0 } else { -1 }
+}
+
+/// Log one line per hook with its state and counters
+#[unsafe(no_mangle)]
+pub extern "C" fn spotify_adblock_log_hooks() {
+    for stats in get_hook_stats() {
+        let state = match (stats.enabled, stats.faulted) {
+            (_, true) => "faulted",
+            (true, false) => "enabled",
+            (false, false) => "disabled",
+        };
+        logging::log_info(Source::Hooks, &format!(
+            "HOOK {}: {state}, {} calls, {} blocked, {:?}, {}",
+            stats.name,
+            stats.calls,
+            stats.blocks,
+            stats.time,
+            stats.library.as_deref().unwrap_or("(unresolved)")
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_are_registered_at_load_time() {
+        let stats = get_hook_stats();
+        let connect = stats.iter().find(|stats| stats.name == "connect").unwrap();
+        assert!(connect.enabled);
+        assert!(stats.iter().any(|stats| stats.name == "SSL_write"));
+        assert!(stats.is_sorted_by_key(|stats| stats.name));
+    }
+
+    #[test]
+    fn hooks_can_be_toggled_by_name() {
+        static STATE: HookState = HookState::new("test_toggled_hook");
+        register(&STATE);
+
+        assert!(set_hook_enabled("test_toggled_hook", false));
+        assert!(!STATE.is_enabled());
+        assert_eq!(spotify_adblock_set_hook_enabled(c"test_toggled_hook".as_ptr(), 1), 0);
+        assert!(STATE.is_enabled());
+        assert!(!set_hook_enabled("no_such_hook", false));
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(clippy::struct_excessive_bools)]
 
+use regex::RegexSet;
+
 use super::rules;
 
 pub(super) struct UrlClassification {
@@ -11,19 +13,52 @@ pub(super) struct UrlClassification {
     pub(super) is_gabo_event_post: bool,
 }
 
-pub(super) fn classify_url(url: &str, method: &str) -> UrlClassification {
-    let is_gabo_event_post = is_gabo_event_post(url, method);
+pub(super) fn classify_url(url: &str, method: &str, body: Option<&[u8]>) -> UrlClassification {
+    let is_event_batch = is_gabo_event_post(url, method);
+    // Event batches are judged by their payload; one we could not read
+    // stays blocked wholesale
+    let is_gabo_event_post = is_event_batch && body.is_none_or(rules::is_ad_related_body);
+    let is_gabo = if is_event_batch {
+        !is_gabo_event_post && !is_gabo_ad_route(url)
+    } else {
+        is_allowed_gabo_service(url)
+    };
 
     UrlClassification {
         is_discord_rpc: is_discord_rpc(url),
-        is_gabo: is_allowed_gabo_service(url) && !is_gabo_event_post,
+        is_gabo,
         is_dealer: url.contains("dealer"),
-        is_ad_related: rules::is_ad_related_url(url),
+        is_ad_related: rules::is_ad_related_url(url) || body.is_some_and(|body| rules::is_ad_related_payload(url, body)),
         is_product_state: is_product_state(url),
         is_gabo_event_post,
     }
 }
 
+/// Decision of the URL rules, with the log context for it
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum UrlVerdict {
+    Allow(&'static str),
+    Block(&'static str),
+}
+
+/// Apply the URL rules shared by every CEF request path
+pub(super) fn url_verdict(classification: &UrlClassification, url: &str, denylist: &RegexSet) -> UrlVerdict {
+    if classification.is_discord_rpc {
+        UrlVerdict::Allow("DISCORD RPC")
+    } else if classification.is_gabo || classification.is_dealer {
+        UrlVerdict::Allow("SERVICE")
+    } else if classification.is_gabo_event_post {
+        // Aggressive Gabo POST events (payload carries ad data)
+        UrlVerdict::Block("BLOCKED GABO POST")
+    } else if classification.is_ad_related {
+        UrlVerdict::Block("BLOCKED AD")
+    } else if denylist.is_match(url) {
+        UrlVerdict::Block("BLOCKED CONFIG")
+    } else {
+        UrlVerdict::Allow("ALLOWED")
+    }
+}
+
 fn is_discord_rpc(url: &str) -> bool {
     url.contains("discord")
         || url.contains("discordapp")
@@ -35,14 +70,18 @@ fn is_discord_rpc(url: &str) -> bool {
 
 fn is_allowed_gabo_service(url: &str) -> bool {
     url.contains("gabo-receiver-service")
-        && !url.contains("/advertisement")
-        && !url.contains("/ad-opportunity")
-        && !url.contains("/adlogic")
-        && !url.contains("/ads")
+        && !is_gabo_ad_route(url)
         && !url.contains("/v3/events/")
         && !url.contains("/public/v3/events/")
 }
 
+fn is_gabo_ad_route(url: &str) -> bool {
+    url.contains("/advertisement")
+        || url.contains("/ad-opportunity")
+        || url.contains("/adlogic")
+        || url.contains("/ads")
+}
+
 fn is_gabo_event_post(url: &str, method: &str) -> bool {
     url.contains("gabo-receiver-service") && url.contains("/events") && method == "POST"
 }
@@ -57,16 +96,37 @@ mod tests {
 
     #[test]
     fn gabo_event_post_does_not_get_service_allowance() {
-        let classification = classify_url("https://gabo-receiver-service.spotify.com/events", "POST");
+        let classification = classify_url("https://gabo-receiver-service.spotify.com/events", "POST", None);
 
         assert!(classification.is_gabo_event_post);
         assert!(!classification.is_gabo);
     }
 
+    #[test]
+    fn gabo_event_batches_are_blocked_by_payload() {
+        let url = "https://spclient.wg.spotify.com/gabo-receiver-service/v3/events/";
+
+        let playback = classify_url(url, "POST", Some(b"\x0a\x0dPlaybackEvent"));
+        assert!(playback.is_gabo);
+        assert!(!playback.is_gabo_event_post);
+
+        let ads = classify_url(url, "POST", Some(b"\x0a\x0dPlaybackEvent\x0a\x07AdEvent"));
+        assert!(!ads.is_gabo);
+        assert!(ads.is_gabo_event_post);
+    }
+
+    #[test]
+    fn spclient_payloads_naming_ad_services_are_ad_related() {
+        let url = "https://spclient.wg.spotify.com/esperanto/v1/call";
+
+        assert!(classify_url(url, "POST", Some(b"\x0a\x12PrepareSlotRequest")).is_ad_related);
+        assert!(!classify_url(url, "POST", Some(b"\x0a\x11PlaybackRequestV2")).is_ad_related);
+    }
+
     #[test]
     fn classification_checks_ad_markers_after_long_prefix() {
         let url = format!("https://spclient.wg.spotify.com/{}/ads/foo", "a".repeat(4096));
-        let classification = classify_url(&url, "GET");
+        let classification = classify_url(&url, "GET", None);
 
         assert!(classification.is_ad_related);
     }
@@ -0,0 +1,171 @@
+//! Outcome statistics for allowed CEF requests
+//!
+//! Completions reported by the proxy client are aggregated per endpoint
+//! (the URL without its query string), so an endpoint we allow that starts
+//! failing after a Spotify update shows up in the logs and in
+//! `get_request_stats`.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use libc::c_int;
+
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+// Bounded so endpoints with IDs in the path cannot grow the table forever
+const MAX_ENDPOINTS: usize = 512;
+
+/// Endpoint that absorbs completions once `MAX_ENDPOINTS` is reached
+const OVERFLOW_ENDPOINT: &str = "(other)";
+
+/// How one request ended, as seen by its client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Completion {
+    /// Whether CEF reported `UR_SUCCESS`
+    pub(super) succeeded: bool,
+    /// HTTP status code, or 0 if no response was received
+    pub(super) http_status: c_int,
+    /// CEF `cef_errorcode_t` value, 0 for none
+    pub(super) error: c_int,
+    /// Downloaded body bytes
+    pub(super) bytes: u64,
+    pub(super) latency: Duration,
+}
+
+impl Completion {
+    const fn is_failure(&self) -> bool {
+        !self.succeeded || self.http_status >= 400
+    }
+}
+
+/// Aggregated outcomes for one endpoint
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointStats {
+    pub requests: u64,
+    pub failures: u64,
+    pub bytes: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    pub last_http_status: c_int,
+    pub last_error: c_int,
+}
+
+impl EndpointStats {
+    /// Mean latency over all completed requests
+    #[must_use]
+    pub fn average_latency(&self) -> Duration {
+        u32::try_from(self.requests)
+            .ok()
+            .filter(|&requests| requests > 0)
+            .map_or(Duration::ZERO, |requests| self.total_latency / requests)
+    }
+}
+
+static REQUEST_STATS: LazyLock<Mutex<HashMap<String, EndpointStats>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Strip the query string and fragment from a URL
+pub(super) fn endpoint(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+/// Fold one completion into the table, returning `true` if the endpoint
+/// failed after its previous request had succeeded
+fn record_into(stats: &mut HashMap<String, EndpointStats>, endpoint: &str, completion: &Completion) -> bool {
+    let key = if stats.len() >= MAX_ENDPOINTS && !stats.contains_key(endpoint) {
+        OVERFLOW_ENDPOINT
+    } else {
+        endpoint
+    };
+    let entry = stats.entry(key.to_string()).or_default();
+
+    let previously_succeeded = entry.requests > entry.failures && entry.last_error == 0 && entry.last_http_status < 400;
+    entry.requests += 1;
+    entry.bytes += completion.bytes;
+    entry.total_latency += completion.latency;
+    entry.max_latency = entry.max_latency.max(completion.latency);
+    entry.last_http_status = completion.http_status;
+    entry.last_error = completion.error;
+    if completion.is_failure() {
+        entry.failures += 1;
+    }
+
+    completion.is_failure() && previously_succeeded
+}
+
+/// Record the outcome of a request to `url`
+pub(super) fn record(method: &str, url: &str, completion: &Completion) {
+    let endpoint = endpoint(url);
+    let started_failing = REQUEST_STATS
+        .lock()
+        .is_ok_and(|mut stats| record_into(&mut stats, endpoint, completion));
+
+    let summary = format!(
+        "{method} {endpoint} -> status {} error {} ({} bytes, {} ms)",
+        completion.http_status,
+        completion.error,
+        completion.bytes,
+        completion.latency.as_millis()
+    );
+    if started_failing {
+        logging::log_error(Source::Stats, &format!("Allowed endpoint started failing: {summary}"));
+    } else if completion.is_failure() {
+        logging::log_info(Source::Stats, &format!("REQUEST FAILED: {summary}"));
+    } else {
+        logging::log_debug(Source::Stats, &format!("REQUEST COMPLETE: {summary}"));
+    }
+}
+
+/// Snapshot of per-endpoint statistics for allowed requests
+pub fn get_request_stats() -> Vec<(String, EndpointStats)> {
+    REQUEST_STATS
+        .lock()
+        .map(|stats| stats.iter().map(|(endpoint, stats)| (endpoint.clone(), *stats)).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(http_status: c_int, latency_ms: u64) -> Completion {
+        Completion { succeeded: true, http_status, error: 0, bytes: 10, latency: Duration::from_millis(latency_ms) }
+    }
+
+    #[test]
+    fn aggregates_per_endpoint_without_query() {
+        let mut stats = HashMap::new();
+        record_into(&mut stats, endpoint("https://spclient.wg.spotify.com/v1/a?x=1"), &completion(200, 10));
+        record_into(&mut stats, endpoint("https://spclient.wg.spotify.com/v1/a?x=2"), &completion(200, 30));
+
+        let entry = &stats["https://spclient.wg.spotify.com/v1/a"];
+        assert_eq!(entry.requests, 2);
+        assert_eq!(entry.bytes, 20);
+        assert_eq!(entry.average_latency(), Duration::from_millis(20));
+        assert_eq!(entry.max_latency, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn flags_endpoints_that_start_failing() {
+        let mut stats = HashMap::new();
+        assert!(!record_into(&mut stats, "https://example.test/a", &completion(200, 1)));
+        assert!(record_into(&mut stats, "https://example.test/a", &completion(503, 1)));
+        assert!(!record_into(&mut stats, "https://example.test/a", &completion(503, 1)));
+
+        let failed = Completion { succeeded: false, error: -102, ..completion(0, 1) };
+        assert!(!record_into(&mut stats, "https://example.test/b", &failed));
+        assert_eq!(stats["https://example.test/a"].failures, 2);
+    }
+
+    #[test]
+    fn caps_the_number_of_endpoints() {
+        let mut stats = HashMap::new();
+        for index in 0..=MAX_ENDPOINTS {
+            record_into(&mut stats, &format!("https://example.test/{index}"), &completion(200, 1));
+        }
+
+        assert_eq!(stats.len(), MAX_ENDPOINTS + 1);
+        assert_eq!(stats[OVERFLOW_ENDPOINT].requests, 1);
+    }
+}
@@ -1,44 +1,65 @@
-use std::{ptr::null_mut, slice::from_raw_parts};
+use std::ptr::null_mut;
 
-use cef_sys::{
-    _cef_request_context_t, _cef_request_t, _cef_urlrequest_client_t, cef_string_userfree_utf16_t, cef_urlrequest_t,
-};
+use cef_sys::{_cef_request_context_t, _cef_request_t, _cef_urlrequest_client_t, cef_urlrequest_t};
 
-use crate::config::{CONFIG, DEBUG_MODE};
+use crate::config::{HookGroup, CONFIG, PASSTHROUGH_MODE};
 use crate::hook;
 use crate::hooks::memory::cef_string_userfree_utf16_free;
+use crate::utils::log_filter::Source;
 use crate::utils::logging;
 
-use super::request_classification::classify_url;
-
-fn cef_userfree_utf16_to_string(value: cef_string_userfree_utf16_t) -> Option<String> {
-    if value.is_null() {
-        return None;
-    }
+use super::cef_util::cef_userfree_utf16_to_string;
+use super::client_proxy::{hide_proxy, wrap_client};
+use super::fault::{self, Fault};
+use super::headers::{apply_header_rules, read_headers, write_headers};
+use super::post_data::read_post_data;
+use super::request_classification::{classify_url, url_verdict, UrlVerdict};
+use super::rewrite::{rewrite_url, set_request_url};
+use super::synthetic_response::{blocked_response_for, respond_blocked};
+
+/// Run the real `cef_urlrequest_create` with a client that records the outcome
+fn create_observed(
+    request: *mut _cef_request_t,
+    client: *mut _cef_urlrequest_client_t,
+    request_context: *mut _cef_request_context_t,
+    method: &str,
+    url: &str,
+) -> *mut cef_urlrequest_t {
+    let urlrequest = REAL_CEF_URLREQUEST_CREATE(request, wrap_client(client, method, url), request_context);
+    hide_proxy(urlrequest);
+    urlrequest
+}
 
-    // SAFETY: Category 8 - FFI boundary. `value` is a non-null CEF userfree
-    // string pointer returned by the request API for the duration of this call.
-    let cef_string = unsafe { &*value };
-    if cef_string.length == 0 {
-        return Some(String::new());
-    }
+/// Answer a blocked request with the response configured for its URL
+fn block(request: *mut _cef_request_t, client: *mut _cef_urlrequest_client_t, url: &str) -> *mut cef_urlrequest_t {
+    respond_blocked(request, client, blocked_response_for(&CONFIG, url))
+}
 
-    if cef_string.str_.is_null() {
-        return None;
+/// Let a request the hook cannot evaluate through, or block it, by policy
+fn on_fault(
+    fault: Fault,
+    request: *mut _cef_request_t,
+    client: *mut _cef_urlrequest_client_t,
+    request_context: *mut _cef_request_context_t,
+) -> *mut cef_urlrequest_t {
+    if fault::fails_open(fault) {
+        REAL_CEF_URLREQUEST_CREATE(request, client, request_context)
+    } else if request.is_null() {
+        null_mut()
+    } else {
+        block(request, client, "")
     }
-
-    // SAFETY: Category 10 - out-of-bounds. CEF reports `length` UTF-16 code
-    // units for the non-null `str_` pointer in this userfree string.
-    let utf16 = unsafe { from_raw_parts(cef_string.str_, cef_string.length) };
-    Some(String::from_utf16_lossy(utf16))
 }
 
 hook! {
     cef_urlrequest_create(request: *mut _cef_request_t, client: *mut _cef_urlrequest_client_t, request_context: *mut _cef_request_context_t) -> *mut cef_urlrequest_t => REAL_CEF_URLREQUEST_CREATE {
+        if !super::is_active(HookGroup::Cef) {
+            return REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+        }
+
         // Validate input pointers
         if request.is_null() {
-            logging::log_error("Null request pointer in cef_urlrequest_create");
-            return null_mut();
+            return on_fault(Fault::CefNullRequest, request, client, request_context);
         }
 
         // Extract URL with safety checks
@@ -46,87 +67,100 @@ hook! {
         // the callback table for the duration of this hook call.
         let url_cef = unsafe {
             if let Some(get_url) = (*request).get_url { get_url(request) } else {
-                logging::log_error("Missing get_url function in request");
-                return REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+                return on_fault(Fault::CefMissingGetUrl, request, client, request_context);
             }
         };
 
         if url_cef.is_null() {
-            return REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+            return on_fault(Fault::CefNullUrl, request, client, request_context);
         }
 
         let Some(url) = cef_userfree_utf16_to_string(url_cef) else {
             cef_string_userfree_utf16_free(url_cef);
-            return REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+            return on_fault(Fault::CefInvalidUrl, request, client, request_context);
         };
 
         // SAFETY: Category 8 - FFI boundary. `request` is non-null and CEF owns
         // the callback table for the duration of this hook call.
         let method_cef = unsafe {
             if let Some(get_method) = (*request).get_method { get_method(request) } else {
-                logging::log_error("Missing get_method function in request");
                 cef_string_userfree_utf16_free(url_cef);
-                return REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+                return on_fault(Fault::CefMissingGetMethod, request, client, request_context);
             }
         };
 
         let Some(method) = cef_userfree_utf16_to_string(method_cef) else {
             cef_string_userfree_utf16_free(url_cef);
             cef_string_userfree_utf16_free(method_cef);
-            return REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+            return on_fault(Fault::CefInvalidMethod, request, client, request_context);
         };
         cef_string_userfree_utf16_free(method_cef);
 
-        // Classify URL using fault-contained function
-        let classification = classify_url(&url, &method);
+        // Size-capped copy of the upload body, if the request has one
+        let body = read_post_data(request);
 
-        // Debug mode handling
-        if *DEBUG_MODE {
-            logging::log_debug(&format!("{method} {url}"));
-            let result = REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+        // Passthrough mode lets everything through
+        if *PASSTHROUGH_MODE {
+            logging::log_allowed(Source::Cef, "PASSTHROUGH", &method, &url);
+            let result = create_observed(request, client, request_context, &method, &url);
             cef_string_userfree_utf16_free(url_cef);
             return result;
         }
 
+        // Rewritten requests are classified (and fetched) by their new URL
+        let url = match rewrite_url(&CONFIG.rewrite_rules, &url) {
+            Some(rewritten) if set_request_url(request, &rewritten) => {
+                logging::log_info(Source::Cef, &format!("REWRITTEN: {method} {url} -> {rewritten}"));
+                rewritten
+            }
+            _ => url,
+        };
+
+        // Classify URL using fault-contained function
+        let classification = classify_url(&url, &method, body.as_deref());
+
         // Decision logic with proper cleanup in all paths
 
         // Monitor product state checks (informational)
         if classification.is_product_state {
-            logging::log_debug(&format!("PRODUCT STATE CHECK: {method} {url}"));
+            logging::log_debug(Source::Cef, &format!("PRODUCT STATE CHECK: {method} {url}"));
         }
 
         if classification.is_discord_rpc {
-            logging::log_allowed("DISCORD RPC", &method, &url);
-            let result = REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
-            cef_string_userfree_utf16_free(url_cef);
-            return result;
-        } else if classification.is_gabo || classification.is_dealer {
-            logging::log_allowed("SERVICE", &method, &url);
-            let result = REAL_CEF_URLREQUEST_CREATE(request, client, request_context);
+            logging::log_allowed(Source::Cef, "DISCORD RPC", &method, &url);
+            let result = create_observed(request, client, request_context, &method, &url);
             cef_string_userfree_utf16_free(url_cef);
             return result;
         }
 
-        // Block aggressive Gabo POST events (payload might contain ad data)
-        if classification.is_gabo_event_post {
-            logging::log_blocked("BLOCKED GABO POST", &method, &url);
-            cef_string_userfree_utf16_free(url_cef);
-            return null_mut();
+        if let Some(mut headers) = (!CONFIG.header_rules.is_empty()).then(|| read_headers(request)).flatten() {
+            let outcome = apply_header_rules(&CONFIG.header_rules, &url, &mut headers);
+            if let Some(header) = outcome.blocked_by {
+                logging::log_blocked(Source::Cef, &format!("BLOCKED HEADER {header}"), &method, &url);
+                cef_string_userfree_utf16_free(url_cef);
+                return block(request, client, &url);
+            }
+            if outcome.modified {
+                logging::log_debug(Source::Cef, &format!("HEADERS REWRITTEN: {method} {url}"));
+                write_headers(request, &headers);
+            }
         }
 
-        if classification.is_ad_related {
-            logging::log_blocked("BLOCKED AD", &method, &url);
-            // No response capturing for now to avoid segfaults
+        if body.as_deref().is_some_and(|body| CONFIG.body_denylist.iter().any(|pattern| pattern.is_match(body))) {
+            logging::log_blocked(Source::Cef, "BLOCKED BODY", &method, &url);
             cef_string_userfree_utf16_free(url_cef);
-            return null_mut();
+            return block(request, client, &url);
         }
 
-        let result = if CONFIG.denylist.is_match(&url) {
-            logging::log_blocked("BLOCKED CONFIG", &method, &url);
-            null_mut()
-        } else {
-            logging::log_allowed("ALLOWED", &method, &url);
-            REAL_CEF_URLREQUEST_CREATE(request, client, request_context)
+        let result = match url_verdict(&classification, &url, &CONFIG.denylist) {
+            UrlVerdict::Allow(context) => {
+                logging::log_allowed(Source::Cef, context, &method, &url);
+                create_observed(request, client, request_context, &method, &url)
+            }
+            UrlVerdict::Block(context) => {
+                logging::log_blocked(Source::Cef, context, &method, &url);
+                block(request, client, &url)
+            }
         };
 
         cef_string_userfree_utf16_free(url_cef);
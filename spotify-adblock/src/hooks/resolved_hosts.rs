@@ -0,0 +1,171 @@
+//! Address-to-hostname memory shared by the resolver and socket hooks
+//!
+//! `connect` only sees a socket address. Remembering which hostname each
+//! address was resolved from lets host rules apply at connect time too.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+
+use libc::{addrinfo, hostent, sockaddr, sockaddr_in, sockaddr_in6, AF_INET, AF_INET6};
+
+// Bounded so a long-running client cannot grow the table without limit
+const MAX_RESOLVED_HOSTS: usize = 4096;
+
+#[derive(Default)]
+struct ResolvedHosts {
+    hosts: HashMap<IpAddr, String>,
+    insertion_order: VecDeque<IpAddr>,
+}
+
+static RESOLVED_HOSTS: LazyLock<Mutex<ResolvedHosts>> = LazyLock::new(|| Mutex::new(ResolvedHosts::default()));
+
+/// Remember that `address` was resolved from `host`
+pub(super) fn record(address: IpAddr, host: &str) {
+    let address = address.to_canonical();
+    let Ok(mut resolved) = RESOLVED_HOSTS.lock() else {
+        return;
+    };
+
+    if resolved.hosts.insert(address, host.to_string()).is_none() {
+        resolved.insertion_order.push_back(address);
+        while resolved.insertion_order.len() > MAX_RESOLVED_HOSTS {
+            if let Some(oldest) = resolved.insertion_order.pop_front() {
+                resolved.hosts.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Remember every address in a `getaddrinfo` result list
+pub(super) fn record_addrinfo(host: &str, mut entry: *const addrinfo) {
+    if host.is_empty() {
+        return;
+    }
+
+    let mut remaining = MAX_RESOLVED_HOSTS;
+    while !entry.is_null() && remaining > 0 {
+        // SAFETY: Category 8 - FFI boundary. `entry` comes from a successful
+        // `getaddrinfo` call and each `ai_next` link is either null or valid.
+        let info = unsafe { &*entry };
+        if let Some(address) = sockaddr_ip(info.ai_addr, info.ai_addrlen) {
+            record(address, host);
+        }
+        entry = info.ai_next;
+        remaining -= 1;
+    }
+}
+
+/// Remember every address in a `gethostbyname` family result
+pub(super) fn record_hostent(host: &str, entry: *const hostent) {
+    if host.is_empty() || entry.is_null() {
+        return;
+    }
+
+    // SAFETY: Category 8 - FFI boundary. `entry` is a non-null result from a
+    // successful lookup whose address list is NULL-terminated.
+    let entry = unsafe { &*entry };
+    let Ok(address_len) = usize::try_from(entry.h_length) else {
+        return;
+    };
+    if entry.h_addr_list.is_null() {
+        return;
+    }
+
+    for index in 0..MAX_RESOLVED_HOSTS {
+        // SAFETY: Category 10 - out-of-bounds. The list is NULL-terminated
+        // and the loop stops at the terminator.
+        let address = unsafe { *entry.h_addr_list.add(index) };
+        if address.is_null() {
+            break;
+        }
+        // SAFETY: Category 10 - out-of-bounds. Each entry holds `h_length`
+        // bytes for the result's address family.
+        let bytes = unsafe { std::slice::from_raw_parts(address.cast::<u8>(), address_len) };
+        if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+            record(IpAddr::from(octets), host);
+        } else if let Ok(octets) = <[u8; 16]>::try_from(bytes) {
+            record(IpAddr::from(octets), host);
+        }
+    }
+}
+
+/// Look up the hostname an address was last resolved from
+pub(super) fn lookup(address: IpAddr) -> Option<String> {
+    RESOLVED_HOSTS
+        .lock()
+        .ok()
+        .and_then(|resolved| resolved.hosts.get(&address.to_canonical()).cloned())
+}
+
+/// Extract the IP address from an `AF_INET`/`AF_INET6` socket address
+pub(super) fn sockaddr_ip(address: *const sockaddr, address_len: libc::socklen_t) -> Option<IpAddr> {
+    sockaddr_ip_port(address, address_len).map(|(ip, _)| ip)
+}
+
+/// Extract the IP address and port from an `AF_INET`/`AF_INET6` socket address
+pub(super) fn sockaddr_ip_port(address: *const sockaddr, address_len: libc::socklen_t) -> Option<(IpAddr, u16)> {
+    if address.is_null() {
+        return None;
+    }
+    let address_len = usize::try_from(address_len).ok()?;
+
+    // SAFETY: Category 8 - FFI boundary. `address` is non-null and callers
+    // pass the length the kernel would read; each cast below is only taken
+    // after checking that the buffer is large enough for that family.
+    unsafe {
+        match i32::from((*address).sa_family) {
+            AF_INET if address_len >= size_of::<sockaddr_in>() => {
+                let address = address.cast::<sockaddr_in>().read_unaligned();
+                Some((IpAddr::from(address.sin_addr.s_addr.to_ne_bytes()), u16::from_be(address.sin_port)))
+            }
+            AF_INET6 if address_len >= size_of::<sockaddr_in6>() => {
+                let address = address.cast::<sockaddr_in6>().read_unaligned();
+                Some((IpAddr::from(address.sin6_addr.s6_addr), u16::from_be(address.sin6_port)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_hosts_for_canonical_addresses() {
+        record("192.0.2.44".parse().unwrap(), "ads.example.test");
+
+        assert_eq!(lookup("192.0.2.44".parse().unwrap()).as_deref(), Some("ads.example.test"));
+        assert_eq!(lookup("::ffff:192.0.2.44".parse().unwrap()).as_deref(), Some("ads.example.test"));
+        assert_eq!(lookup("192.0.2.45".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn reads_ipv4_and_ipv6_socket_addresses() {
+        // SAFETY: all-zero bytes are a valid `sockaddr_in`.
+        let mut ipv4: sockaddr_in = unsafe { std::mem::zeroed() };
+        ipv4.sin_family = libc::sa_family_t::try_from(AF_INET).unwrap();
+        ipv4.sin_port = 443u16.to_be();
+        ipv4.sin_addr.s_addr = u32::from_be_bytes([203, 0, 113, 5]).to_be();
+        let length = libc::socklen_t::try_from(size_of::<sockaddr_in>()).unwrap();
+
+        assert_eq!(
+            sockaddr_ip_port((&raw const ipv4).cast(), length),
+            Some(("203.0.113.5".parse().unwrap(), 443))
+        );
+        assert_eq!(sockaddr_ip_port((&raw const ipv4).cast(), length - 1), None);
+
+        // SAFETY: all-zero bytes are a valid `sockaddr_in6`.
+        let mut ipv6: sockaddr_in6 = unsafe { std::mem::zeroed() };
+        ipv6.sin6_family = libc::sa_family_t::try_from(AF_INET6).unwrap();
+        ipv6.sin6_port = 80u16.to_be();
+        ipv6.sin6_addr.s6_addr = "2001:db8::5".parse::<std::net::Ipv6Addr>().unwrap().octets();
+        let length = libc::socklen_t::try_from(size_of::<sockaddr_in6>()).unwrap();
+
+        assert_eq!(
+            sockaddr_ip((&raw const ipv6).cast(), length),
+            Some("2001:db8::5".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Rule filtering for resource loads issued by browsers (xpui)
+//!
+//! Most xpui traffic goes through Chromium's network service rather than
+//! `cef_urlrequest_create`. At browser creation the browser's
+//! `cef_client_t` has its `get_request_handler` callback patched
+//! in place, and so is `get_resource_request_handler` on the request
+//! handler it returns and `on_before_resource_load` on the resource request
+//! handler after that. Every other callback stays Spotify's own. The
+//! patched `on_before_resource_load` runs the load through the URL rules and
+//! cancels it when blocked, otherwise delegating to the original callback.
+//! Where Spotify provides no handler, a minimal one of ours is returned,
+//! created once per client or request handler and released with it.
+
+use std::ptr::{fn_addr_eq, null_mut};
+
+use cef_sys::{
+    _cef_browser_t, _cef_callback_t, _cef_client_t, _cef_frame_t, _cef_request_handler_t, _cef_request_t,
+    _cef_resource_request_handler_t, cef_return_value_t, cef_string_t,
+};
+use libc::c_int;
+
+use crate::config::CONFIG;
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+use super::callback_patch::{HandlerSource, OriginalCallbacks, PatchedObjects};
+use super::cef_object::CefObject;
+use super::cef_util::{release, take_cef_string};
+use super::post_data::read_post_data;
+use super::request_classification::{classify_url, url_verdict, UrlVerdict};
+
+type GetRequestHandler = unsafe extern "C" fn(*mut _cef_client_t) -> *mut _cef_request_handler_t;
+
+type GetResourceRequestHandler = unsafe extern "C" fn(
+    *mut _cef_request_handler_t,
+    *mut _cef_browser_t,
+    *mut _cef_frame_t,
+    *mut _cef_request_t,
+    c_int,
+    c_int,
+    *const cef_string_t,
+    *mut c_int,
+) -> *mut _cef_resource_request_handler_t;
+
+type OnBeforeResourceLoad = unsafe extern "C" fn(
+    *mut _cef_resource_request_handler_t,
+    *mut _cef_browser_t,
+    *mut _cef_frame_t,
+    *mut _cef_request_t,
+    *mut _cef_callback_t,
+) -> cef_return_value_t;
+
+// Our callbacks as the pointer types stored in CEF's callback tables
+const FILTERED_GET_REQUEST_HANDLER: GetRequestHandler = filtered_get_request_handler;
+const FILTERED_GET_RESOURCE_REQUEST_HANDLER: GetResourceRequestHandler = filtered_get_resource_request_handler;
+const FILTERED_ON_BEFORE_RESOURCE_LOAD: OnBeforeResourceLoad = filtered_on_before_resource_load;
+
+type RequestHandlerSource = HandlerSource<GetRequestHandler, _cef_request_handler_t>;
+type ResourceHandlerSource = HandlerSource<GetResourceRequestHandler, _cef_resource_request_handler_t>;
+
+static REQUEST_HANDLER_GETTERS: PatchedObjects<RequestHandlerSource> = PatchedObjects::new();
+static RESOURCE_HANDLER_GETTERS: PatchedObjects<ResourceHandlerSource> = PatchedObjects::new();
+static RESOURCE_HANDLER_ORIGINALS: OriginalCallbacks<OnBeforeResourceLoad> = OriginalCallbacks::new();
+
+/// Patch `get_request_handler` on a browser's client
+pub(super) fn install_on_client(client: *mut _cef_client_t) {
+    if client.is_null() {
+        return;
+    }
+    // SAFETY: Category 8 - FFI boundary. `client` is the non-null client
+    // passed to browser creation; its callback table is writable memory
+    // owned by the client object.
+    unsafe {
+        let current = (*client).get_request_handler;
+        if current.is_some_and(|current| fn_addr_eq(current, FILTERED_GET_REQUEST_HANDLER)) {
+            return;
+        }
+        if REQUEST_HANDLER_GETTERS.remember(client, HandlerSource::new(current)) {
+            (*client).get_request_handler = Some(FILTERED_GET_REQUEST_HANDLER);
+        }
+    }
+}
+
+/// Patch `get_resource_request_handler` on a request handler
+fn install_on_request_handler(handler: *mut _cef_request_handler_t) {
+    // SAFETY: Category 8 - FFI boundary. `handler` is a non-null handler
+    // returned by the client; its callback table is writable object memory.
+    unsafe {
+        let current = (*handler).get_resource_request_handler;
+        if current.is_some_and(|current| fn_addr_eq(current, FILTERED_GET_RESOURCE_REQUEST_HANDLER)) {
+            return;
+        }
+        if RESOURCE_HANDLER_GETTERS.remember(handler, HandlerSource::new(current)) {
+            (*handler).get_resource_request_handler = Some(FILTERED_GET_RESOURCE_REQUEST_HANDLER);
+        }
+    }
+}
+
+/// Patch `on_before_resource_load` on a resource request handler
+fn install_on_resource_handler(handler: *mut _cef_resource_request_handler_t) {
+    // SAFETY: Category 8 - FFI boundary. As in `install_on_request_handler`.
+    unsafe {
+        let current = (*handler).on_before_resource_load;
+        if current.is_some_and(|current| fn_addr_eq(current, FILTERED_ON_BEFORE_RESOURCE_LOAD)) {
+            return;
+        }
+        if RESOURCE_HANDLER_ORIGINALS.remember(handler, current) {
+            (*handler).on_before_resource_load = Some(FILTERED_ON_BEFORE_RESOURCE_LOAD);
+        }
+    }
+}
+
+/// Request handler of ours, for clients that do not provide one
+fn create_request_handler() -> *mut _cef_request_handler_t {
+    let handler = CefObject::create(
+        _cef_request_handler_t {
+            get_resource_request_handler: Some(FILTERED_GET_RESOURCE_REQUEST_HANDLER),
+            ..Default::default()
+        },
+        (),
+    );
+    // Gives the handler a place for its own fallback resource handler
+    RESOURCE_HANDLER_GETTERS.remember(handler, HandlerSource::new(None));
+    handler
+}
+
+/// Resource request handler of ours, for loads Spotify does not handle
+fn create_resource_handler() -> *mut _cef_resource_request_handler_t {
+    CefObject::create(
+        _cef_resource_request_handler_t {
+            on_before_resource_load: Some(FILTERED_ON_BEFORE_RESOURCE_LOAD),
+            ..Default::default()
+        },
+        (),
+    )
+}
+
+unsafe extern "C" fn filtered_get_request_handler(client: *mut _cef_client_t) -> *mut _cef_request_handler_t {
+    let handler = HandlerSource::getter(&REQUEST_HANDLER_GETTERS, client).map_or(null_mut(), |get_request_handler| {
+        // SAFETY: Category 8 - FFI boundary. The original callback of this
+        // client, called with the client CEF passed to us.
+        unsafe { get_request_handler(client) }
+    });
+
+    if handler.is_null() {
+        HandlerSource::fallback(&REQUEST_HANDLER_GETTERS, client, create_request_handler)
+    } else {
+        install_on_request_handler(handler);
+        handler
+    }
+}
+
+unsafe extern "C" fn filtered_get_resource_request_handler(
+    handler: *mut _cef_request_handler_t,
+    browser: *mut _cef_browser_t,
+    frame: *mut _cef_frame_t,
+    request: *mut _cef_request_t,
+    is_navigation: c_int,
+    is_download: c_int,
+    request_initiator: *const cef_string_t,
+    disable_default_handling: *mut c_int,
+) -> *mut _cef_resource_request_handler_t {
+    let resource_handler = HandlerSource::getter(&RESOURCE_HANDLER_GETTERS, handler).map_or_else(
+        || {
+            release(browser);
+            release(frame);
+            release(request);
+            null_mut()
+        },
+        |get_resource_request_handler| {
+            // SAFETY: Category 8 - FFI boundary. The original callback of this
+            // handler; our references to the arguments are handed on.
+            unsafe {
+                get_resource_request_handler(
+                    handler,
+                    browser,
+                    frame,
+                    request,
+                    is_navigation,
+                    is_download,
+                    request_initiator,
+                    disable_default_handling,
+                )
+            }
+        },
+    );
+
+    if resource_handler.is_null() {
+        HandlerSource::fallback(&RESOURCE_HANDLER_GETTERS, handler, create_resource_handler)
+    } else {
+        install_on_resource_handler(resource_handler);
+        resource_handler
+    }
+}
+
+/// Run a resource load through the URL rules; `true` means cancel it
+fn should_block_resource(request: *mut _cef_request_t) -> bool {
+    // SAFETY: Category 8 - FFI boundary. `request` is non-null and referenced
+    // by us for the duration of `on_before_resource_load`.
+    let (url, method) = unsafe {
+        (
+            (*request).get_url.and_then(|get_url| take_cef_string(get_url(request))),
+            (*request).get_method.and_then(|get_method| take_cef_string(get_method(request))),
+        )
+    };
+    let (Some(url), Some(method)) = (url, method) else {
+        return false;
+    };
+
+    let body = read_post_data(request);
+    if body.as_deref().is_some_and(|body| CONFIG.body_denylist.iter().any(|pattern| pattern.is_match(body))) {
+        logging::log_blocked(Source::Cef, "BLOCKED BODY RESOURCE", &method, &url);
+        return true;
+    }
+
+    let classification = classify_url(&url, &method, body.as_deref());
+    match url_verdict(&classification, &url, &CONFIG.denylist) {
+        UrlVerdict::Allow(context) => {
+            logging::log_debug(Source::Cef, &format!("{context} RESOURCE: {method} {url}"));
+            false
+        }
+        UrlVerdict::Block(context) => {
+            logging::log_blocked(Source::Cef, &format!("{context} RESOURCE"), &method, &url);
+            true
+        }
+    }
+}
+
+unsafe extern "C" fn filtered_on_before_resource_load(
+    handler: *mut _cef_resource_request_handler_t,
+    browser: *mut _cef_browser_t,
+    frame: *mut _cef_frame_t,
+    request: *mut _cef_request_t,
+    callback: *mut _cef_callback_t,
+) -> cef_return_value_t {
+    let blocked = !request.is_null() && should_block_resource(request);
+    if !blocked {
+        if let Some(on_before_resource_load) = RESOURCE_HANDLER_ORIGINALS.get(handler) {
+            // SAFETY: Category 8 - FFI boundary. The original callback of
+            // this handler; our references to the arguments are handed on.
+            return unsafe { on_before_resource_load(handler, browser, frame, request, callback) };
+        }
+    }
+
+    release(browser);
+    release(frame);
+    release(request);
+    release(callback);
+    if blocked { cef_return_value_t::RV_CANCEL } else { cef_return_value_t::RV_CONTINUE }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static SPOTIFY_LOADS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn spotify_on_before_resource_load(
+        _handler: *mut _cef_resource_request_handler_t,
+        _browser: *mut _cef_browser_t,
+        _frame: *mut _cef_frame_t,
+        _request: *mut _cef_request_t,
+        _callback: *mut _cef_callback_t,
+    ) -> cef_return_value_t {
+        SPOTIFY_LOADS.fetch_add(1, Ordering::SeqCst);
+        cef_return_value_t::RV_CONTINUE_ASYNC
+    }
+
+    fn load(handler: *mut _cef_resource_request_handler_t) -> cef_return_value_t {
+        // SAFETY: `handler` is a live handler created by the test.
+        unsafe { (*handler).on_before_resource_load.unwrap()(handler, null_mut(), null_mut(), null_mut(), null_mut()) }
+    }
+
+    #[test]
+    fn patched_handlers_delegate_to_spotify() {
+        let handler = CefObject::create(
+            _cef_resource_request_handler_t {
+                on_before_resource_load: Some(spotify_on_before_resource_load),
+                ..Default::default()
+            },
+            (),
+        );
+        install_on_resource_handler(handler);
+        // Patching twice must not record our own callback as the original
+        install_on_resource_handler(handler);
+
+        let before = SPOTIFY_LOADS.load(Ordering::SeqCst);
+        assert_eq!(load(handler), cef_return_value_t::RV_CONTINUE_ASYNC);
+        assert_eq!(SPOTIFY_LOADS.load(Ordering::SeqCst), before + 1);
+        release(handler);
+    }
+
+    #[test]
+    fn clients_without_handlers_get_ours() {
+        let client = CefObject::create(_cef_client_t::default(), ());
+        install_on_client(client);
+
+        let get_resource_handler = |request_handler: *mut _cef_request_handler_t| {
+            // SAFETY: `request_handler` is a live handler returned by the client.
+            unsafe {
+                (*request_handler).get_resource_request_handler.unwrap()(
+                    request_handler,
+                    null_mut(),
+                    null_mut(),
+                    null_mut(),
+                    0,
+                    0,
+                    std::ptr::null(),
+                    null_mut(),
+                )
+            }
+        };
+
+        // SAFETY: `client` is a live test object.
+        let request_handler = unsafe { (*client).get_request_handler.unwrap()(client) };
+        assert!(!request_handler.is_null());
+        let resource_handler = get_resource_handler(request_handler);
+        assert_eq!(load(resource_handler), cef_return_value_t::RV_CONTINUE);
+
+        // Later calls hand out the same handlers instead of new ones
+        // SAFETY: As above.
+        let again = unsafe { (*client).get_request_handler.unwrap()(client) };
+        assert_eq!(again, request_handler);
+        assert_eq!(get_resource_handler(request_handler), resource_handler);
+
+        release(resource_handler);
+        release(resource_handler);
+        release(again);
+        release(request_handler);
+        release(client);
+    }
+}
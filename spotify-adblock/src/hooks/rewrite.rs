@@ -0,0 +1,108 @@
+//! URL rewriting for CEF requests
+//!
+//! Rewrite rules edit a request's URL with `set_url` before it is classified
+//! and handed to the real `cef_urlrequest_create`, e.g. to skip podcast
+//! tracking redirectors and fetch the enclosure directly.
+
+use cef_sys::_cef_request_t;
+
+use crate::config::RewriteRule;
+
+use super::cef_util::CefStringBuf;
+
+// Redirectors can be chained (pdst.fm -> chtbl.com -> podtrac); stop after a
+// few passes so rules that keep matching their own output cannot loop
+const MAX_REWRITE_PASSES: usize = 8;
+
+/// Apply `rules` to `url`, returning the new URL if any rule changed it
+///
+/// Rules run in order with capture-group substitution (`$1`, `${name}`),
+/// and the whole list is re-run until the URL stops changing.
+pub(super) fn rewrite_url(rules: &[RewriteRule], url: &str) -> Option<String> {
+    let mut current = url.to_string();
+    for _ in 0..MAX_REWRITE_PASSES {
+        let before = current.clone();
+        for rule in rules {
+            current = rule.url.replace_all(&current, rule.replacement.as_str()).into_owned();
+        }
+        if current == before {
+            break;
+        }
+    }
+
+    (current != url).then_some(current)
+}
+
+/// Set a request's URL, returning `false` if the request cannot be modified
+pub(super) fn set_request_url(request: *mut _cef_request_t, url: &str) -> bool {
+    let url_buf = CefStringBuf::new(url);
+    let url = url_buf.as_cef_string();
+    // SAFETY: Category 8 - FFI boundary. `request` is non-null, CEF owns the
+    // callback table for this hook call and `set_url` copies the string.
+    unsafe {
+        if (*request).is_read_only.is_some_and(|is_read_only| is_read_only(request) != 0) {
+            return false;
+        }
+        let Some(set_url) = (*request).set_url else {
+            return false;
+        };
+        set_url(request, &raw const url);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    fn rule(url: &str, replacement: &str) -> RewriteRule {
+        RewriteRule { url: Regex::new(url).unwrap(), replacement: replacement.to_string() }
+    }
+
+    fn podcast_rules() -> Vec<RewriteRule> {
+        vec![
+            rule(r"^(https?)://dts\.podtrac\.com/redirect\.[a-z0-9]+/(?:https?://)?", "$1://"),
+            rule(r"^(https?)://chtbl\.com/track/[^/]+/(?:https?://)?", "$1://"),
+            rule(r"^(https?)://pdst\.fm/e/(?:https?://)?", "$1://"),
+        ]
+    }
+
+    #[test]
+    fn strips_chained_tracking_redirectors() {
+        let rules = podcast_rules();
+
+        assert_eq!(
+            rewrite_url(&rules, "https://dts.podtrac.com/redirect.mp3/traffic.libsyn.com/show/ep1.mp3").as_deref(),
+            Some("https://traffic.libsyn.com/show/ep1.mp3")
+        );
+        assert_eq!(
+            rewrite_url(&rules, "https://pdst.fm/e/chtbl.com/track/ABC123/dts.podtrac.com/redirect.mp3/cdn.example.test/ep.mp3")
+                .as_deref(),
+            Some("https://cdn.example.test/ep.mp3")
+        );
+        assert_eq!(rewrite_url(&rules, "https://cdn.example.test/ep.mp3"), None);
+    }
+
+    #[test]
+    fn removes_tracking_query_parameters() {
+        let rules = [rule(r"([?&])utm_[a-z]+=[^&#]*(&|$)", "$1"), rule(r"[?&]$", "")];
+
+        assert_eq!(
+            rewrite_url(&rules, "https://example.test/ep.mp3?utm_source=spotify&id=7").as_deref(),
+            Some("https://example.test/ep.mp3?id=7")
+        );
+        assert_eq!(
+            rewrite_url(&rules, "https://example.test/ep.mp3?utm_source=spotify").as_deref(),
+            Some("https://example.test/ep.mp3")
+        );
+    }
+
+    #[test]
+    fn stops_rules_that_never_settle() {
+        let rules = [rule(r"$", "x")];
+
+        assert_eq!(rewrite_url(&rules, "a").as_deref(), Some("axxxxxxxx"));
+    }
+}
@@ -24,7 +24,7 @@ pub(in crate::hooks) fn is_ad_related_url(url: &str) -> bool {
             || privacy::is_privacy_hard_url(url))
 }
 
-fn is_critical_allowlisted(url: &str) -> bool {
+pub(super) fn is_critical_allowlisted(url: &str) -> bool {
     contains_any(
         url,
         &[
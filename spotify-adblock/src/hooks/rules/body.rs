@@ -0,0 +1,64 @@
+use super::ad::{is_ad_related_url, is_critical_allowlisted};
+use super::matchers::is_spotify_client_url;
+use super::protobuf::embedded_strings;
+
+/// Ad-related event names that appear inside gabo event batches
+const AD_EVENT_NAMES: &[&[u8]] = &[
+    b"AdEvent",
+    b"AdDecisionEvent",
+    b"AdRequestEvent",
+    b"AdTransparencyEvent",
+    b"AdDetectionResult",
+    b"AdSegmentsMetadataReceived",
+    b"AdSlotEvent",
+    b"AdImpression",
+    b"AdInteraction",
+    b"AdOpportunity",
+    b"audio_ad_event",
+    b"ad_opportunity",
+    b"SponsoredContentListenerPayload",
+    b"PODCAST_SPONSORED_CONTENT",
+];
+
+pub(in crate::hooks) fn is_ad_related_body(body: &[u8]) -> bool {
+    AD_EVENT_NAMES
+        .iter()
+        .any(|name| body.windows(name.len()).any(|window| window == *name))
+}
+
+/// Whether a spclient request body names an ad service or message
+///
+/// The body is walked as protobuf and every embedded string goes through the
+/// URL rules, which already list those names.
+pub(in crate::hooks) fn is_ad_related_payload(url: &str, body: &[u8]) -> bool {
+    is_spotify_client_url(url) && !is_critical_allowlisted(url) && embedded_strings(body).into_iter().any(is_ad_related_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_ad_event_names_in_batches() {
+        assert!(is_ad_related_body(b"\x0a\x07AdEvent\x12\x04data"));
+        assert!(is_ad_related_body(b"{\"events\":[{\"name\":\"audio_ad_event\"}]}"));
+    }
+
+    #[test]
+    fn ignores_batches_without_ad_events() {
+        assert!(!is_ad_related_body(b"\x0a\x0cDownloadEvent\x0a\x09PlayEvent"));
+        assert!(!is_ad_related_body(b""));
+    }
+
+    #[test]
+    fn detects_ad_services_named_in_spclient_payloads() {
+        let url = "https://spclient.wg.spotify.com/esperanto/v1/call";
+        let body = b"\x0a\x2dspotify.ads.esperanto.proto.AdService/Prepare\x12\x00";
+
+        assert!(is_ad_related_payload(url, body));
+        assert!(is_ad_related_payload(url, b"\x12\x17\x0a\x15RemainingSkipsRequest"));
+        assert!(!is_ad_related_payload(url, b"\x0a\x16spotify:track:4uLU6hMC"));
+        assert!(!is_ad_related_payload("https://example.com/esperanto/v1/call", body));
+        assert!(!is_ad_related_payload("https://spclient.wg.spotify.com/product_state/get", body));
+    }
+}
@@ -1,9 +1,12 @@
 mod ad;
+mod body;
 mod ida;
 mod matchers;
 mod privacy;
+mod protobuf;
 
 #[cfg(test)]
 mod tests;
 
 pub(super) use ad::is_ad_related_url;
+pub(super) use body::{is_ad_related_body, is_ad_related_payload};
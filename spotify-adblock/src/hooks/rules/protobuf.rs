@@ -0,0 +1,161 @@
+//! Schema-less scan of protobuf messages for embedded strings
+//!
+//! spclient payloads are protobuf without a schema at hand. Walking the wire
+//! format still recovers the length-delimited fields that hold text, such
+//! as service, message and enum names, so the string rules can see them.
+
+/// Nested messages deeper than this are not descended into
+const MAX_DEPTH: usize = 8;
+/// Only this much of a payload is walked
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+/// Walking stops once this many strings were found
+const MAX_STRINGS: usize = 256;
+/// Shorter fields are too often binary that happens to be printable
+const MIN_STRING_LEN: usize = 4;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// Token-like strings (no whitespace) embedded in a protobuf message
+///
+/// A truncated or otherwise malformed message yields the strings found
+/// before the point where it stops parsing.
+pub(super) fn embedded_strings(message: &[u8]) -> Vec<&str> {
+    let mut strings = Vec::new();
+    walk(&message[..message.len().min(MAX_MESSAGE_LEN)], 0, &mut strings);
+    strings
+}
+
+/// Collect the strings of `message` into `strings`
+///
+/// Returns whether `message` is well-formed all the way through.
+fn walk<'a>(message: &'a [u8], depth: usize, strings: &mut Vec<&'a str>) -> bool {
+    let mut pos = 0;
+    while pos < message.len() && strings.len() < MAX_STRINGS {
+        let Some(key) = read_varint(message, &mut pos) else {
+            return false;
+        };
+        if key >> 3 == 0 {
+            return false;
+        }
+        let is_valid = match key & 0x7 {
+            WIRE_VARINT => read_varint(message, &mut pos).is_some(),
+            WIRE_FIXED64 => skip(message, &mut pos, 8),
+            WIRE_FIXED32 => skip(message, &mut pos, 4),
+            WIRE_LEN => read_len_delimited(message, &mut pos).map(|field| collect_field(field, depth, strings)).is_some(),
+            // Groups are long deprecated and not worth following
+            _ => false,
+        };
+        if !is_valid {
+            return false;
+        }
+    }
+    true
+}
+
+/// Record a length-delimited field as a string, or walk it as a nested message
+fn collect_field<'a>(field: &'a [u8], depth: usize, strings: &mut Vec<&'a str>) {
+    if let Some(text) = token_string(field) {
+        strings.push(text);
+    } else if depth < MAX_DEPTH {
+        // Bytes that only start out like a message contribute nothing
+        let mut nested = Vec::new();
+        if walk(field, depth + 1, &mut nested) {
+            strings.extend(nested);
+            strings.truncate(MAX_STRINGS);
+        }
+    }
+}
+
+fn token_string(field: &[u8]) -> Option<&str> {
+    std::str::from_utf8(field)
+        .ok()
+        .filter(|text| text.len() >= MIN_STRING_LEN && !text.chars().any(|c| c.is_control() || c.is_whitespace()))
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_len_delimited<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = usize::try_from(read_varint(data, pos)?).ok()?;
+    let end = pos.checked_add(len)?;
+    let field = data.get(*pos..end)?;
+    *pos = end;
+    Some(field)
+}
+
+const fn skip(data: &[u8], pos: &mut usize, len: usize) -> bool {
+    let end = *pos + len;
+    let fits = end <= data.len();
+    if fits {
+        *pos = end;
+    }
+    fits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a length-delimited field with a single-byte key and length
+    fn field(number: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![(number << 3) | 2, u8::try_from(content.len()).unwrap()];
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    #[test]
+    fn extracts_strings_from_nested_messages() {
+        let slot = [field(1, b"PrepareSlotRequest"), vec![0x10, 0x96, 0x01], field(3, b"ad break")].concat();
+        let message = [
+            field(1, b"spotify.ads.esperanto.proto.Slots"),
+            vec![0x19, 0, 0, 0, 0, 0, 0, 0, 0],
+            field(2, &field(1, &slot)),
+        ]
+        .concat();
+
+        assert_eq!(embedded_strings(&message), ["spotify.ads.esperanto.proto.Slots", "PrepareSlotRequest"]);
+    }
+
+    #[test]
+    fn keeps_strings_before_a_truncation() {
+        let message = [field(1, b"RemainingSkipsRequest"), field(2, b"cut short")].concat();
+
+        assert_eq!(embedded_strings(&message[..message.len() - 3]), ["RemainingSkipsRequest"]);
+    }
+
+    #[test]
+    fn ignores_binary_and_non_protobuf_payloads() {
+        // A nested "message" that stops parsing halfway is left alone
+        let message = field(1, &[field(1, b"SlotRealtimeDecisions"), vec![0x0a, 0x7f]].concat());
+        assert!(embedded_strings(&message).is_empty());
+
+        assert!(embedded_strings(b"{\"uri\":\"spotify:ad:1234\"}").is_empty());
+        assert!(embedded_strings(&[0xff; 16]).is_empty());
+        assert!(embedded_strings(b"").is_empty());
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let mut message = field(1, b"AdDecisionEvent");
+        for _ in 0..MAX_DEPTH {
+            message = field(1, &message);
+        }
+        assert_eq!(embedded_strings(&message), ["AdDecisionEvent"]);
+
+        let deeper = field(1, &message);
+        assert!(embedded_strings(&deeper).is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+//! Socket-level hooks for traffic that never goes through `getaddrinfo`
+//!
+//! Connections to literal IP addresses, or to addresses resolved by code
+//! paths we do not hook, bypass hostname filtering entirely. `connect` maps
+//! the target address back to the hostname it was resolved from (so host
+//! rules still apply) and checks it against the configured CIDR ranges.
+//! Linux has no `connectat`, so `connect` is the only entry point needed.
+//!
+//! When QUIC blocking is enabled, UDP traffic to port 443 is also refused so
+//! that Chromium falls back from HTTP/3 to TCP+TLS, where the `SSL_write`
+//! hook can inspect it.
+
+use std::net::{IpAddr, SocketAddr};
+
+use libc::{
+    c_int, c_uint, c_void, mmsghdr, msghdr, size_t, sockaddr, socklen_t, ssize_t, ECONNREFUSED, EINPROGRESS, EPERM, SOCK_DGRAM,
+    SOL_SOCKET, SO_TYPE,
+};
+
+use crate::config::{Config, HookGroup, QuicConfig, CONFIG};
+use crate::hook;
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+
+use super::guard;
+use super::network::is_allowed_domain;
+use super::plain_http;
+use super::resolved_hosts;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ConnectVerdict {
+    Allow,
+    BlockedHost(String),
+    BlockedRange,
+}
+
+fn connect_verdict(config: &Config, address: IpAddr, host: Option<String>) -> ConnectVerdict {
+    if config.cidr_allowlist.iter().any(|range| range.contains(address)) {
+        return ConnectVerdict::Allow;
+    }
+
+    if let Some(host) = host.filter(|host| !is_allowed_domain(host)) {
+        return ConnectVerdict::BlockedHost(host);
+    }
+
+    if config.cidr_denylist.iter().any(|range| range.contains(address)) {
+        ConnectVerdict::BlockedRange
+    } else {
+        ConnectVerdict::Allow
+    }
+}
+
+const HTTPS_PORT: u16 = 443;
+
+// HTTP/3 is negotiated on the HTTPS port
+const QUIC_PORT: u16 = HTTPS_PORT;
+
+/// `errno` for refused QUIC traffic, as a firewall rule would report it
+const QUIC_BLOCKED_ERRNO: c_int = EPERM;
+
+fn blocks_quic(quic: &QuicConfig, port: u16, host: &str) -> bool {
+    quic.block && port == QUIC_PORT && !quic.allowed_hosts.is_match(host)
+}
+
+/// Read `SO_TYPE` for a descriptor, or `None` if it is not a socket
+pub(super) fn socket_type(socket: c_int) -> Option<c_int> {
+    let mut socket_type: c_int = 0;
+    let mut length = socklen_t::try_from(size_of::<c_int>()).ok()?;
+    // SAFETY: Category 8 - FFI boundary. `getsockopt` writes at most `length`
+    // bytes into the local `socket_type` and fails cleanly on non-sockets.
+    let result = unsafe {
+        libc::getsockopt(socket, SOL_SOCKET, SO_TYPE, (&raw mut socket_type).cast::<c_void>(), &raw mut length)
+    };
+    (result == 0).then_some(socket_type)
+}
+
+fn is_udp_socket(socket: c_int) -> bool {
+    socket_type(socket) == Some(SOCK_DGRAM)
+}
+
+/// Check a UDP destination against the QUIC policy, logging blocked sends
+fn is_blocked_quic_destination(socket: c_int, address: *const sockaddr, address_len: socklen_t) -> bool {
+    if !CONFIG.quic.block || !super::is_active(HookGroup::Socket) {
+        return false;
+    }
+    let Some((ip, port)) = resolved_hosts::sockaddr_ip_port(address, address_len) else {
+        return false;
+    };
+    if port != QUIC_PORT || !is_udp_socket(socket) {
+        return false;
+    }
+
+    let host = resolved_hosts::lookup(ip).unwrap_or_else(|| ip.to_string());
+    if blocks_quic(&CONFIG.quic, port, &host) {
+        logging::log_blocked(Source::Socket, "BLOCKED QUIC", &host, &SocketAddr::new(ip, port).to_string());
+        true
+    } else {
+        false
+    }
+}
+
+fn last_errno() -> c_int {
+    // SAFETY: Category 8 - FFI boundary. `__errno_location` always returns a
+    // valid pointer to the calling thread's `errno`.
+    unsafe { *libc::__errno_location() }
+}
+
+/// Set the calling thread's `errno`
+pub(super) fn set_errno(value: c_int) {
+    // SAFETY: Category 8 - FFI boundary. `__errno_location` always returns a
+    // valid pointer to the calling thread's `errno`.
+    unsafe { *libc::__errno_location() = value };
+}
+
+hook! {
+    connect(socket: c_int, address: *const sockaddr, address_len: socklen_t) -> c_int => REAL_CONNECT else { guard::unsupported() } {
+        plain_http::forget_socket(socket);
+        let Some((ip, port)) = resolved_hosts::sockaddr_ip_port(address, address_len) else {
+            // Unix sockets, netlink and anything else without an IP address
+            return REAL_CONNECT(socket, address, address_len);
+        };
+        if !super::is_active(HookGroup::Socket) {
+            return REAL_CONNECT(socket, address, address_len);
+        }
+
+        if is_blocked_quic_destination(socket, address, address_len) {
+            set_errno(QUIC_BLOCKED_ERRNO);
+            return -1;
+        }
+
+        let host = resolved_hosts::lookup(ip);
+        let target = SocketAddr::new(ip, port).to_string();
+        match connect_verdict(&CONFIG, ip, host.clone()) {
+            ConnectVerdict::Allow => {
+                let result = REAL_CONNECT(socket, address, address_len);
+                // TLS is covered by `SSL_write`; anything else may be plain HTTP
+                if port != HTTPS_PORT && (result == 0 || last_errno() == EINPROGRESS) {
+                    plain_http::watch_socket(socket, host);
+                }
+                result
+            }
+            ConnectVerdict::BlockedHost(host) => {
+                logging::log_blocked(Source::Socket, "BLOCKED CONNECT", &host, &target);
+                set_errno(ECONNREFUSED);
+                -1
+            }
+            ConnectVerdict::BlockedRange => {
+                logging::log_blocked(Source::Socket, "BLOCKED CONNECT", "CIDR", &target);
+                set_errno(ECONNREFUSED);
+                -1
+            }
+        }
+    }
+}
+
+hook! {
+    sendto(socket: c_int, buf: *const c_void, len: size_t, flags: c_int, address: *const sockaddr, address_len: socklen_t) -> ssize_t => REAL_SENDTO else { guard::unsupported() } {
+        // Connected sockets pass no address; their `connect` was checked
+        if is_blocked_quic_destination(socket, address, address_len) {
+            set_errno(QUIC_BLOCKED_ERRNO);
+            return -1;
+        }
+
+        REAL_SENDTO(socket, buf, len, flags, address, address_len)
+    }
+}
+
+hook! {
+    sendmsg(socket: c_int, message: *const msghdr, flags: c_int) -> ssize_t => REAL_SENDMSG else { guard::unsupported() } {
+        if !message.is_null() {
+            // SAFETY: Category 8 - FFI boundary. `message` is the caller's
+            // non-null message header for the duration of this call.
+            let header = unsafe { &*message };
+            if is_blocked_quic_destination(socket, header.msg_name.cast::<sockaddr>(), header.msg_namelen) {
+                set_errno(QUIC_BLOCKED_ERRNO);
+                return -1;
+            }
+        }
+
+        REAL_SENDMSG(socket, message, flags)
+    }
+}
+
+hook! {
+    sendmmsg(socket: c_int, messages: *mut mmsghdr, vlen: c_uint, flags: c_int) -> c_int => REAL_SENDMMSG else { guard::unsupported() } {
+        if !messages.is_null() {
+            let count = usize::try_from(vlen).unwrap_or_default();
+            // SAFETY: Category 10 - out-of-bounds. `messages` points to `vlen`
+            // caller-owned headers for the duration of this call.
+            let messages = unsafe { std::slice::from_raw_parts(messages, count) };
+            let blocked = messages.iter().any(|message| {
+                let header = &message.msg_hdr;
+                is_blocked_quic_destination(socket, header.msg_name.cast::<sockaddr>(), header.msg_namelen)
+            });
+            if blocked {
+                set_errno(QUIC_BLOCKED_ERRNO);
+                return -1;
+            }
+        }
+
+        REAL_SENDMMSG(socket, messages, vlen, flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::RegexSet;
+
+    use super::*;
+
+    fn config(cidr_allowlist: &[&str], cidr_denylist: &[&str]) -> Config {
+        Config {
+            cidr_allowlist: cidr_allowlist.iter().map(|range| range.parse().unwrap()).collect(),
+            cidr_denylist: cidr_denylist.iter().map(|range| range.parse().unwrap()).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn refuses_denied_ranges_unless_allowlisted() {
+        let config = config(&["203.0.113.8/29"], &["203.0.113.0/24"]);
+
+        assert_eq!(
+            connect_verdict(&config, "203.0.113.1".parse().unwrap(), None),
+            ConnectVerdict::BlockedRange
+        );
+        assert_eq!(connect_verdict(&config, "203.0.113.9".parse().unwrap(), None), ConnectVerdict::Allow);
+        assert_eq!(connect_verdict(&config, "198.51.100.1".parse().unwrap(), None), ConnectVerdict::Allow);
+    }
+
+    #[test]
+    fn applies_host_rules_to_resolved_addresses() {
+        let config = config(&[], &[]);
+        let address = "192.0.2.10".parse().unwrap();
+
+        assert_eq!(
+            connect_verdict(&config, address, Some("ads.example.test".to_string())),
+            ConnectVerdict::BlockedHost("ads.example.test".to_string())
+        );
+        assert_eq!(
+            connect_verdict(&config, address, Some("spclient.wg.spotify.com".to_string())),
+            ConnectVerdict::Allow
+        );
+    }
+
+    #[test]
+    fn blocks_quic_only_when_enabled_for_port_443() {
+        let mut quic = QuicConfig {
+            block: false,
+            allowed_hosts: RegexSet::new([r"^quic-ok\.example\.test$"]).unwrap(),
+        };
+        assert!(!blocks_quic(&quic, 443, "spclient.wg.spotify.com"));
+
+        quic.block = true;
+        assert!(blocks_quic(&quic, 443, "spclient.wg.spotify.com"));
+        assert!(blocks_quic(&quic, 443, "203.0.113.7"));
+        assert!(!blocks_quic(&quic, 443, "quic-ok.example.test"));
+        assert!(!blocks_quic(&quic, 53, "spclient.wg.spotify.com"));
+    }
+
+    #[test]
+    fn only_datagram_sockets_count_as_udp() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert!(is_udp_socket(std::os::fd::AsRawFd::as_raw_fd(&udp)));
+        assert!(!is_udp_socket(std::os::fd::AsRawFd::as_raw_fd(&tcp)));
+        assert!(!is_udp_socket(-1));
+    }
+}
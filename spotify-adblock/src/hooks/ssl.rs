@@ -3,18 +3,25 @@
 //! Spotify's native code (Rust/C++) makes HTTPS requests through `OpenSSL`
 //! that bypass CEF entirely. This module hooks `SSL_write` to intercept
 //! all outgoing HTTPS traffic including cosmos/hermes protocol, leavebehind
-//! ads, and spclient API calls.
+//! ads, and spclient API calls. The read functions, `SSL_pending` and
+//! `SSL_free` are hooked as well so the dealer WebSocket can be filtered per
+//! message.
 
 use std::ffi::c_void;
 use std::os::raw::c_int;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::LazyLock;
 
-use crate::config::DEBUG_MODE;
+use libc::size_t;
+
+use crate::config::HookGroup;
 use crate::hook;
+use crate::utils::log_filter::{Level, Source};
 use crate::utils::logging;
 
-use super::rules;
+use super::fault::{self, Fault};
+use super::http_request::{is_ad_related_request, starts_like_request, HttpRequest};
+use super::websocket;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -26,104 +33,27 @@ static SSL_VERBOSE: LazyLock<AtomicBool> = LazyLock::new(|| AtomicBool::new(fals
 
 const MAX_INSPECT_LEN: usize = 4096;
 
-fn is_spotify_client_host(host: &str) -> bool {
-    let host = host.strip_suffix(":443").unwrap_or(host);
-    host.eq_ignore_ascii_case("spclient.wg.spotify.com")
-        || host
-            .get(host.len().saturating_sub("-spclient.spotify.com".len())..)
-            .is_some_and(|tail| tail.eq_ignore_ascii_case("-spclient.spotify.com"))
-}
-
 fn should_block_ssl_request(data: &[u8]) -> Option<String> {
-    let header_len = data
-        .windows(4)
-        .position(|window| window == b"\r\n\r\n")
-        .map_or(data.len(), |header_end| header_end + 4);
-    let data_str = std::str::from_utf8(&data[..header_len]).ok()?;
-
-    if !data_str.starts_with("GET ")
-        && !data_str.starts_with("POST ")
-        && !data_str.starts_with("PUT ")
-        && !data_str.starts_with("DELETE ")
-        && !data_str.starts_with("PATCH ")
-        && !data_str.starts_with("HEAD ")
-        && !data_str.starts_with("OPTIONS ")
-    {
-        return None;
-    }
-
-    let mut request_parts = data_str.lines().next()?.split_whitespace();
-    let method = request_parts.next()?;
-    let path = request_parts.next()?;
-
-    let host = data_str
-        .lines()
-        .find(|line| line.get(..5).is_some_and(|prefix| prefix.eq_ignore_ascii_case("host:")))
-        .map_or("unknown", |line| line[5..].trim());
-
-    let url = format!("https://{host}{path}");
-    let is_spotify_client_host = is_spotify_client_host(host);
-
-    // Ad blocking patterns - endpoints that bypass CEF
-    let is_ad_related =
-        // Leavebehind ads
-        path.contains("leavebehind") ||
-        path.contains("leave-behind") ||
-        path.contains("podcast-ap4p/leavebehind") ||
-        path.contains("/ap4p/") ||
-        // Sponsored content
-        path.contains("sponsoredplaylist") ||
-        path.contains("/sponsored") ||
-        path.contains("/sponsor") ||
-        // Ad tracking
-        host.contains("aet.spotify.com") ||
-        path.contains("/ads/") ||
-        path.contains("/ad-logic") ||
-        path.contains("/adlogic") ||
-        // Gabo ad events
-        (path.contains("gabo-receiver-service") && (
-            path.contains("/advertisement") ||
-            path.contains("/ad-opportunity") ||
-            path.contains("/ads") ||
-            path.contains("/v3/events/") ||
-            path.contains("/public/v3/events/")
-        )) ||
-        // Ad creative delivery
-        path.contains("ad-creative") ||
-        path.contains("ad_creative") ||
-        path.contains("/promotion/") ||
-        // Partner/attribution tracking
-        host.contains("branch.io") ||
-        host.contains("adjust.com") ||
-        (is_spotify_client_host && (
-            path.contains("/partner_user_id") ||
-            path.contains("partner-userid")
-        )) ||
-        // Podcast ad networks
-        host.contains("megaphone.fm") ||
-        host.contains("art19.com") ||
-        host.contains("chartable.com") ||
-        host.contains("podsights.com") ||
-        (is_spotify_client_host && path.contains("nextAdSegment")) ||
-        // Display ad segments
-        (path.contains("display-segments") && path.contains("sponsor")) ||
-        // Ad event reporting
-        path.contains("/AdEvent") ||
-        path.contains("/EndAd") ||
-        path.contains("/AdDecision") ||
-        // Skip limits
-        (is_spotify_client_host && (
-            path.contains("skip-limit") ||
-            path.contains("skip_limit") ||
-            path.contains("/playback/restrictions")
-        )) ||
-        rules::is_ad_related_url(&url);
-
-    if is_ad_related {
-        Some(format!("{method} {url}"))
+    let Some(request) = HttpRequest::parse(data) else {
+        // Most writes are not request heads (HTTP/2 frames, bodies); only a
+        // head we failed to read is a fault
+        return (starts_like_request(data) && !fault::fails_open(Fault::SslMalformedRequest))
+            .then(|| "(malformed request)".to_string());
+    };
+    let url = request.url("https");
+
+    if is_ad_related_request(&request, &url) {
+        Some(format!("{} {url}", request.method))
     } else {
-        if *DEBUG_MODE || SSL_VERBOSE.load(Ordering::Relaxed) {
-            logging::log_debug(&format!("[SSL] {method} {host} {path}"));
+        let verbose = SSL_VERBOSE.load(Ordering::Relaxed);
+        if verbose || logging::log_enabled(Source::Ssl, Level::Trace) {
+            let line = format!("[SSL] {} {} {}", request.method, request.host.unwrap_or("unknown"), request.path);
+            // Verbose logging shows these lines whatever the filter says
+            if verbose {
+                logging::log_info(Source::Ssl, &line);
+            } else {
+                logging::log_trace(Source::Ssl, &line);
+            }
         }
         None
     }
@@ -131,28 +61,135 @@ fn should_block_ssl_request(data: &[u8]) -> Option<String> {
 
 hook! {
     SSL_write(ssl: *mut SSL, buf: *const c_void, num: c_int) -> c_int => REAL_SSL_WRITE {
-        if ssl.is_null() || buf.is_null() || num <= 0 {
+        if ssl.is_null() || buf.is_null() || num <= 0 || !super::is_active(HookGroup::Ssl) {
             return REAL_SSL_WRITE(ssl, buf, num);
         }
 
         let Ok(len) = usize::try_from(num) else {
             return REAL_SSL_WRITE(ssl, buf, num);
         };
-        let inspect_len = len.min(MAX_INSPECT_LEN);
         // SAFETY: Category 10 - out-of-bounds. `SSL_write` receives a non-null
-        // buffer and positive byte count; `inspect_len` is capped to that count.
-        let data = unsafe { std::slice::from_raw_parts(buf.cast::<u8>(), inspect_len) };
+        // buffer and positive byte count.
+        let written = unsafe { std::slice::from_raw_parts(buf.cast::<u8>(), len) };
+        if let Some(rewritten) = websocket::filter_write(ssl as usize, written) {
+            // Report dropped frames as written so the connection carries on
+            if rewritten.data.is_empty() {
+                return num;
+            }
+            let rewritten_len = c_int::try_from(rewritten.data.len()).unwrap_or(num);
+            let result = REAL_SSL_WRITE(ssl, rewritten.data.as_ptr().cast(), rewritten_len);
+            let Some(sent) = usize::try_from(result).ok().filter(|&sent| sent > 0) else {
+                websocket::keep_unsent(ssl as usize, rewritten);
+                return result;
+            };
+            return c_int::try_from(rewritten.consumed(sent)).unwrap_or(num);
+        }
 
+        let data = &written[..len.min(MAX_INSPECT_LEN)];
         if let Some(blocked_url) = should_block_ssl_request(data) {
-            logging::log_blocked("BLOCKED SSL", "HTTPS", &blocked_url);
+            logging::log_blocked(Source::Ssl, "BLOCKED SSL", "HTTPS", &blocked_url);
             // Return -1 to signal SSL_ERROR_SYSCALL, forcing proper error handling
             return -1;
         }
+        websocket::watch_upgrade(ssl as usize, data);
 
         REAL_SSL_WRITE(ssl, buf, num)
     }
 }
 
+hook! {
+    SSL_read(ssl: *mut SSL, buf: *mut c_void, num: c_int) -> c_int => REAL_SSL_READ {
+        if ssl.is_null() || buf.is_null() || num <= 0 {
+            return REAL_SSL_READ(ssl, buf, num);
+        }
+        let Ok(len) = usize::try_from(num) else {
+            return REAL_SSL_READ(ssl, buf, num);
+        };
+
+        // SAFETY: Category 10 - out-of-bounds. `SSL_read` receives a non-null
+        // buffer with room for `num` bytes.
+        let out = unsafe { std::slice::from_raw_parts_mut(buf.cast::<u8>(), len) };
+        websocket::read_filtered(ssl as usize, out, false, |chunk| read_chunk(ssl, chunk))
+            .unwrap_or_else(|| REAL_SSL_READ(ssl, buf, num))
+    }
+}
+
+hook! {
+    SSL_read_ex(ssl: *mut SSL, buf: *mut c_void, num: size_t, readbytes: *mut size_t) -> c_int => REAL_SSL_READ_EX {
+        if ssl.is_null() || buf.is_null() || num == 0 || readbytes.is_null() {
+            return REAL_SSL_READ_EX(ssl, buf, num, readbytes);
+        }
+
+        // SAFETY: Category 10 - out-of-bounds. `SSL_read_ex` receives a
+        // non-null buffer with room for `num` bytes.
+        let out = unsafe { std::slice::from_raw_parts_mut(buf.cast::<u8>(), num) };
+        websocket::read_filtered(ssl as usize, out, false, |chunk| read_chunk(ssl, chunk))
+            .map_or_else(|| REAL_SSL_READ_EX(ssl, buf, num, readbytes), |result| report_read_ex(result, readbytes))
+    }
+}
+
+hook! {
+    SSL_peek(ssl: *mut SSL, buf: *mut c_void, num: c_int) -> c_int => REAL_SSL_PEEK {
+        if ssl.is_null() || buf.is_null() || num <= 0 {
+            return REAL_SSL_PEEK(ssl, buf, num);
+        }
+        let Ok(len) = usize::try_from(num) else {
+            return REAL_SSL_PEEK(ssl, buf, num);
+        };
+
+        // SAFETY: Category 10 - out-of-bounds. `SSL_peek` receives a non-null
+        // buffer with room for `num` bytes.
+        let out = unsafe { std::slice::from_raw_parts_mut(buf.cast::<u8>(), len) };
+        // What is read from TLS here stays buffered for the next read
+        websocket::read_filtered(ssl as usize, out, true, |chunk| read_chunk(ssl, chunk))
+            .unwrap_or_else(|| REAL_SSL_PEEK(ssl, buf, num))
+    }
+}
+
+hook! {
+    SSL_peek_ex(ssl: *mut SSL, buf: *mut c_void, num: size_t, readbytes: *mut size_t) -> c_int => REAL_SSL_PEEK_EX {
+        if ssl.is_null() || buf.is_null() || num == 0 || readbytes.is_null() {
+            return REAL_SSL_PEEK_EX(ssl, buf, num, readbytes);
+        }
+
+        // SAFETY: Category 10 - out-of-bounds. As in `SSL_read_ex`.
+        let out = unsafe { std::slice::from_raw_parts_mut(buf.cast::<u8>(), num) };
+        websocket::read_filtered(ssl as usize, out, true, |chunk| read_chunk(ssl, chunk))
+            .map_or_else(|| REAL_SSL_PEEK_EX(ssl, buf, num, readbytes), |result| report_read_ex(result, readbytes))
+    }
+}
+
+hook! {
+    SSL_pending(ssl: *const SSL) -> c_int => REAL_SSL_PENDING {
+        let pending = REAL_SSL_PENDING(ssl);
+        websocket::pending_len(ssl as usize).map_or(pending, |held| {
+            c_int::try_from(held).map_or(c_int::MAX, |held| held.saturating_add(pending.max(0)))
+        })
+    }
+}
+
+hook! {
+    SSL_free(ssl: *mut SSL) -> () => REAL_SSL_FREE {
+        websocket::forget(ssl as usize);
+        REAL_SSL_FREE(ssl);
+    }
+}
+
+/// Read from TLS into `chunk` for the WebSocket filter, as `SSL_read` does
+fn read_chunk(ssl: *mut SSL, chunk: &mut [u8]) -> c_int {
+    let chunk_len = c_int::try_from(chunk.len()).unwrap_or(c_int::MAX);
+    REAL_SSL_READ(ssl, chunk.as_mut_ptr().cast(), chunk_len)
+}
+
+/// Turn an `SSL_read` style result into what `SSL_read_ex` returns
+fn report_read_ex(result: c_int, readbytes: *mut size_t) -> c_int {
+    let read = usize::try_from(result).unwrap_or(0);
+    // SAFETY: Category 8 - FFI boundary. `readbytes` is the caller's
+    // non-null out-parameter.
+    unsafe { *readbytes = read };
+    c_int::from(read > 0)
+}
+
 #[allow(dead_code)]
 pub fn enable_verbose_logging() {
     SSL_VERBOSE.store(true, Ordering::Relaxed);
@@ -198,4 +235,14 @@ mod tests {
         ))
         .is_some());
     }
+
+    #[test]
+    fn blocks_spotify_client_payloads_naming_ad_services() {
+        let head = "POST /esperanto/v1/call HTTP/1.1\r\nHost: spclient.wg.spotify.com\r\n\r\n";
+        let ads = [head.as_bytes(), b"\x0a\x15SlotRealtimeDecisions"].concat();
+        let playback = [head.as_bytes(), b"\x0a\x11PlaybackRequestV2"].concat();
+
+        assert!(should_block_ssl_request(&ads).is_some());
+        assert!(should_block_ssl_request(&playback).is_none());
+    }
 }
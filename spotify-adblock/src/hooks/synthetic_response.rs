@@ -0,0 +1,431 @@
+//! Synthesized completions for blocked `cef_urlrequest_create` calls
+//!
+//! Returning NULL leaves the caller's `cef_urlrequest_client_t` waiting for
+//! an `on_request_complete` that never comes, which xpui answers with
+//! retries, hangs or error toasts. Instead, a blocked request gets a fake
+//! `cef_urlrequest_t` and a task posted to the current thread's task runner
+//! reports the configured outcome to the client, as a real request would.
+
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use cef_sys::{
+    _cef_request_t, _cef_response_t, _cef_task_t, _cef_urlrequest_client_t, cef_errorcode_t, cef_response_create,
+    cef_task_runner_get_for_current_thread, cef_urlrequest_status_t, cef_urlrequest_t,
+};
+use libc::c_int;
+
+use crate::config::{BlockedResponse, Config};
+
+use super::cef_object::{add_ref_object, CefObject};
+use super::cef_util::{release, CefStringBuf};
+
+/// Pick the response for a blocked URL, honouring per-URL overrides
+pub(super) fn blocked_response_for(config: &Config, url: &str) -> BlockedResponse {
+    config
+        .blocked_response_rules
+        .iter()
+        .find(|rule| rule.url.is_match(url))
+        .map_or(config.blocked_response, |rule| rule.response)
+}
+
+/// What a fake request reports once it completes
+struct Outcome {
+    status: cef_urlrequest_status_t,
+    error: cef_errorcode_t,
+    http_status: c_int,
+    status_text: &'static str,
+    mime_type: &'static str,
+    body: &'static [u8],
+}
+
+impl Outcome {
+    fn for_response(response: BlockedResponse) -> Option<Self> {
+        let success = |http_status, status_text, mime_type, body| Self {
+            status: cef_urlrequest_status_t::UR_SUCCESS,
+            error: cef_errorcode_t::ERR_NONE,
+            http_status,
+            status_text,
+            mime_type,
+            body,
+        };
+        match response {
+            BlockedResponse::NoContent => Some(success(204, "No Content", "text/plain", b"")),
+            BlockedResponse::EmptyJson => Some(success(200, "OK", "application/json", b"{}")),
+            BlockedResponse::EmptyProtobuf => Some(success(200, "OK", "application/x-protobuf", b"")),
+            BlockedResponse::Failure => Some(Self {
+                status: cef_urlrequest_status_t::UR_FAILED,
+                error: cef_errorcode_t::ERR_BLOCKED_BY_CLIENT,
+                http_status: 0,
+                status_text: "",
+                mime_type: "",
+                body: b"",
+            }),
+            BlockedResponse::Null => None,
+        }
+    }
+}
+
+/// Rust-side state of a fake `cef_urlrequest_t`
+///
+/// Holds the references to the request and client that
+/// `cef_urlrequest_create` received; they are released with the fake.
+struct FakeUrlRequest {
+    request: *mut _cef_request_t,
+    client: *mut _cef_urlrequest_client_t,
+    response: *mut _cef_response_t,
+    outcome: Outcome,
+    completed: AtomicBool,
+    canceled: AtomicBool,
+}
+
+impl FakeUrlRequest {
+    fn status(&self) -> cef_urlrequest_status_t {
+        if self.canceled.load(Ordering::Acquire) {
+            cef_urlrequest_status_t::UR_CANCELED
+        } else if self.completed.load(Ordering::Acquire) {
+            self.outcome.status
+        } else {
+            cef_urlrequest_status_t::UR_IO_PENDING
+        }
+    }
+
+    fn error(&self) -> cef_errorcode_t {
+        if self.canceled.load(Ordering::Acquire) {
+            cef_errorcode_t::ERR_ABORTED
+        } else if self.completed.load(Ordering::Acquire) {
+            self.outcome.error
+        } else {
+            cef_errorcode_t::ERR_NONE
+        }
+    }
+}
+
+impl Drop for FakeUrlRequest {
+    fn drop(&mut self) {
+        release(self.request);
+        release(self.client);
+        release(self.response);
+    }
+}
+
+type FakeUrlRequestObject = CefObject<cef_urlrequest_t, FakeUrlRequest>;
+
+fn fake_state<'a>(urlrequest: *mut cef_urlrequest_t) -> &'a FakeUrlRequest {
+    // SAFETY: Category 8 - FFI boundary. These callbacks are only installed on
+    // fakes created by `create_fake`, and CEF passes the object as `self_`.
+    unsafe { FakeUrlRequestObject::state(urlrequest) }
+}
+
+unsafe extern "C" fn get_request(urlrequest: *mut cef_urlrequest_t) -> *mut _cef_request_t {
+    // Returned objects carry a reference for the caller
+    add_ref_object(fake_state(urlrequest).request)
+}
+
+unsafe extern "C" fn get_client(urlrequest: *mut cef_urlrequest_t) -> *mut _cef_urlrequest_client_t {
+    add_ref_object(fake_state(urlrequest).client)
+}
+
+unsafe extern "C" fn get_request_status(urlrequest: *mut cef_urlrequest_t) -> cef_urlrequest_status_t {
+    fake_state(urlrequest).status()
+}
+
+unsafe extern "C" fn get_request_error(urlrequest: *mut cef_urlrequest_t) -> cef_errorcode_t {
+    fake_state(urlrequest).error()
+}
+
+unsafe extern "C" fn get_response(urlrequest: *mut cef_urlrequest_t) -> *mut _cef_response_t {
+    let state = fake_state(urlrequest);
+    if state.completed.load(Ordering::Acquire) {
+        add_ref_object(state.response)
+    } else {
+        null_mut()
+    }
+}
+
+const unsafe extern "C" fn response_was_cached(_urlrequest: *mut cef_urlrequest_t) -> c_int {
+    0
+}
+
+unsafe extern "C" fn cancel(urlrequest: *mut cef_urlrequest_t) {
+    let state = fake_state(urlrequest);
+    if !state.completed.load(Ordering::Acquire) {
+        state.canceled.store(true, Ordering::Release);
+    }
+}
+
+fn create_fake(
+    request: *mut _cef_request_t,
+    client: *mut _cef_urlrequest_client_t,
+    response: *mut _cef_response_t,
+    outcome: Outcome,
+) -> *mut cef_urlrequest_t {
+    let urlrequest = cef_urlrequest_t {
+        get_request: Some(get_request),
+        get_client: Some(get_client),
+        get_request_status: Some(get_request_status),
+        get_request_error: Some(get_request_error),
+        get_response: Some(get_response),
+        response_was_cached: Some(response_was_cached),
+        cancel: Some(cancel),
+        ..Default::default()
+    };
+    let state = FakeUrlRequest {
+        request,
+        client,
+        response,
+        outcome,
+        completed: AtomicBool::new(false),
+        canceled: AtomicBool::new(false),
+    };
+    CefObject::create(urlrequest, state)
+}
+
+/// Deliver the body and completion to the client of a fake request
+fn complete(urlrequest: *mut cef_urlrequest_t) {
+    let state = fake_state(urlrequest);
+    let canceled = state.canceled.load(Ordering::Acquire);
+    state.completed.store(true, Ordering::Release);
+    if state.client.is_null() {
+        return;
+    }
+
+    // SAFETY: Category 8 - FFI boundary. `client` is the non-null client we
+    // hold a reference to; objects passed to CEF callbacks carry a reference
+    // that the callee releases.
+    unsafe {
+        let client = state.client;
+        let body = state.outcome.body;
+        if !canceled && !body.is_empty() {
+            if let Some(on_download_data) = (*client).on_download_data {
+                on_download_data(client, add_ref_object(urlrequest), body.as_ptr().cast(), body.len());
+            }
+        }
+        if let Some(on_request_complete) = (*client).on_request_complete {
+            on_request_complete(client, add_ref_object(urlrequest));
+        }
+    }
+}
+
+/// Task that completes a fake request on the thread that created it
+struct CompletionTask {
+    urlrequest: *mut cef_urlrequest_t,
+}
+
+impl Drop for CompletionTask {
+    fn drop(&mut self) {
+        release(self.urlrequest);
+    }
+}
+
+unsafe extern "C" fn execute_completion(task: *mut _cef_task_t) {
+    // SAFETY: Category 8 - FFI boundary. `execute_completion` is only
+    // installed on tasks created by `post_completion`.
+    let task = unsafe { CefObject::<_cef_task_t, CompletionTask>::state(task) };
+    complete(task.urlrequest);
+}
+
+/// Post the completion of `urlrequest` to the current thread's task runner
+fn post_completion(urlrequest: *mut cef_urlrequest_t) -> bool {
+    // SAFETY: Category 8 - FFI boundary. The returned runner, if any, carries
+    // a reference that is released below.
+    let runner = unsafe { cef_task_runner_get_for_current_thread() };
+    if runner.is_null() {
+        return false;
+    }
+
+    let task = CefObject::create(
+        _cef_task_t { execute: Some(execute_completion), ..Default::default() },
+        CompletionTask { urlrequest: add_ref_object(urlrequest) },
+    );
+    // SAFETY: Category 8 - FFI boundary. `runner` is non-null; `post_task`
+    // takes over the task reference we pass it.
+    let posted = unsafe { (*runner).post_task.is_some_and(|post_task| post_task(runner, task) != 0) };
+    release(runner);
+    posted
+}
+
+fn create_response(outcome: &Outcome) -> *mut _cef_response_t {
+    // SAFETY: Category 8 - FFI boundary. `cef_response_create` returns a new
+    // response with one reference, or NULL.
+    let response = unsafe { cef_response_create() };
+    if response.is_null() {
+        return response;
+    }
+
+    let (status_text_buf, mime_type_buf) = (CefStringBuf::new(outcome.status_text), CefStringBuf::new(outcome.mime_type));
+    let (status_text, mime_type) = (status_text_buf.as_cef_string(), mime_type_buf.as_cef_string());
+    // SAFETY: Category 8 - FFI boundary. `response` is non-null and the setters
+    // copy the borrowed strings.
+    unsafe {
+        if let Some(set_status) = (*response).set_status {
+            set_status(response, outcome.http_status);
+        }
+        if let Some(set_error) = (*response).set_error {
+            set_error(response, outcome.error);
+        }
+        if let Some(set_status_text) = (*response).set_status_text {
+            set_status_text(response, &raw const status_text);
+        }
+        if let Some(set_mime_type) = (*response).set_mime_type {
+            set_mime_type(response, &raw const mime_type);
+        }
+    }
+    response
+}
+
+/// Answer a blocked request with `response` instead of a real request
+///
+/// Takes over the caller's references to `request` and `client`. Falls back
+/// to NULL (the historical behaviour) when `response` is `Null` or no task
+/// runner is available on this thread.
+pub(super) fn respond_blocked(
+    request: *mut _cef_request_t,
+    client: *mut _cef_urlrequest_client_t,
+    response: BlockedResponse,
+) -> *mut cef_urlrequest_t {
+    let Some(outcome) = Outcome::for_response(response) else {
+        return null_mut();
+    };
+
+    let cef_response = create_response(&outcome);
+    let urlrequest = create_fake(request, client, cef_response, outcome);
+    if post_completion(urlrequest) {
+        urlrequest
+    } else {
+        // Nothing will complete the fake; dropping it also drops the
+        // references it took over, matching what CEF would do on failure
+        release(urlrequest);
+        null_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use regex::Regex;
+
+    use super::*;
+    use crate::config::BlockedResponseRule;
+
+    #[derive(Default, Clone)]
+    struct Recorded {
+        body: Vec<u8>,
+        completions: usize,
+        status: Option<cef_urlrequest_status_t>,
+    }
+
+    unsafe extern "C" fn on_download_data(
+        client: *mut _cef_urlrequest_client_t,
+        urlrequest: *mut cef_urlrequest_t,
+        data: *const libc::c_void,
+        data_length: usize,
+    ) {
+        // SAFETY: test client created below; `data` holds `data_length` bytes.
+        unsafe {
+            let recorded = CefObject::<_cef_urlrequest_client_t, Mutex<Recorded>>::state(client);
+            let data = std::slice::from_raw_parts(data.cast::<u8>(), data_length);
+            recorded.lock().unwrap().body.extend_from_slice(data);
+        }
+        release(urlrequest);
+    }
+
+    unsafe extern "C" fn on_request_complete(client: *mut _cef_urlrequest_client_t, urlrequest: *mut cef_urlrequest_t) {
+        // SAFETY: test client created below and a fake request from `create_fake`.
+        unsafe {
+            let recorded = CefObject::<_cef_urlrequest_client_t, Mutex<Recorded>>::state(client);
+            let mut recorded = recorded.lock().unwrap();
+            recorded.completions += 1;
+            recorded.status = (*urlrequest).get_request_status.map(|get_status| get_status(urlrequest));
+        }
+        release(urlrequest);
+    }
+
+    fn client() -> *mut _cef_urlrequest_client_t {
+        CefObject::create(
+            _cef_urlrequest_client_t {
+                on_download_data: Some(on_download_data),
+                on_request_complete: Some(on_request_complete),
+                ..Default::default()
+            },
+            Mutex::new(Recorded::default()),
+        )
+    }
+
+    fn recorded(client: *mut _cef_urlrequest_client_t) -> Recorded {
+        // SAFETY: `client` comes from `client()` and is still referenced.
+        unsafe { CefObject::<_cef_urlrequest_client_t, Mutex<Recorded>>::state(client) }.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn reports_body_and_completion_to_the_client() {
+        let client = client();
+        let outcome = Outcome::for_response(BlockedResponse::EmptyJson).unwrap();
+        let urlrequest = create_fake(null_mut(), add_ref_object(client), null_mut(), outcome);
+
+        assert_eq!(fake_state(urlrequest).status(), cef_urlrequest_status_t::UR_IO_PENDING);
+        complete(urlrequest);
+
+        let recorded = recorded(client);
+        assert_eq!(recorded.body, b"{}");
+        assert_eq!(recorded.completions, 1);
+        assert_eq!(recorded.status, Some(cef_urlrequest_status_t::UR_SUCCESS));
+        release(urlrequest);
+        release(client);
+    }
+
+    #[test]
+    fn canceled_requests_complete_without_a_body() {
+        let client = client();
+        let outcome = Outcome::for_response(BlockedResponse::EmptyJson).unwrap();
+        let urlrequest = create_fake(null_mut(), add_ref_object(client), null_mut(), outcome);
+
+        // SAFETY: `urlrequest` was created above and is still referenced.
+        unsafe { (*urlrequest).cancel.unwrap()(urlrequest) };
+        complete(urlrequest);
+
+        let recorded = recorded(client);
+        assert!(recorded.body.is_empty());
+        assert_eq!(recorded.status, Some(cef_urlrequest_status_t::UR_CANCELED));
+        release(urlrequest);
+        release(client);
+    }
+
+    #[test]
+    fn failure_reports_blocked_by_client() {
+        let outcome = Outcome::for_response(BlockedResponse::Failure).unwrap();
+        let urlrequest = create_fake(null_mut(), null_mut(), null_mut(), outcome);
+        complete(urlrequest);
+
+        assert_eq!(fake_state(urlrequest).status(), cef_urlrequest_status_t::UR_FAILED);
+        assert_eq!(fake_state(urlrequest).error(), cef_errorcode_t::ERR_BLOCKED_BY_CLIENT);
+        assert!(Outcome::for_response(BlockedResponse::Null).is_none());
+        release(urlrequest);
+    }
+
+    #[test]
+    fn selects_the_first_matching_rule() {
+        let config = Config {
+            blocked_response: BlockedResponse::NoContent,
+            blocked_response_rules: vec![
+                BlockedResponseRule {
+                    url: Regex::new(r"/ads/v\d+/").unwrap(),
+                    response: BlockedResponse::EmptyProtobuf,
+                },
+                BlockedResponseRule { url: Regex::new(r"/ads/.*").unwrap(), response: BlockedResponse::Failure },
+            ],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            blocked_response_for(&config, "https://spclient.wg.spotify.com/ads/v2/slot"),
+            BlockedResponse::EmptyProtobuf
+        );
+        assert_eq!(
+            blocked_response_for(&config, "https://spclient.wg.spotify.com/ads/slot"),
+            BlockedResponse::Failure
+        );
+        assert_eq!(blocked_response_for(&config, "https://example.com/"), BlockedResponse::NoContent);
+    }
+}
@@ -0,0 +1,553 @@
+//! Message inspection on the dealer WebSocket
+//!
+//! The dealer connection (`*-dealer.spotify.com`) must stay up for playback
+//! sync, so host and URL rules allow it wholesale, yet ad-related pushes
+//! arrive on it too. When `SSL_write` sees a WebSocket upgrade to a dealer
+//! host, the connection is tracked here. Its `SSL_read` data is buffered
+//! until it forms whole frames, and text messages in either direction are
+//! decoded as JSON envelopes and run through `websocket_rules`. Dropped
+//! incoming messages are cut out of the stream handed to Spotify, so the
+//! connection itself is never disturbed; dropped outgoing messages are cut
+//! out of the buffer handed to the real `SSL_write`. Bytes read ahead for
+//! Spotify still count towards `SSL_pending`. Compressed (`permessage-deflate`) and
+//! fragmented messages are passed through uninspected, and so are writes
+//! that do not consist of whole frames.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+
+use libc::c_int;
+use serde::Deserialize;
+
+use crate::config::{WebSocketAction, WebSocketDirection, WebSocketRule, CONFIG};
+use crate::utils::log_filter::{Level, Source};
+use crate::utils::logging;
+
+use super::http_request::HttpRequest;
+
+// An upgrade response this long is not one we understand
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+// Frames are held back until complete; larger ones end inspection
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+// Upper bound on a single read from TLS, whatever the caller asked for
+const READ_CHUNK_LEN: usize = 16 * 1024;
+
+// Spotify keeps one dealer connection; bounded in case of reconnect storms
+const MAX_CONNECTIONS: usize = 16;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// One WebSocket frame with its payload unmasked
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    /// RSV1, set on `permessage-deflate` compressed messages
+    compressed: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parse the frame at the start of `data`, returning it and its length on
+/// the wire, or `None` if `data` does not hold a complete frame yet
+fn parse_frame(data: &[u8]) -> Option<(Frame, usize)> {
+    let [first, second, ..] = *data else {
+        return None;
+    };
+    let (length, header_len) = match second & 0x7f {
+        126 => (u64::from(u16::from_be_bytes(data.get(2..4)?.try_into().ok()?)), 4),
+        127 => (u64::from_be_bytes(data.get(2..10)?.try_into().ok()?), 10),
+        length => (u64::from(length), 2),
+    };
+    let masked = second & 0x80 != 0;
+    let payload_start: usize = header_len + if masked { 4 } else { 0 };
+    let frame_len = payload_start.checked_add(usize::try_from(length).ok()?)?;
+
+    let mut payload = data.get(payload_start..frame_len)?.to_vec();
+    if masked {
+        let key = data.get(header_len..payload_start)?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[index % 4];
+        }
+    }
+    let frame = Frame { fin: first & 0x80 != 0, compressed: first & 0x40 != 0, opcode: first & 0x0f, payload };
+    Some((frame, frame_len))
+}
+
+/// The JSON envelope of a dealer message
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+struct Envelope {
+    #[serde(default, rename = "type")]
+    message_type: String,
+    #[serde(default)]
+    uri: Option<String>,
+    /// Where requests carry their URI
+    #[serde(default)]
+    message_ident: Option<String>,
+}
+
+impl Envelope {
+    fn uri(&self) -> &str {
+        self.uri.as_deref().or(self.message_ident.as_deref()).unwrap_or("")
+    }
+}
+
+const fn direction_label(direction: WebSocketDirection) -> &'static str {
+    match direction {
+        WebSocketDirection::Incoming => "IN",
+        WebSocketDirection::Outgoing => "OUT",
+        WebSocketDirection::Both => "BOTH",
+    }
+}
+
+fn rule_matches(rule: &WebSocketRule, direction: WebSocketDirection, envelope: &Envelope) -> bool {
+    rule.meta.enabled
+        && (rule.direction == WebSocketDirection::Both || rule.direction == direction)
+        && rule.message_type.as_ref().is_none_or(|message_type| *message_type == envelope.message_type)
+        && rule.uri.as_ref().is_none_or(|uri| uri.is_match(envelope.uri()))
+}
+
+/// Run a frame through the rules, returning whether to keep it
+fn keep_frame(rules: &[WebSocketRule], direction: WebSocketDirection, frame: &Frame) -> bool {
+    if frame.opcode != OPCODE_TEXT || !frame.fin || frame.compressed {
+        return true;
+    }
+    let Ok(envelope) = serde_json::from_slice::<Envelope>(&frame.payload) else {
+        return true;
+    };
+
+    let label = direction_label(direction);
+    if logging::log_enabled(Source::Websocket, Level::Trace) {
+        logging::log_trace(Source::Websocket, &format!("[WS] {label} {} {}", envelope.message_type, envelope.uri()));
+    }
+    for rule in rules.iter().filter(|rule| rule_matches(rule, direction, &envelope)) {
+        match rule.action {
+            WebSocketAction::Log => logging::log_info(Source::Websocket, &format!(
+                "WEBSOCKET {label} ({}, {}): {} {}",
+                rule.meta.name,
+                rule.meta.category.as_str(),
+                envelope.message_type,
+                envelope.uri()
+            )),
+            WebSocketAction::Drop => {
+                let context = format!("BLOCKED WEBSOCKET {label} ({}, {})", rule.meta.name, rule.meta.category.as_str());
+                logging::log_blocked(Source::Websocket, &context, &envelope.message_type, envelope.uri());
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Upgrade request sent, waiting for the `101` response head
+    Upgrading,
+    /// Exchanging frames
+    Open,
+    /// Closed, refused or not understood; bytes are no longer inspected
+    Passthrough,
+}
+
+/// A write on a tracked connection with its dropped frames cut out
+#[derive(Debug)]
+pub(super) struct RewrittenWrite {
+    /// The caller's bytes, to recognise the write when it is retried
+    original: Vec<u8>,
+    /// The bytes to write instead
+    pub(super) data: Vec<u8>,
+    /// Start and length in `original` of each kept frame
+    kept_frames: Vec<(usize, usize)>,
+}
+
+impl RewrittenWrite {
+    /// How many of the caller's bytes the first `written` bytes of `data`
+    /// account for, counting dropped frames before them as written
+    pub(super) fn consumed(&self, written: usize) -> usize {
+        let mut kept_before = 0;
+        for &(start, len) in &self.kept_frames {
+            if written < kept_before + len {
+                return start + (written - kept_before);
+            }
+            kept_before += len;
+        }
+        self.original.len()
+    }
+}
+
+/// State of a tracked connection
+#[derive(Debug)]
+struct Connection {
+    phase: Phase,
+    /// Bytes read from TLS that do not form a complete unit yet
+    pending: Vec<u8>,
+    /// Bytes ready to hand to Spotify
+    deliverable: Vec<u8>,
+    /// A rewritten write that failed, kept so a retry hands the same buffer
+    /// to `SSL_write` again
+    unsent: Option<RewrittenWrite>,
+}
+
+impl Connection {
+    const fn new() -> Self {
+        Self { phase: Phase::Upgrading, pending: Vec::new(), deliverable: Vec::new(), unsent: None }
+    }
+
+    /// Take in bytes read from TLS, moving whatever can be decided on to
+    /// `deliverable` and dropping filtered messages
+    fn receive(&mut self, data: &[u8], rules: &[WebSocketRule]) {
+        self.pending.extend_from_slice(data);
+        loop {
+            match self.phase {
+                Phase::Passthrough => {
+                    self.deliverable.append(&mut self.pending);
+                    return;
+                }
+                Phase::Upgrading => {
+                    let Some(head_end) = self.pending.windows(4).position(|window| window == b"\r\n\r\n") else {
+                        if self.pending.len() > MAX_HEAD_LEN {
+                            self.phase = Phase::Passthrough;
+                            continue;
+                        }
+                        return;
+                    };
+                    let is_switching = self.pending.starts_with(b"HTTP/1.1 101");
+                    self.phase = if is_switching { Phase::Open } else { Phase::Passthrough };
+                    self.deliverable.extend(self.pending.drain(..head_end + 4));
+                }
+                Phase::Open => {
+                    let Some((frame, frame_len)) = parse_frame(&self.pending) else {
+                        if self.pending.len() > MAX_FRAME_LEN {
+                            self.phase = Phase::Passthrough;
+                            continue;
+                        }
+                        return;
+                    };
+                    let frame_bytes = self.pending.drain(..frame_len);
+                    if keep_frame(rules, WebSocketDirection::Incoming, &frame) {
+                        self.deliverable.extend(frame_bytes);
+                    }
+                    if frame.opcode == OPCODE_CLOSE {
+                        self.phase = Phase::Passthrough;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Copy up to `out.len()` deliverable bytes into `out`, leaving them
+    /// deliverable
+    fn peek_deliverable(&self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.deliverable.len());
+        out[..len].copy_from_slice(&self.deliverable[..len]);
+        len
+    }
+
+    /// Move up to `out.len()` deliverable bytes into `out`
+    fn take_deliverable(&mut self, out: &mut [u8]) -> usize {
+        let len = self.peek_deliverable(out);
+        self.deliverable.drain(..len);
+        len
+    }
+}
+
+static CONNECTIONS: LazyLock<Mutex<HashMap<usize, Arc<Mutex<Connection>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Mirror of the table size so other TLS connections skip the lock entirely
+static CONNECTION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn connection(ssl: usize) -> Option<Arc<Mutex<Connection>>> {
+    if CONNECTION_COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    CONNECTIONS.lock().ok()?.get(&ssl).cloned()
+}
+
+/// Whether a request head upgrades to a WebSocket
+fn is_websocket_upgrade(data: &[u8]) -> bool {
+    let head_len = data.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..head_len]).lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade") && value.trim().eq_ignore_ascii_case("websocket")
+        })
+    })
+}
+
+/// Start tracking `ssl` if `data` is a WebSocket upgrade to a dealer host
+pub(super) fn watch_upgrade(ssl: usize, data: &[u8]) {
+    let Some(request) = HttpRequest::parse(data) else {
+        return;
+    };
+    if request.method != "GET" || !request.host.is_some_and(|host| host.contains("dealer")) {
+        return;
+    }
+    if !is_websocket_upgrade(data) {
+        return;
+    }
+    let Ok(mut connections) = CONNECTIONS.lock() else {
+        return;
+    };
+    if connections.len() >= MAX_CONNECTIONS && !connections.contains_key(&ssl) {
+        return;
+    }
+    logging::log_debug(Source::Websocket, &format!("[WS] inspecting {}", request.url("wss")));
+    connections.insert(ssl, Arc::new(Mutex::new(Connection::new())));
+    CONNECTION_COUNT.store(connections.len(), Ordering::Relaxed);
+}
+
+/// Stop tracking `ssl`, e.g. because it is being freed
+pub(super) fn forget(ssl: usize) {
+    if CONNECTION_COUNT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if let Ok(mut connections) = CONNECTIONS.lock() {
+        connections.remove(&ssl);
+        CONNECTION_COUNT.store(connections.len(), Ordering::Relaxed);
+    }
+}
+
+/// Inspect frames written on a tracked connection, returning what to write
+/// instead if any frame was dropped
+///
+/// Only writes made up entirely of complete frames are inspected. A frame
+/// that spans two writes is left to the real `SSL_write` uninspected, and
+/// so is every frame sharing a write with it.
+pub(super) fn filter_write(ssl: usize, data: &[u8]) -> Option<RewrittenWrite> {
+    let connection = connection(ssl)?;
+    let unsent = {
+        let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+        if connection.phase != Phase::Open {
+            return None;
+        }
+        connection.unsent.take()
+    };
+    if let Some(unsent) = unsent.filter(|unsent| unsent.original == data) {
+        return Some(unsent);
+    }
+
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let (frame, frame_len) = parse_frame(&data[offset..])?;
+        frames.push((offset, frame_len, frame));
+        offset += frame_len;
+    }
+
+    let kept_frames = frames
+        .into_iter()
+        .filter(|(_, _, frame)| keep_frame(&CONFIG.websocket_rules, WebSocketDirection::Outgoing, frame))
+        .map(|(start, len, _)| (start, len))
+        .collect::<Vec<_>>();
+    let kept_len = kept_frames.iter().map(|(_, len)| len).sum::<usize>();
+    if kept_len == data.len() {
+        return None;
+    }
+    let rewritten = kept_frames.iter().flat_map(|&(start, len)| &data[start..start + len]).copied().collect();
+    Some(RewrittenWrite { original: data.to_vec(), data: rewritten, kept_frames })
+}
+
+/// Hold on to a rewritten write the real `SSL_write` did not take, so the
+/// caller's retry is written from the same buffer
+pub(super) fn keep_unsent(ssl: usize, rewritten: RewrittenWrite) {
+    if let Some(connection) = connection(ssl) {
+        connection.lock().unwrap_or_else(PoisonError::into_inner).unsent = Some(rewritten);
+    }
+}
+
+/// Bytes of a tracked connection that are ready to read without touching
+/// TLS, or `None` for connections that are not tracked
+pub(super) fn pending_len(ssl: usize) -> Option<usize> {
+    let connection = connection(ssl)?;
+    let len = connection.lock().unwrap_or_else(PoisonError::into_inner).deliverable.len();
+    Some(len)
+}
+
+/// Read from a tracked connection through its filter
+///
+/// Returns `None` for connections that are not tracked. Otherwise reads
+/// through `real_read` until at least one byte can be delivered, so dropped
+/// messages never surface as an empty read, and returns what `SSL_read`
+/// should return. `real_read` must consume what it reads; with `peek` the
+/// bytes delivered to `out` are left for the next read.
+pub(super) fn read_filtered(
+    ssl: usize,
+    out: &mut [u8],
+    peek: bool,
+    mut real_read: impl FnMut(&mut [u8]) -> c_int,
+) -> Option<c_int> {
+    let connection = connection(ssl)?;
+    // Never read more than was asked for, so little is held back from
+    // `SSL_pending` and socket readiness
+    let mut chunk = vec![0; out.len().min(READ_CHUNK_LEN)];
+    loop {
+        let delivered = {
+            let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+            if peek { connection.peek_deliverable(out) } else { connection.take_deliverable(out) }
+        };
+        if delivered > 0 {
+            return Some(c_int::try_from(delivered).unwrap_or(c_int::MAX));
+        }
+
+        let result = real_read(&mut chunk);
+        let Some(len) = usize::try_from(result).ok().filter(|&len| len > 0) else {
+            return Some(result);
+        };
+        let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+        connection.receive(&chunk[..len], &CONFIG.websocket_rules);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adblock_rules::{RuleCategory, RuleMeta};
+    use regex::Regex;
+
+    use super::*;
+
+    fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut bytes = vec![0x80 | opcode];
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => bytes.push(mask_bit | u8::try_from(len).unwrap()),
+            len => {
+                bytes.push(mask_bit | 0x7e);
+                bytes.extend_from_slice(&u16::try_from(len).unwrap().to_be_bytes());
+            }
+        }
+        match mask {
+            Some(key) => {
+                bytes.extend_from_slice(&key);
+                bytes.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ key[index % 4]));
+            }
+            None => bytes.extend_from_slice(payload),
+        }
+        bytes
+    }
+
+    fn drop_rule(uri: &str) -> WebSocketRule {
+        WebSocketRule {
+            meta: RuleMeta { name: "ad-push".to_string(), category: RuleCategory::Ads, enabled: true },
+            action: WebSocketAction::Drop,
+            direction: WebSocketDirection::Both,
+            uri: Some(Regex::new(uri).unwrap()),
+            message_type: Some("message".to_string()),
+        }
+    }
+
+    const AD_MESSAGE: &[u8] = br#"{"type":"message","uri":"hm://ads/v1/push","payloads":[]}"#;
+    const SYNC_MESSAGE: &[u8] = br#"{"type":"message","uri":"hm://connect-state/v1/cluster"}"#;
+
+    #[test]
+    fn parses_masked_and_extended_frames() {
+        let payload = vec![b'x'; 300];
+        let bytes = frame(OPCODE_TEXT, &payload, Some([1, 2, 3, 4]));
+        let (parsed, len) = parse_frame(&bytes).unwrap();
+
+        assert_eq!(len, bytes.len());
+        assert_eq!(parsed, Frame { fin: true, compressed: false, opcode: OPCODE_TEXT, payload });
+        assert_eq!(parse_frame(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(parse_frame(&bytes[..3]), None);
+    }
+
+    #[test]
+    fn decodes_request_envelopes() {
+        let envelope: Envelope =
+            serde_json::from_slice(br#"{"type":"request","message_ident":"hm://ads/v1/x","key":"1"}"#).unwrap();
+        assert_eq!(envelope.message_type, "request");
+        assert_eq!(envelope.uri(), "hm://ads/v1/x");
+    }
+
+    #[test]
+    fn drops_matching_incoming_messages() {
+        let rules = [drop_rule(r"^hm://(ads|ad-logic)/")];
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let ad = frame(OPCODE_TEXT, AD_MESSAGE, None);
+        let sync = frame(OPCODE_TEXT, SYNC_MESSAGE, None);
+        let stream = [head.as_slice(), &ad, &sync].concat();
+
+        // Split mid-frame to exercise buffering
+        let mut connection = Connection::new();
+        connection.receive(&stream[..head.len() + 5], &rules);
+        assert_eq!(connection.deliverable, head);
+        connection.receive(&stream[head.len() + 5..], &rules);
+        assert_eq!(connection.deliverable, [head.as_slice(), &sync].concat());
+        assert!(connection.pending.is_empty());
+
+        let mut out = [0; 8];
+        assert_eq!(connection.take_deliverable(&mut out), 8);
+        assert_eq!(&out, b"HTTP/1.1");
+    }
+
+    #[test]
+    fn stops_inspecting_refused_upgrades() {
+        let rules = [drop_rule("^hm://ads/")];
+        let mut connection = Connection::new();
+        let ad = frame(OPCODE_TEXT, AD_MESSAGE, None);
+        connection.receive(&[b"HTTP/1.1 403 Forbidden\r\n\r\n".as_slice(), &ad].concat(), &rules);
+
+        assert_eq!(connection.phase, Phase::Passthrough);
+        assert!(connection.deliverable.ends_with(&ad));
+    }
+
+    #[test]
+    fn keeps_frames_that_cannot_be_inspected() {
+        let rules = [drop_rule("^hm://ads/")];
+        let outgoing = |frame: &Frame| keep_frame(&rules, WebSocketDirection::Outgoing, frame);
+        let text = |payload: &[u8]| Frame { fin: true, compressed: false, opcode: OPCODE_TEXT, payload: payload.to_vec() };
+
+        assert!(!outgoing(&text(AD_MESSAGE)));
+        assert!(outgoing(&text(SYNC_MESSAGE)));
+        assert!(outgoing(&text(b"not json")));
+        assert!(outgoing(&Frame { compressed: true, ..text(AD_MESSAGE) }));
+        assert!(outgoing(&Frame { fin: false, ..text(AD_MESSAGE) }));
+    }
+
+    #[test]
+    fn reads_no_more_than_asked_and_counts_the_rest_as_pending() {
+        let ssl = 0x5eed;
+        let head = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+        CONNECTIONS.lock().unwrap().insert(ssl, Arc::new(Mutex::new(Connection::new())));
+        CONNECTION_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        let mut stream = head.as_slice();
+        let mut real_read = |chunk: &mut [u8]| {
+            assert!(chunk.len() <= 16);
+            let len = chunk.len().min(stream.len());
+            chunk[..len].copy_from_slice(&stream[..len]);
+            stream = &stream[len..];
+            c_int::try_from(len).unwrap()
+        };
+        let mut out = [0; 16];
+        // The head only becomes deliverable once it is complete
+        assert_eq!(read_filtered(ssl, &mut out[..4], true, &mut real_read), Some(4));
+        assert_eq!(&out[..4], b"HTTP");
+        assert_eq!(pending_len(ssl), Some(head.len()));
+        assert_eq!(read_filtered(ssl, &mut out, false, &mut real_read), Some(16));
+        assert_eq!(pending_len(ssl), Some(head.len() - 16));
+
+        forget(ssl);
+        assert_eq!(pending_len(ssl), None);
+    }
+
+    #[test]
+    fn maps_rewritten_writes_back_to_the_callers_bytes() {
+        // Kept frames at 0..10 and 30..50, a dropped one between them
+        let rewritten = RewrittenWrite { original: vec![0; 60], data: vec![0; 30], kept_frames: vec![(0, 10), (30, 20)] };
+
+        assert_eq!(rewritten.consumed(5), 5);
+        assert_eq!(rewritten.consumed(10), 30);
+        assert_eq!(rewritten.consumed(15), 35);
+        assert_eq!(rewritten.consumed(30), 60);
+    }
+
+    #[test]
+    fn recognises_dealer_upgrades() {
+        let upgrade = b"GET /?access_token=x HTTP/1.1\r\nHost: gew1-dealer.spotify.com:443\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        assert!(is_websocket_upgrade(upgrade));
+        assert!(!is_websocket_upgrade(b"GET / HTTP/1.1\r\nHost: gew1-dealer.spotify.com\r\n\r\n"));
+    }
+}
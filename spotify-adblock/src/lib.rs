@@ -14,36 +14,76 @@ pub mod hooks;
 pub mod utils;
 
 // Define the hook macro for intercepting functions
+//
+// The body runs through `hooks::guard::run`, which falls back to the real
+// function on panics and nested calls. A symbol missing from the rest of
+// the process is reported once; calls then return the type's fault value,
+// or the value of the block after `else` where the function reports errors
+// differently.
 #[macro_export]
 macro_rules! hook {
     ($function_name:ident($($parameter_name:ident: $parameter_type:ty),*) -> $return_type:ty => $new_function_name:ident $body:block) => {
+        $crate::hook! {
+            $function_name($($parameter_name: $parameter_type),*) -> $return_type => $new_function_name
+            else { <$return_type as $crate::hooks::guard::FaultValue>::fault_value() }
+            $body
+        }
+    };
+    ($function_name:ident($($parameter_name:ident: $parameter_type:ty),*) -> $return_type:ty => $new_function_name:ident else $fault:block $body:block) => {
         lazy_static::lazy_static! {
             static ref $new_function_name: extern "C" fn($($parameter_type),*) -> $return_type = {
+                #[allow(clippy::missing_const_for_fn)]
+                extern "C" fn unavailable($($parameter_name: $parameter_type),*) -> $return_type {
+                    $(let _ = $parameter_name;)*
+                    $fault
+                }
+
                 let Ok(function_name) = std::ffi::CStr::from_bytes_with_nul(concat!(stringify!($function_name), "\0").as_bytes()) else {
                     unreachable!("hook symbol names are compile-time C strings");
                 };
-                // SAFETY: `dlsym` is called with `RTLD_NEXT` and a valid
-                // NUL-terminated symbol name. The pointer is checked for null
-                // before conversion.
-                let function_pointer = unsafe { libc::dlsym(libc::RTLD_NEXT, function_name.as_ptr()) };
+                // The pointer is checked for null before conversion
+                let function_pointer = $crate::hooks::registry::resolve(function_name);
                 if function_pointer.is_null() {
-                    panic!("[*] Error: Unable to find function \"{}\"", stringify!($function_name));
+                    unavailable
+                } else {
+                    // SAFETY: Category 8 - FFI boundary. The macro is only used
+                    // for interposed symbols whose parameter and return types
+                    // match the corresponding C ABI function exactly.
+                    unsafe {
+                        std::mem::transmute::<*mut libc::c_void, extern "C" fn($($parameter_type),*) -> $return_type>(function_pointer)
+                    }
                 }
-                // SAFETY: Category 8 - FFI boundary. The macro is only used
-                // for interposed symbols whose parameter and return types
-                // match the corresponding C ABI function exactly.
-                unsafe { std::mem::transmute(function_pointer) }
             };
         }
 
         #[unsafe(no_mangle)]
         pub extern "C" fn $function_name($($parameter_name: $parameter_type),*) -> $return_type {
-            $body
+            static HOOK_STATE: $crate::hooks::registry::HookState = $crate::hooks::registry::HookState::new(stringify!($function_name));
+            // Registers the hook while the library is loaded, before any call.
+            // Prioritized entries run before plain `.init_array` ones, so
+            // every hook is registered when `hooks` prepares them.
+            #[used]
+            #[unsafe(link_section = ".init_array.00200")]
+            static REGISTER_HOOK: extern "C" fn() = {
+                extern "C" fn register() {
+                    $crate::hooks::registry::register(&HOOK_STATE);
+                }
+                register
+            };
+            $crate::hooks::guard::run(&HOOK_STATE, move || $body, move || $new_function_name($($parameter_name),*))
         }
     }
 }
 
+pub use hooks::browser::{cef_browser_host_create_browser, cef_browser_host_create_browser_sync};
+pub use hooks::exec::{execv, execve, execvp, execvpe, posix_spawn, posix_spawnp};
 pub use hooks::memory::cef_string_userfree_utf16_free;
-pub use hooks::network::getaddrinfo;
+pub use hooks::network::{
+    __res_nquery, __res_query, gai_error, getaddrinfo, getaddrinfo_a, gethostbyname, gethostbyname2, gethostbyname_r,
+    res_nquery, res_query,
+};
+pub use hooks::plain_http::{close, send, write, writev};
+pub use hooks::registry::{spotify_adblock_log_hooks, spotify_adblock_set_hook_enabled};
 pub use hooks::requests::cef_urlrequest_create;
-pub use hooks::ssl::SSL_write;
+pub use hooks::socket::{connect, sendmmsg, sendmsg, sendto};
+pub use hooks::ssl::{SSL_free, SSL_peek, SSL_peek_ex, SSL_pending, SSL_read, SSL_read_ex, SSL_write};
@@ -0,0 +1,121 @@
+//! CIDR network ranges for address-level filtering
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+/// An IPv4 or IPv6 network written as `address/prefix_len`
+///
+/// A bare address is accepted as a single-host network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Check whether `address` falls inside this network
+    ///
+    /// IPv4-mapped IPv6 addresses are compared as their IPv4 form, so an
+    /// IPv4 range also covers dual-stack sockets connecting to it.
+    #[must_use]
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(u128::from(network.to_bits()), u128::from(address.to_bits()), 32, self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(network.to_bits(), address.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, width: u8, prefix_len: u8) -> bool {
+    let host_bits = u32::from(width - prefix_len);
+    network.checked_shr(host_bits).unwrap_or(0) == address.checked_shr(host_bits).unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrParseError(String);
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "invalid CIDR range \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let error = || CidrParseError(value.to_string());
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+
+        let network = IpAddr::from_str(address).map_err(|_| error())?.to_canonical();
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| error())?,
+            None => width,
+        };
+        if prefix_len > width {
+            return Err(error());
+        }
+
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().expect("valid CIDR")
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().expect("valid address")
+    }
+
+    #[test]
+    fn matches_ipv4_ranges_and_single_hosts() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.200.3.4")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7").contains(ip("192.0.2.8")));
+    }
+
+    #[test]
+    fn matches_ipv6_and_mapped_ipv4_addresses() {
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("198.51.100.0/24").contains(ip("::ffff:198.51.100.20")));
+        assert!(!cidr("198.51.100.0/24").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("example.com/24".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Optional JSON-lines log of allow/block decisions
+//!
+//! With `[logging] events` set in the config, or `SPOTIFY_ADBLOCK_EVENT_LOG`
+//! in the environment, every decision logged as allowed or blocked is also
+//! appended to that file as one JSON object per line, so it can be queried
+//! with `jq` or loaded into dashboards instead of scraping stdout. All of
+//! Spotify's processes append to the same file; every line is a single
+//! `write` on an `O_APPEND` descriptor, so lines do not interleave.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::config::CONFIG;
+use crate::hooks;
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+use crate::utils::process_role::PROCESS_ROLE;
+use crate::utils::redact;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allowed,
+    Blocked,
+}
+
+/// One line of the event log
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    timestamp_ms: u64,
+    pid: u32,
+    role: &'static str,
+    thread: i32,
+    /// Hook the decision was made in; `None` for CEF callbacks
+    hook: Option<&'static str>,
+    method: &'a str,
+    host: &'a str,
+    path: &'a str,
+    decision: Decision,
+    /// Rule or check that decided, as in the stdout line
+    rule: &'a str,
+    /// Time from entering the hook to the decision
+    latency_us: Option<u64>,
+}
+
+impl<'a> Event<'a> {
+    fn new(decision: Decision, rule: &'a str, method: &'a str, target: &'a str) -> Self {
+        let (host, path) = split_target(target);
+        let hook = hooks::current_hook();
+        Self {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)),
+            pid: std::process::id(),
+            role: PROCESS_ROLE.as_str(),
+            // SAFETY: Category 8 - FFI boundary. `gettid` has no preconditions.
+            thread: unsafe { libc::gettid() },
+            hook: hook.map(|(name, _)| name),
+            method,
+            host,
+            path,
+            decision,
+            rule,
+            latency_us: hook.map(|(_, elapsed)| u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX)),
+        }
+    }
+}
+
+/// Host and path of a logged URL, domain or `address:port`
+fn split_target(target: &str) -> (&str, &str) {
+    let without_scheme = target.split_once("://").map_or(target, |(_, rest)| rest);
+    let path_start = without_scheme.find(['/', '?']).unwrap_or(without_scheme.len());
+    without_scheme.split_at(path_start)
+}
+
+static EVENT_LOG: LazyLock<Option<Mutex<File>>> = LazyLock::new(|| {
+    let path = env::var_os("SPOTIFY_ADBLOCK_EVENT_LOG")
+        .map(PathBuf::from)
+        .or_else(|| CONFIG.logging.events.clone())?;
+    match OpenOptions::new().append(true).create(true).mode(0o600).open(&path) {
+        Ok(file) => Some(Mutex::new(file)),
+        Err(error) => {
+            logging::log_error(Source::Config, &format!("Open event log {} ({error})", path.display()));
+            None
+        }
+    }
+});
+
+/// Append a decision to the event log, if one is configured
+pub fn record(decision: Decision, rule: &str, method: &str, target: &str) {
+    let Some(event_log) = EVENT_LOG.as_ref() else {
+        return;
+    };
+    let target = redact::redact(target);
+    let Ok(mut line) = serde_json::to_vec(&Event::new(decision, rule, method, &target)) else {
+        return;
+    };
+    line.push(b'\n');
+    if let Ok(mut file) = event_log.lock() {
+        let _ = file.write_all(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_urls_domains_and_addresses() {
+        assert_eq!(split_target("https://spclient.wg.spotify.com/ads/v1?x=1"), ("spclient.wg.spotify.com", "/ads/v1?x=1"));
+        assert_eq!(split_target("hm://ads/v1/x"), ("ads", "/v1/x"));
+        assert_eq!(split_target("doubleclick.net"), ("doubleclick.net", ""));
+        assert_eq!(split_target("10.0.0.1:443"), ("10.0.0.1:443", ""));
+    }
+
+    #[test]
+    fn serializes_one_flat_object_per_event() {
+        let event = Event::new(Decision::Blocked, "BLOCKED SSL", "GET", "https://ads.example.com/ad");
+        let value = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(value["decision"], "blocked");
+        assert_eq!(value["rule"], "BLOCKED SSL");
+        assert_eq!(value["host"], "ads.example.com");
+        assert_eq!(value["path"], "/ad");
+        assert_eq!(value["pid"], std::process::id());
+        assert!(value["hook"].is_null());
+    }
+}
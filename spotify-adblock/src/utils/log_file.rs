@@ -0,0 +1,199 @@
+//! Log file with size-based rotation, shared by all Spotify processes
+//!
+//! Every process Spotify starts appends to the same file. Appends and
+//! rotation are serialised across processes with `flock` on a lock file
+//! next to the log, and a process notices that another one rotated the file
+//! by comparing inodes before each append. `flock` belongs to the open file
+//! description, which a forked child shares with its parent, so a process
+//! that finds itself forked reopens both files before taking the lock.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    /// Size after which the file is rotated; 0 never rotates
+    max_size: u64,
+    /// Rotated files kept next to the log (`.1` is the newest)
+    max_files: u32,
+    lock_path: PathBuf,
+    lock: File,
+    file: File,
+    /// Process that opened `lock` and `file`
+    pid: u32,
+}
+
+/// Holds an exclusive `flock` on a descriptor until dropped
+struct FileLock(RawFd);
+
+impl FileLock {
+    fn acquire(file: &File) -> io::Result<Self> {
+        let fd = file.as_raw_fd();
+        // SAFETY: Category 8 - FFI boundary. `fd` is an open descriptor owned
+        // by `file`.
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // SAFETY: Category 8 - FFI boundary. Unlocks the descriptor locked in
+        // `acquire`; the `LogFile` owning it outlives the lock.
+        unsafe { libc::flock(self.0, libc::LOCK_UN) };
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).mode(0o600).open(path)
+}
+
+/// `path` with `.index` appended, e.g. `spotify-adblock.log.2`
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+impl LogFile {
+    /// Open (creating it and its directory) the log at `path`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the directory, the log or its lock file cannot be created.
+    pub fn open(path: &Path, max_size: u64, max_files: u32) -> io::Result<Self> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            lock: open_append(&lock_path)?,
+            lock_path,
+            file: open_append(path)?,
+            pid: process::id(),
+        })
+    }
+
+    /// Append `bytes` in one write, rotating the file first if it would grow
+    /// past `max_size`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the lock cannot be taken or the file cannot be rotated or
+    /// written.
+    pub fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.reopen_if_forked()?;
+        let _lock = FileLock::acquire(&self.lock)?;
+        self.reopen_if_rotated()?;
+        let size = self.file.metadata()?.len();
+        if self.max_size > 0 && size > 0 && size + bytes.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(bytes)
+    }
+
+    /// Reopen both files in a child forked after they were opened, so its
+    /// lock is its own rather than the one shared with its parent
+    fn reopen_if_forked(&mut self) -> io::Result<()> {
+        let pid = process::id();
+        if pid != self.pid {
+            self.lock = open_append(&self.lock_path)?;
+            self.file = open_append(&self.path)?;
+            self.pid = pid;
+        }
+        Ok(())
+    }
+
+    /// Reopen the log when another process rotated it away
+    fn reopen_if_rotated(&mut self) -> io::Result<()> {
+        let current = fs::metadata(&self.path).map(|metadata| metadata.ino()).ok();
+        if current != Some(self.file.metadata()?.ino()) {
+            self.file = open_append(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            match fs::rename(rotated_path(&self.path, index), rotated_path(&self.path, index + 1)) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("spotify-adblock-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory.join("spotify-adblock.log")
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_max_files() {
+        let path = temp_log("rotation");
+        let mut log = LogFile::open(&path, 8, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            log.append(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "cccccc\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 2)).unwrap(), "bbbbbb\n");
+        assert!(!rotated_path(&path, 3).exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn reopens_its_files_after_a_fork() {
+        let path = temp_log("fork");
+        let mut log = LogFile::open(&path, 0, 1).unwrap();
+        let inherited = log.lock.as_raw_fd();
+        // As seen by a child forked from the process that opened the log
+        log.pid = 0;
+        log.append(b"child\n").unwrap();
+
+        assert_eq!(log.pid, process::id());
+        assert_ne!(log.lock.as_raw_fd(), inherited);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "child\n");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn follows_a_rotation_done_by_another_process() {
+        let path = temp_log("shared");
+        let mut first = LogFile::open(&path, 8, 1).unwrap();
+        let mut second = LogFile::open(&path, 8, 1).unwrap();
+        first.append(b"first1\n").unwrap();
+        first.append(b"first2\n").unwrap();
+        second.append(b"second\n").unwrap();
+
+        // `second` still had the rotated-away file open
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "first2\n");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+//! Log levels and per-source filtering
+//!
+//! Every log line has a level and the part of the library it comes from.
+//! The `[logging]` config sets a default level and overrides per source;
+//! `SPOTIFY_ADBLOCK_LOG` takes a spec such as `warn,ssl=info` on top of
+//! that, e.g. to see only the blocks made by the SSL hook.
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Nothing is logged; only used in filters
+    Off,
+    Error,
+    Warn,
+    /// Allowed and blocked decisions and notable events
+    Info,
+    Debug,
+    /// Every inspected request and message
+    Trace,
+}
+
+impl Level {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "off" => Self::Off,
+            "error" => Self::Error,
+            "warn" => Self::Warn,
+            "info" => Self::Info,
+            "debug" => Self::Debug,
+            "trace" => Self::Trace,
+            _ => return None,
+        })
+    }
+}
+
+/// Part of the library a log line comes from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// Resolver hooks
+    Dns,
+    /// `connect` and QUIC suppression
+    Socket,
+    /// `cef_urlrequest_create` and the browser's resource handlers
+    Cef,
+    Ssl,
+    /// Plain-HTTP requests on `send`/`write`/`writev`
+    Http,
+    Websocket,
+    Cosmetic,
+    /// Loading the config and setting up log output
+    Config,
+    /// Request and logging statistics
+    Stats,
+    /// The hook machinery: registry, faults and panics
+    Hooks,
+}
+
+impl Source {
+    const ALL: [Self; 10] = [
+        Self::Dns,
+        Self::Socket,
+        Self::Cef,
+        Self::Ssl,
+        Self::Http,
+        Self::Websocket,
+        Self::Cosmetic,
+        Self::Config,
+        Self::Stats,
+        Self::Hooks,
+    ];
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Socket => "socket",
+            Self::Cef => "cef",
+            Self::Ssl => "ssl",
+            Self::Http => "http",
+            Self::Websocket => "websocket",
+            Self::Cosmetic => "cosmetic",
+            Self::Config => "config",
+            Self::Stats => "stats",
+            Self::Hooks => "hooks",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|source| source.as_str() == name)
+    }
+}
+
+/// Most detailed level logged, per source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFilter {
+    levels: [Level; Source::ALL.len()],
+}
+
+impl LogFilter {
+    /// Filter logging up to `level` from every source
+    #[must_use]
+    pub const fn new(level: Level) -> Self {
+        Self { levels: [level; Source::ALL.len()] }
+    }
+
+    pub const fn set(&mut self, source: Source, level: Level) {
+        self.levels[source as usize] = level;
+    }
+
+    /// Log at least up to `level` from every source
+    pub fn raise(&mut self, level: Level) {
+        for current in &mut self.levels {
+            *current = (*current).max(level);
+        }
+    }
+
+    /// Whether a line of `level` from `source` is logged
+    #[must_use]
+    pub fn allows(&self, source: Source, level: Level) -> bool {
+        level != Level::Off && level <= self.levels[source as usize]
+    }
+
+    /// Apply a comma-separated spec: a bare level sets every source, and
+    /// `source=level` one of them, e.g. `warn,ssl=info`
+    ///
+    /// # Errors
+    ///
+    /// Returns the first entry that names no level or source; the entries
+    /// before it are applied.
+    pub fn apply_spec<'a>(&mut self, spec: &'a str) -> Result<(), &'a str> {
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=') {
+                None => *self = Self::new(Level::parse(entry).ok_or(entry)?),
+                Some((source, level)) => {
+                    let source = Source::parse(source.trim()).ok_or(entry)?;
+                    self.set(source, Level::parse(level.trim()).ok_or(entry)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_by_level_per_source() {
+        let mut filter = LogFilter::new(Level::Info);
+        filter.set(Source::Dns, Level::Off);
+
+        assert!(filter.allows(Source::Ssl, Level::Error));
+        assert!(filter.allows(Source::Ssl, Level::Info));
+        assert!(!filter.allows(Source::Ssl, Level::Debug));
+        assert!(!filter.allows(Source::Dns, Level::Error));
+    }
+
+    #[test]
+    fn applies_specs_in_order() {
+        let mut filter = LogFilter::new(Level::Info);
+        filter.apply_spec("warn, ssl=info,cef=trace").unwrap();
+
+        assert!(!filter.allows(Source::Dns, Level::Info));
+        assert!(filter.allows(Source::Dns, Level::Warn));
+        assert!(filter.allows(Source::Ssl, Level::Info));
+        assert!(filter.allows(Source::Cef, Level::Trace));
+        assert_eq!(filter.apply_spec("info,tls=debug"), Err("tls=debug"));
+        assert_eq!(filter.apply_spec("loud"), Err("loud"));
+    }
+}
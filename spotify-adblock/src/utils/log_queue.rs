@@ -0,0 +1,326 @@
+//! Bounded lock-free queue between hooks and the logger thread
+//!
+//! Hooks run on Spotify's network threads, so they must never wait for a
+//! slow or blocked stdout or log file. Log lines are pushed onto a bounded
+//! multi-producer queue (Vyukov's array queue) and written by a background
+//! thread; when the queue is full the line is dropped and counted instead of
+//! blocking. Whatever is still queued at exit is flushed from `atexit`,
+//! along with the summaries of repeated lines still being counted.
+
+use std::cell::UnsafeCell;
+use std::cmp::Ordering as CmpOrdering;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Once, OnceLock};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use crate::utils::log_filter::Source;
+use crate::utils::logging;
+use crate::utils::process_role::{ProcessRole, PROCESS_ROLE};
+
+/// Lines the queue holds; a power of two
+const CAPACITY: usize = 4096;
+
+/// How long the logger thread sleeps when nobody wakes it
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Slot {
+    /// Position this slot is next written (== position) or read
+    /// (== position + 1) at
+    sequence: AtomicUsize,
+    line: UnsafeCell<Option<String>>,
+}
+
+pub struct LogQueue {
+    slots: Box<[Slot]>,
+    enqueue_position: AtomicUsize,
+    dequeue_position: AtomicUsize,
+}
+
+// SAFETY: Category 8 - FFI boundary. A slot's line is only accessed by the
+// single thread that claimed the slot's position through the sequence
+// protocol in `push` and `pop`.
+unsafe impl Sync for LogQueue {}
+
+impl std::fmt::Debug for LogQueue {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.debug_struct("LogQueue").field("capacity", &self.slots.len()).finish_non_exhaustive()
+    }
+}
+
+impl LogQueue {
+    /// Queue holding `capacity` lines, rounded up to a power of two
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let slots = (0..capacity.next_power_of_two())
+            .map(|position| Slot { sequence: AtomicUsize::new(position), line: UnsafeCell::new(None) })
+            .collect();
+        Self { slots, enqueue_position: AtomicUsize::new(0), dequeue_position: AtomicUsize::new(0) }
+    }
+
+    fn slot(&self, position: usize) -> &Slot {
+        &self.slots[position & (self.slots.len() - 1)]
+    }
+
+    /// Queue `line`, handing it back when the queue is full
+    ///
+    /// # Errors
+    ///
+    /// Returns the line if every slot is taken.
+    pub fn push(&self, line: String) -> Result<(), String> {
+        let mut position = self.enqueue_position.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(position);
+            match slot.sequence.load(Ordering::Acquire).cmp(&position) {
+                CmpOrdering::Equal => {
+                    let next = position.wrapping_add(1);
+                    match self.enqueue_position.compare_exchange_weak(position, next, Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => {
+                            // SAFETY: Category 8 - FFI boundary. Winning the
+                            // exchange gives this thread the slot until the
+                            // sequence is advanced.
+                            unsafe { *slot.line.get() = Some(line) };
+                            slot.sequence.store(next, Ordering::Release);
+                            return Ok(());
+                        }
+                        Err(current) => position = current,
+                    }
+                }
+                // The slot still holds the line from one lap ago
+                CmpOrdering::Less => return Err(line),
+                CmpOrdering::Greater => position = self.enqueue_position.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Oldest queued line, if any
+    pub fn pop(&self) -> Option<String> {
+        let mut position = self.dequeue_position.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(position);
+            let next = position.wrapping_add(1);
+            match slot.sequence.load(Ordering::Acquire).cmp(&next) {
+                CmpOrdering::Equal => {
+                    match self.dequeue_position.compare_exchange_weak(position, next, Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => {
+                            // SAFETY: Category 8 - FFI boundary. Winning the
+                            // exchange gives this thread the written slot
+                            // until the sequence is advanced.
+                            let line = unsafe { (*slot.line.get()).take() };
+                            slot.sequence.store(position.wrapping_add(self.slots.len()), Ordering::Release);
+                            return line;
+                        }
+                        Err(current) => position = current,
+                    }
+                }
+                // Nothing written here yet
+                CmpOrdering::Less => return None,
+                CmpOrdering::Greater => position = self.dequeue_position.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+static QUEUE: LazyLock<LogQueue> = LazyLock::new(|| LogQueue::new(CAPACITY));
+
+/// Lines dropped because the queue was full
+static DROPPED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Process the logger thread runs in; a forked child has to start its own
+static WRITER_PID: AtomicU32 = AtomicU32::new(0);
+
+static WRITER: OnceLock<Thread> = OnceLock::new();
+
+/// Set while the logger thread waits for lines
+static WRITER_IDLE: AtomicBool = AtomicBool::new(false);
+
+static WRITE_LINE: OnceLock<fn(&str)> = OnceLock::new();
+
+/// Registers `flush_at_exit` once; forked children inherit the registration
+static AT_EXIT: Once = Once::new();
+
+/// Number of lines dropped because the queue was full
+#[must_use]
+pub fn dropped_lines() -> usize {
+    DROPPED_COUNT.load(Ordering::Relaxed)
+}
+
+/// Write every queued line through `write_line`
+fn drain(write_line: fn(&str)) {
+    while let Some(line) = QUEUE.pop() {
+        write_line(&line);
+    }
+}
+
+extern "C" fn flush_at_exit() {
+    if let Some(write_line) = WRITE_LINE.get() {
+        logging::flush_all_repeats();
+        drain(*write_line);
+    }
+}
+
+/// Body of the logger thread
+fn write_lines(write_line: fn(&str)) -> ! {
+    let mut reported_drops = 0;
+    loop {
+        drain(write_line);
+        let drops = dropped_lines();
+        if drops != reported_drops {
+            logging::log_error(Source::Stats, &format!("{} log lines dropped, the log queue was full", drops - reported_drops));
+            reported_drops = drops;
+            continue;
+        }
+        logging::flush_expired_repeats();
+
+        WRITER_IDLE.store(true, Ordering::SeqCst);
+        // A line pushed before the flag was set would not wake the thread
+        if let Some(line) = QUEUE.pop() {
+            WRITER_IDLE.store(false, Ordering::SeqCst);
+            write_line(&line);
+            continue;
+        }
+        thread::park_timeout(IDLE_INTERVAL);
+        WRITER_IDLE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// How `enqueue` writes lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Queued for the logger thread
+    Background,
+    /// Written by the calling thread, in processes that must not start one
+    Synchronous,
+}
+
+impl WriteMode {
+    /// Mode for this process: the zygote must stay single-threaded to fork
+    /// renderers
+    #[must_use]
+    pub fn for_process() -> Self {
+        if *PROCESS_ROLE == ProcessRole::Zygote { Self::Synchronous } else { Self::Background }
+    }
+}
+
+/// Start the logger thread for this process unless it runs already;
+/// `false` if lines have to be written synchronously
+fn ensure_writer(write_line: fn(&str), mode: WriteMode) -> bool {
+    if mode == WriteMode::Synchronous {
+        return false;
+    }
+    let pid = std::process::id();
+    let writer_pid = WRITER_PID.load(Ordering::Acquire);
+    if writer_pid == pid {
+        return true;
+    }
+    if WRITER_PID.compare_exchange(writer_pid, pid, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return WRITER_PID.load(Ordering::Acquire) == pid;
+    }
+
+    let _ = WRITE_LINE.set(write_line);
+    AT_EXIT.call_once(|| {
+        // SAFETY: Category 8 - FFI boundary. `flush_at_exit` is a plain
+        // `extern "C"` function without arguments.
+        unsafe { libc::atexit(flush_at_exit) };
+    });
+
+    let spawned = thread::Builder::new().name("adblock-logger".to_string()).spawn(move || write_lines(write_line));
+    let Ok(handle) = spawned else {
+        WRITER_PID.store(0, Ordering::Release);
+        return false;
+    };
+    // A forked child keeps the parent's handle and its writer only wakes up
+    // by timeout
+    let _ = WRITER.set(handle.thread().clone());
+    true
+}
+
+/// Hand `line` to the logger thread, or write it through `write_line` where
+/// there is none
+pub fn enqueue(line: String, write_line: fn(&str), mode: WriteMode) {
+    if !ensure_writer(write_line, mode) {
+        drain(write_line);
+        write_line(&line);
+        return;
+    }
+    if QUEUE.push(line).is_err() {
+        DROPPED_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if WRITER_IDLE.load(Ordering::SeqCst) {
+        if let Some(writer) = WRITER.get() {
+            writer.unpark();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn keeps_lines_in_order_and_refuses_when_full() {
+        let queue = LogQueue::new(4);
+        for index in 0..4 {
+            queue.push(format!("line {index}")).unwrap();
+        }
+        assert_eq!(queue.push("overflow".to_string()), Err("overflow".to_string()));
+
+        assert_eq!(queue.pop().as_deref(), Some("line 0"));
+        queue.push("line 4".to_string()).unwrap();
+        let rest = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(rest, ["line 1", "line 2", "line 3", "line 4"]);
+    }
+
+    #[test]
+    fn loses_nothing_with_concurrent_producers() {
+        let queue = LogQueue::new(1024);
+        thread::scope(|scope| {
+            for producer in 0..4 {
+                let queue = &queue;
+                scope.spawn(move || {
+                    for index in 0..200 {
+                        queue.push(format!("{producer}:{index}")).unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut lines = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 800);
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), 800);
+    }
+
+    static WRITTEN: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+
+    fn record(line: &str) {
+        let thread = thread::current().name().map(str::to_string);
+        WRITTEN.lock().unwrap().push((line.to_string(), thread));
+    }
+
+    #[test]
+    fn synchronous_mode_writes_on_the_calling_thread() {
+        let name = thread::current().name().map(str::to_string);
+        enqueue("synchronous line".to_string(), record, WriteMode::Synchronous);
+
+        assert!(WRITTEN.lock().unwrap().contains(&("synchronous line".to_string(), name)));
+    }
+
+    #[test]
+    fn background_mode_starts_the_logger_thread() {
+        assert!(ensure_writer(record, WriteMode::Background));
+
+        // Another test may have won the race to start it
+        let started = Instant::now();
+        while WRITER.get().is_none() && started.elapsed() < Duration::from_secs(1) {
+            thread::yield_now();
+        }
+        assert_eq!(WRITER.get().and_then(Thread::name), Some("adblock-logger"));
+        assert_eq!(WRITER_PID.load(Ordering::Acquire), std::process::id());
+    }
+}
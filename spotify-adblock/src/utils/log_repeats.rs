@@ -0,0 +1,190 @@
+//! Coalescing of repeated decision lines
+//!
+//! Spotify retries blocked endpoints constantly, so one blocked host can
+//! produce thousands of identical lines an hour. Within `repeat_window`
+//! seconds only the first `repeat_limit` copies of an allowed or blocked line
+//! are logged; the rest are counted and summarised once the window is over,
+//! e.g. `... (blocked 312× in last 60s)`. Both can be overridden per source.
+//! The counters and the event log still see every decision.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::utils::log_filter::Source;
+
+/// Lines tracked at once; further distinct lines are logged unthrottled
+const MAX_TRACKED_LINES: usize = 1024;
+
+/// How often expired windows are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Copies of a line logged per window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Limit {
+    window: Duration,
+    copies: u32,
+}
+
+#[derive(Debug)]
+struct Repeats {
+    started: Instant,
+    limit: Limit,
+    /// Copies logged in this window
+    logged: u32,
+    /// Copies counted but not logged in this window
+    suppressed: u64,
+    verb: &'static str,
+}
+
+impl Repeats {
+    fn summary(&self, line: &str) -> Option<String> {
+        (self.suppressed > 0).then(|| {
+            format!(
+                "{line} ({} {}× in last {}s)",
+                self.verb,
+                self.suppressed + u64::from(self.logged),
+                self.limit.window.as_secs()
+            )
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RepeatFilter {
+    limit: Limit,
+    /// Limits overriding `limit` for single sources
+    sources: HashMap<Source, Limit>,
+    lines: HashMap<String, Repeats>,
+    last_sweep: Option<Instant>,
+}
+
+impl RepeatFilter {
+    /// Filter logging `limit` copies of a line per `window`; a zero window
+    /// logs every line
+    #[must_use]
+    pub fn new(window: Duration, limit: u32) -> Self {
+        Self {
+            limit: Limit { window, copies: limit },
+            sources: HashMap::new(),
+            lines: HashMap::new(),
+            last_sweep: None,
+        }
+    }
+
+    /// Log `limit` copies per `window` of the lines from `source` instead
+    pub fn set(&mut self, source: Source, window: Duration, limit: u32) {
+        self.sources.insert(source, Limit { window, copies: limit });
+    }
+
+    /// Forget lines whose window is over, pushing their summaries
+    fn sweep(&mut self, now: Instant, summaries: &mut Vec<String>) {
+        if self.last_sweep.is_some_and(|last_sweep| now.duration_since(last_sweep) < SWEEP_INTERVAL) {
+            return;
+        }
+        self.last_sweep = Some(now);
+        let expired = self
+            .lines
+            .iter()
+            .filter(|(_, repeats)| now.duration_since(repeats.started) >= repeats.limit.window)
+            .map(|(line, _)| line.clone())
+            .collect::<Vec<_>>();
+        for line in expired {
+            if let Some(repeats) = self.lines.remove(&line) {
+                summaries.extend(repeats.summary(&line));
+            }
+        }
+    }
+
+    /// Summaries of the windows over at `now`, for when no further line
+    /// comes in to carry them
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut summaries = Vec::new();
+        self.sweep(now, &mut summaries);
+        summaries
+    }
+
+    /// Summaries of every window still open, e.g. at exit
+    pub fn drain(&mut self) -> Vec<String> {
+        self.lines.drain().filter_map(|(line, repeats)| repeats.summary(&line)).collect()
+    }
+
+    /// Count one copy of `line` from `source` at `now`; returns whether to
+    /// log it, after the summaries of windows that ended
+    pub fn check(&mut self, line: &str, source: Source, verb: &'static str, now: Instant) -> (bool, Vec<String>) {
+        let mut summaries = Vec::new();
+        self.sweep(now, &mut summaries);
+        let limit = self.sources.get(&source).copied().unwrap_or(self.limit);
+        if limit.window.is_zero() {
+            return (true, summaries);
+        }
+
+        if let Some(repeats) = self.lines.get(line) {
+            if now.duration_since(repeats.started) >= repeats.limit.window {
+                summaries.extend(repeats.summary(line));
+                self.lines.remove(line);
+            }
+        }
+        if let Some(repeats) = self.lines.get_mut(line) {
+            if repeats.logged < repeats.limit.copies {
+                repeats.logged += 1;
+                return (true, summaries);
+            }
+            repeats.suppressed += 1;
+            return (false, summaries);
+        }
+        if self.lines.len() < MAX_TRACKED_LINES {
+            self.lines.insert(line.to_string(), Repeats { started: now, limit, logged: 1, suppressed: 0, verb });
+        }
+        (true, summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_repeats_within_the_window() {
+        let mut filter = RepeatFilter::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+
+        assert_eq!(filter.check("[-] ads", Source::Dns, "blocked", start), (true, vec![]));
+        assert_eq!(filter.check("[-] ads", Source::Dns, "blocked", start), (true, vec![]));
+        for _ in 0..310 {
+            assert_eq!(filter.check("[-] ads", Source::Dns, "blocked", start + Duration::from_secs(1)), (false, vec![]));
+        }
+        assert_eq!(filter.check("[+] api", Source::Ssl, "allowed", start + Duration::from_secs(2)), (true, vec![]));
+
+        let (logged, summaries) = filter.check("[-] ads", Source::Dns, "blocked", start + Duration::from_secs(61));
+        assert!(logged);
+        assert_eq!(summaries, ["[-] ads (blocked 312× in last 60s)"]);
+    }
+
+    #[test]
+    fn summarises_without_further_lines() {
+        let mut filter = RepeatFilter::new(Duration::from_secs(60), 1);
+        filter.set(Source::Ssl, Duration::from_secs(10), 1);
+        let start = Instant::now();
+        for _ in 0..3 {
+            filter.check("[-] ads", Source::Dns, "blocked", start);
+            filter.check("[-] api", Source::Ssl, "blocked", start);
+        }
+
+        assert!(filter.expire(start + Duration::from_secs(5)).is_empty());
+        assert_eq!(filter.expire(start + Duration::from_secs(10)), ["[-] api (blocked 3× in last 10s)"]);
+        assert_eq!(filter.drain(), ["[-] ads (blocked 3× in last 60s)"]);
+        assert!(filter.drain().is_empty());
+    }
+
+    #[test]
+    fn logs_everything_without_a_window() {
+        let mut filter = RepeatFilter::new(Duration::ZERO, 1);
+        let now = Instant::now();
+        filter.set(Source::Ssl, Duration::from_secs(60), 1);
+        for _ in 0..3 {
+            assert_eq!(filter.check("[-] ads", Source::Dns, "blocked", now), (true, vec![]));
+        }
+        assert_eq!(filter.check("[-] api", Source::Ssl, "blocked", now), (true, vec![]));
+        assert_eq!(filter.check("[-] api", Source::Ssl, "blocked", now), (false, vec![]));
+    }
+}
@@ -1,5 +1,17 @@
-use crate::config::DEBUG_MODE;
+use crate::config::{LoggingConfig, DEBUG_MODE};
+use crate::hooks;
+use crate::utils::event_log::{self, Decision};
+use crate::utils::log_file::LogFile;
+use crate::utils::log_filter::{Level, LogFilter, Source};
+use crate::utils::log_queue::{self, WriteMode};
+use crate::utils::log_repeats::RepeatFilter;
+use crate::utils::redact;
+use crate::utils::process_role::PROCESS_ROLE;
+use std::borrow::Cow;
+use std::env;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 // Log counters for monitoring
 static DEBUG_COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -9,46 +21,269 @@ static BLOCKED_COUNT: AtomicUsize = AtomicUsize::new(0);
 // Maximum log line length for memory safety
 const MAX_LOG_LENGTH: usize = 512;
 
-/// Truncate message to prevent buffer overflows
+/// Truncate message to prevent buffer overflows, cutting on a character
+/// boundary
 fn truncate_message(message: &str) -> &str {
-    if message.len() > MAX_LOG_LENGTH {
-        &message[0..MAX_LOG_LENGTH]
-    } else {
-        message
+    if message.len() <= MAX_LOG_LENGTH {
+        return message;
+    }
+    let mut end = MAX_LOG_LENGTH;
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    &message[..end]
+}
+
+/// Mask secrets in `message`, then truncate it; masking first means a
+/// secret cut short cannot slip past the patterns
+fn sanitize(message: &str) -> Cow<'_, str> {
+    match redact::redact(message) {
+        Cow::Borrowed(message) => Cow::Borrowed(truncate_message(message)),
+        Cow::Owned(message) => Cow::Owned(truncate_message(&message).to_string()),
+    }
+}
+
+/// Destination of log lines, set once the config is loaded
+#[derive(Debug)]
+struct Sink {
+    stdout: bool,
+    file: Option<Mutex<LogFile>>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Lines logged before the config was loaded (e.g. while loading it), kept
+/// for the log file
+static EARLY_LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Maximum number of early lines kept
+const MAX_EARLY_LINES: usize = 64;
+
+/// Filter and route log lines as `config` asks; later calls are ignored
+pub fn configure(config: &LoggingConfig) {
+    redact::configure(config.redact, config.redact_patterns.clone());
+    let mut repeats = RepeatFilter::new(Duration::from_secs(config.repeat_window), config.repeat_limit);
+    for (source, limits) in &config.repeat_sources {
+        repeats.set(
+            *source,
+            Duration::from_secs(limits.window.unwrap_or(config.repeat_window)),
+            limits.limit.unwrap_or(config.repeat_limit),
+        );
+    }
+    let _ = REPEATS.set(Mutex::new(repeats));
+    let mut filter = LogFilter::new(config.level);
+    for (source, level) in &config.sources {
+        filter.set(*source, *level);
+    }
+    let (filter, spec_error) = env_filter(filter);
+    if FILTER.set(filter).is_ok() {
+        if let Some(entry) = spec_error {
+            log_warn(Source::Config, &format!("Ignoring \"{entry}\" and the rest of SPOTIFY_ADBLOCK_LOG"));
+        }
+    }
+
+    let Ok(mut early_lines) = EARLY_LINES.lock() else {
+        return;
+    };
+    let file = config.file.then(|| LogFile::open(&config.file_path, config.max_file_size, config.max_files));
+    let error = match &file {
+        Some(Err(error)) => Some(format!("Open log file {} ({error})", config.file_path.display())),
+        _ => None,
+    };
+    let sink = Sink { stdout: config.stdout || error.is_some(), file: file.and_then(Result::ok).map(Mutex::new) };
+    if SINK.set(sink).is_err() {
+        return;
+    }
+    for line in early_lines.drain(..) {
+        append_to_file(&line);
+    }
+    drop(early_lines);
+    if let Some(error) = error {
+        log_error(Source::Config, &error);
     }
 }
 
-pub fn log_debug(message: &str) {
+fn append_to_file(line: &str) {
+    if let Some(file) = SINK.get().and_then(|sink| sink.file.as_ref()) {
+        if let Ok(mut file) = file.lock() {
+            let _ = file.append(format!("{line}\n").as_bytes());
+        }
+    }
+}
+
+/// Keep a line logged before `configure` for the log file
+fn keep_early_line(line: &str) {
+    let Ok(mut early_lines) = EARLY_LINES.lock() else {
+        return;
+    };
+    if SINK.get().is_some() {
+        // `configure` ran meanwhile and already replayed the early lines
+        drop(early_lines);
+        append_to_file(line);
+    } else if early_lines.len() < MAX_EARLY_LINES {
+        early_lines.push(line.to_string());
+    }
+}
+
+/// Write one finished line to stdout and/or the log file; runs on the
+/// logger thread
+fn write_now(line: &str) {
+    let Some(sink) = SINK.get() else {
+        println!("{line}");
+        keep_early_line(line);
+        return;
+    };
+    if sink.stdout {
+        println!("{line}");
+    }
+    append_to_file(line);
+}
+
+/// Whether this process writes lines on a logger thread
+static WRITE_MODE: LazyLock<WriteMode> = LazyLock::new(WriteMode::for_process);
+
+/// Hand a finished line to the logger thread
+fn write_line(line: String) {
+    log_queue::enqueue(line, write_now, *WRITE_MODE);
+}
+
+/// Repeated decision lines, once the config is loaded
+static REPEATS: OnceLock<Mutex<RepeatFilter>> = OnceLock::new();
+
+/// Hand a decision line to the logger thread unless it was repeated too
+/// often, along with the summaries of repeats that ended
+///
+/// Lines are built from redacted fields, so repeats are told apart by what
+/// is logged rather than by the secrets masked in it.
+fn write_decision(source: Source, line: String, verb: &'static str) {
+    let checked = REPEATS
+        .get()
+        .and_then(|repeats| repeats.lock().ok())
+        .map(|mut repeats| repeats.check(&line, source, verb, Instant::now()));
+    let (logged, summaries) = checked.unwrap_or((true, Vec::new()));
+    for summary in summaries {
+        write_line(summary);
+    }
+    if logged {
+        write_line(line);
+    }
+}
+
+/// Log the summaries of repeats whose window is over; the logger thread
+/// calls this while idle, so summaries do not wait for the next decision
+pub fn flush_expired_repeats() {
+    let summaries = REPEATS.get().and_then(|repeats| repeats.lock().ok()).map(|mut repeats| repeats.expire(Instant::now()));
+    for summary in summaries.into_iter().flatten() {
+        write_line(summary);
+    }
+}
+
+/// Log the summaries of every repeat still being counted, at exit
+pub fn flush_all_repeats() {
+    let summaries = REPEATS.get().and_then(|repeats| repeats.lock().ok()).map(|mut repeats| repeats.drain());
+    for summary in summaries.into_iter().flatten() {
+        write_line(summary);
+    }
+}
+
+/// Tag naming the process role, so lines from Spotify's subprocesses can be
+/// told apart
+fn role_tag() -> &'static str {
+    PROCESS_ROLE.as_str()
+}
+
+/// Filter before the config is loaded, from the environment alone
+static EARLY_FILTER: LazyLock<LogFilter> = LazyLock::new(|| env_filter(LogFilter::new(Level::Info)).0);
+
+static FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+/// `filter` with `SPOTIFY_ADBLOCK_DEBUG` and `SPOTIFY_ADBLOCK_LOG` applied,
+/// and the spec entry that could not be applied, if any
+fn env_filter(mut filter: LogFilter) -> (LogFilter, Option<String>) {
     if *DEBUG_MODE {
+        filter.raise(Level::Debug);
+    }
+    let spec = env::var("SPOTIFY_ADBLOCK_LOG").unwrap_or_default();
+    let error = filter.apply_spec(&spec).err().map(str::to_string);
+    (filter, error)
+}
+
+/// Whether a line of `level` from `source` would be logged, to skip
+/// formatting lines nobody sees
+pub fn log_enabled(source: Source, level: Level) -> bool {
+    FILTER.get().unwrap_or(&EARLY_FILTER).allows(source, level)
+}
+
+pub fn log_trace(source: Source, message: &str) {
+    if log_enabled(source, Level::Trace) {
+        write_line(format!("[TRACE] [{}] {}", role_tag(), sanitize(message)));
+    }
+}
+
+pub fn log_debug(source: Source, message: &str) {
+    if log_enabled(source, Level::Debug) {
         DEBUG_COUNT.fetch_add(1, Ordering::Relaxed);
-        println!("[DEBUG] {}", truncate_message(message));
+        write_line(format!("[DEBUG] [{}] {}", role_tag(), sanitize(message)));
     }
 }
 
-pub fn log_allowed(context: &str, method: &str, url: &str) {
+pub fn log_allowed(source: Source, context: &str, method: &str, url: &str) {
     ALLOWED_COUNT.fetch_add(1, Ordering::Relaxed);
-    println!("[+] {}: {} {}",
-             truncate_message(context),
-             truncate_message(method),
-             truncate_message(url)
-    );
+    event_log::record(Decision::Allowed, context, method, url);
+    if log_enabled(source, Level::Info) {
+        write_decision(source, format!("[+] [{}] {}: {} {}",
+                 role_tag(),
+                 sanitize(context),
+                 sanitize(method),
+                 sanitize(url)
+        ), "allowed");
+    }
 }
 
-pub fn log_blocked(context: &str, method: &str, url: &str) {
+pub fn log_blocked(source: Source, context: &str, method: &str, url: &str) {
     BLOCKED_COUNT.fetch_add(1, Ordering::Relaxed);
-    println!("[-] {}: {} {}",
-             truncate_message(context),
-             truncate_message(method),
-             truncate_message(url)
-    );
+    hooks::count_block();
+    event_log::record(Decision::Blocked, context, method, url);
+    if log_enabled(source, Level::Info) {
+        write_decision(source, format!("[-] [{}] {}: {} {}",
+                 role_tag(),
+                 sanitize(context),
+                 sanitize(method),
+                 sanitize(url)
+        ), "blocked");
+    }
 }
 
-pub fn log_info(message: &str) {
-    println!("[*] {}", truncate_message(message));
+/// Log a resolver lookup and whether it was let through
+pub fn log_resolved(function: &str, domain: &str, is_allowed: bool) {
+    if !is_allowed {
+        hooks::count_block();
+    }
+    let decision = if is_allowed { Decision::Allowed } else { Decision::Blocked };
+    event_log::record(decision, "DNS", function, domain);
+    if log_enabled(Source::Dns, Level::Info) {
+        let marker = if is_allowed { '+' } else { '-' };
+        let verb = if is_allowed { "allowed" } else { "blocked" };
+        write_decision(Source::Dns, format!("[{marker}] [{}] {}:\t\t {}", role_tag(), sanitize(function), sanitize(domain)), verb);
+    }
 }
 
-pub fn log_error(message: &str) {
-    println!("[!] Error: {}", truncate_message(message));
+pub fn log_info(source: Source, message: &str) {
+    if log_enabled(source, Level::Info) {
+        write_line(format!("[*] [{}] {}", role_tag(), sanitize(message)));
+    }
+}
+
+pub fn log_warn(source: Source, message: &str) {
+    if log_enabled(source, Level::Warn) {
+        write_line(format!("[!] [{}] Warning: {}", role_tag(), sanitize(message)));
+    }
+}
+
+pub fn log_error(source: Source, message: &str) {
+    if log_enabled(source, Level::Error) {
+        write_line(format!("[!] [{}] Error: {}", role_tag(), sanitize(message)));
+    }
 }
 
 /// Get statistics about logging activity
@@ -59,3 +294,30 @@ pub fn get_log_stats() -> (usize, usize, usize) {
         BLOCKED_COUNT.load(Ordering::Relaxed)
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_a_character_boundary() {
+        let ascii = "a".repeat(MAX_LOG_LENGTH + 10);
+        assert_eq!(truncate_message(&ascii).len(), MAX_LOG_LENGTH);
+
+        // "é" is two bytes, so byte MAX_LOG_LENGTH falls inside one
+        let accented = format!("a{}", "é".repeat(MAX_LOG_LENGTH));
+        let truncated = truncate_message(&accented);
+        assert_eq!(truncated.len(), MAX_LOG_LENGTH - 1);
+        assert!(truncated.ends_with('é'));
+        assert_eq!(truncate_message("short"), "short");
+    }
+
+    #[test]
+    fn redacts_before_truncating() {
+        // Truncation would leave 12 characters of the token, too few to match
+        let message = format!("{} {}", "a ".repeat(250), "T".repeat(40));
+        let sanitized = sanitize(&message);
+        assert!(sanitized.ends_with("***"));
+        assert!(!sanitized.contains('T'));
+    }
+}
@@ -2,4 +2,12 @@
 //!
 //! This module provides support functionality for the main hooks
 
+pub mod cidr;
+pub mod event_log;
+pub mod log_file;
+pub mod log_filter;
+pub mod log_queue;
+pub mod log_repeats;
 pub mod logging;
+pub mod process_role;
+pub mod redact;
@@ -0,0 +1,124 @@
+//! Chromium process role of the current process
+//!
+//! `LD_PRELOAD` loads the library into every process Spotify spawns: the
+//! browser process and its renderer, GPU, utility and zygote children, plus
+//! the crashpad handler. Chromium tells them apart by the `--type` switch.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProcessRole {
+    /// The main process, started without `--type`
+    Browser,
+    Renderer,
+    Gpu,
+    /// The utility process hosting Chromium's network service
+    NetworkService,
+    /// Any other utility process (audio, storage, ...)
+    Utility,
+    Zygote,
+    CrashpadHandler,
+    /// A `--type` this library does not know
+    Other,
+}
+
+impl ProcessRole {
+    /// Role described by a process's command line
+    #[must_use]
+    pub fn from_args<'a>(args: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut args = args.into_iter();
+        let program = args.next().unwrap_or_default();
+        let mut process_type = None;
+        let mut utility_sub_type = None;
+        for arg in args {
+            if let Some(value) = arg.strip_prefix("--type=") {
+                process_type = Some(value);
+            } else if let Some(value) = arg.strip_prefix("--utility-sub-type=") {
+                utility_sub_type = Some(value);
+            }
+        }
+
+        match process_type {
+            None if Path::new(program).file_name().is_some_and(|name| name == "crashpad_handler") => {
+                Self::CrashpadHandler
+            }
+            None => Self::Browser,
+            Some("renderer") => Self::Renderer,
+            Some("gpu-process") => Self::Gpu,
+            Some("utility") if utility_sub_type == Some("network.mojom.NetworkService") => Self::NetworkService,
+            Some("utility") => Self::Utility,
+            Some("zygote") => Self::Zygote,
+            Some("crashpad-handler") => Self::CrashpadHandler,
+            Some(_) => Self::Other,
+        }
+    }
+
+    /// Name used in config and log lines
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Browser => "browser",
+            Self::Renderer => "renderer",
+            Self::Gpu => "gpu",
+            Self::NetworkService => "network-service",
+            Self::Utility => "utility",
+            Self::Zygote => "zygote",
+            Self::CrashpadHandler => "crashpad-handler",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Role of this process, read once from `/proc/self/cmdline`
+///
+/// An unreadable command line counts as the browser process, which keeps
+/// every hook active.
+pub static PROCESS_ROLE: LazyLock<ProcessRole> = LazyLock::new(|| {
+    let cmdline = fs::read("/proc/self/cmdline").unwrap_or_default();
+    let cmdline = String::from_utf8_lossy(&cmdline);
+    ProcessRole::from_args(cmdline.split('\0'))
+});
+
+/// Executable of this process, from `/proc/self/exe`
+pub static EXECUTABLE: LazyLock<Option<PathBuf>> = LazyLock::new(|| fs::read_link("/proc/self/exe").ok());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(cmdline: &str) -> ProcessRole {
+        ProcessRole::from_args(cmdline.split(' '))
+    }
+
+    #[test]
+    fn detects_chromium_process_types() {
+        assert_eq!(role("/opt/spotify/spotify --force-device-scale-factor=1"), ProcessRole::Browser);
+        assert_eq!(role("/opt/spotify/spotify --type=renderer --lang=en-US"), ProcessRole::Renderer);
+        assert_eq!(role("/opt/spotify/spotify --type=gpu-process"), ProcessRole::Gpu);
+        assert_eq!(role("/opt/spotify/spotify --type=zygote --no-zygote-sandbox"), ProcessRole::Zygote);
+        assert_eq!(role("/opt/spotify/spotify --type=broker"), ProcessRole::Other);
+    }
+
+    #[test]
+    fn tells_the_network_service_from_other_utilities() {
+        assert_eq!(
+            role("/opt/spotify/spotify --type=utility --utility-sub-type=network.mojom.NetworkService"),
+            ProcessRole::NetworkService
+        );
+        assert_eq!(
+            role("/opt/spotify/spotify --type=utility --utility-sub-type=audio.mojom.AudioService"),
+            ProcessRole::Utility
+        );
+    }
+
+    #[test]
+    fn detects_the_crashpad_handler() {
+        assert_eq!(role("/opt/spotify/crashpad_handler --database=/tmp/crashes"), ProcessRole::CrashpadHandler);
+        assert_eq!(role("/opt/spotify/spotify --type=crashpad-handler"), ProcessRole::CrashpadHandler);
+    }
+}
@@ -0,0 +1,199 @@
+//! Rewriting `xpui.spa` (a zip archive) with patches applied
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::patches::{PatchError, XpuiPatch};
+
+/// Entry listing the patches applied to an archive, one per line
+pub const MARKER_ENTRY: &str = "spotify-adblock-patches.txt";
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Zip(ZipError),
+    Io(io::Error),
+    Patch(PatchError),
+    /// The archive carries the marker of a previous run
+    AlreadyPatched,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Zip(error) => write!(formatter, "invalid archive ({error})"),
+            Self::Io(error) => write!(formatter, "archive I/O ({error})"),
+            Self::Patch(error) => error.fmt(formatter),
+            Self::AlreadyPatched => formatter.write_str("archive is already patched"),
+        }
+    }
+}
+
+impl From<ZipError> for ArchiveError {
+    fn from(error: ZipError) -> Self {
+        Self::Zip(error)
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<PatchError> for ArchiveError {
+    fn from(error: PatchError) -> Self {
+        Self::Patch(error)
+    }
+}
+
+/// Marker line recorded for an applied patch
+fn marker_line(patch: &XpuiPatch) -> String {
+    format!("{} {} r{}", patch.meta.name, patch.meta.category.as_str(), patch.revision)
+}
+
+/// Apply `patches` to the archive in `original`, returning the new archive
+///
+/// Entries no patch touches are copied without recompression. Every patch
+/// must apply, otherwise nothing is returned.
+///
+/// # Errors
+///
+/// Fails on an unreadable archive, on an entry a patch cannot edit, and if
+/// `original` already carries the marker of a previous run.
+pub fn patch_archive(original: &[u8], patches: &[&XpuiPatch]) -> Result<Vec<u8>, ArchiveError> {
+    let mut archive = ZipArchive::new(Cursor::new(original))?;
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut patched_files = HashSet::new();
+
+    for index in 0..archive.len() {
+        let name = archive.by_index_raw(index)?.name().to_string();
+        if name == MARKER_ENTRY {
+            return Err(ArchiveError::AlreadyPatched);
+        }
+        let file_patches = patches.iter().filter(|patch| patch.file == name).collect::<Vec<_>>();
+        if file_patches.is_empty() {
+            writer.raw_copy_file(archive.by_index_raw(index)?)?;
+            continue;
+        }
+
+        let mut entry = archive.by_index(index)?;
+        let mut options = SimpleFileOptions::default().compression_method(entry.compression());
+        if let Some(mode) = entry.unix_mode() {
+            options = options.unix_permissions(mode);
+        }
+        let mut content = String::new();
+        if entry.read_to_string(&mut content).is_err() {
+            let patch = file_patches[0].meta.name.clone();
+            return Err(PatchError::NotText { patch, file: name }.into());
+        }
+        for patch in file_patches {
+            content = patch.apply(&content)?;
+        }
+        writer.start_file(name.clone(), options)?;
+        writer.write_all(content.as_bytes())?;
+        patched_files.insert(name);
+    }
+
+    if let Some(patch) = patches.iter().find(|patch| !patched_files.contains(&patch.file)) {
+        return Err(PatchError::MissingFile { patch: patch.meta.name.clone(), file: patch.file.clone() }.into());
+    }
+
+    let marker = patches.iter().map(|patch| marker_line(patch) + "\n").collect::<String>();
+    writer.start_file(MARKER_ENTRY, SimpleFileOptions::default())?;
+    writer.write_all(marker.as_bytes())?;
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Patches recorded in an archive's marker, or `None` for an unpatched one
+///
+/// # Errors
+///
+/// Fails on an unreadable archive.
+pub fn applied_patches(archive: &[u8]) -> Result<Option<Vec<String>>, ArchiveError> {
+    let mut archive = ZipArchive::new(Cursor::new(archive))?;
+    let mut marker = match archive.by_name(MARKER_ENTRY) {
+        Ok(marker) => marker,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let mut content = String::new();
+    marker.read_to_string(&mut content)?;
+    Ok(Some(content.lines().map(str::to_string).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use adblock_rules::{RuleCategory, RuleMeta};
+    use zip::CompressionMethod;
+
+    use super::*;
+
+    fn archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default().compression_method(CompressionMethod::Deflated))
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn read_entry(archive: &[u8], name: &str) -> String {
+        let mut archive = ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut content = String::new();
+        archive.by_name(name).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    fn patch(file: &str, find: &str, replace: &str) -> XpuiPatch {
+        XpuiPatch {
+            meta: RuleMeta { name: format!("{file}:{find}"), category: RuleCategory::Upsell, enabled: true },
+            file: file.to_string(),
+            find: find.to_string(),
+            replace: replace.to_string(),
+            revision: 2,
+            spotify_versions: None,
+        }
+    }
+
+    #[test]
+    fn patches_entries_and_records_a_marker() {
+        let original = archive(&[("xpui.js", "a=showUpgrade:!0;b=ads:!0"), ("index.html", "<html>")]);
+        let upgrade = patch("xpui.js", "showUpgrade:!0", "showUpgrade:!1");
+        let ads = patch("xpui.js", "ads:!0", "ads:!1");
+
+        let patched = patch_archive(&original, &[&upgrade, &ads]).unwrap();
+        assert_eq!(read_entry(&patched, "xpui.js"), "a=showUpgrade:!1;b=ads:!1");
+        assert_eq!(read_entry(&patched, "index.html"), "<html>");
+        assert_eq!(
+            applied_patches(&patched).unwrap().unwrap(),
+            ["xpui.js:showUpgrade:!0 upsell r2", "xpui.js:ads:!0 upsell r2"]
+        );
+        assert_eq!(applied_patches(&original).unwrap(), None);
+
+        // A patched archive is never patched again
+        assert!(matches!(patch_archive(&patched, &[]), Err(ArchiveError::AlreadyPatched)));
+    }
+
+    #[test]
+    fn fails_when_any_patch_does_not_apply() {
+        let original = archive(&[("xpui.js", "showUpgrade:!0")]);
+
+        let missing = patch("vendor.js", "x", "y");
+        assert!(matches!(
+            patch_archive(&original, &[&missing]),
+            Err(ArchiveError::Patch(PatchError::MissingFile { .. }))
+        ));
+        let stale = patch("xpui.js", "showUpgrade:!1", "y");
+        assert!(matches!(
+            patch_archive(&original, &[&stale]),
+            Err(ArchiveError::Patch(PatchError::MatchCount { count: 0, .. }))
+        ));
+    }
+}
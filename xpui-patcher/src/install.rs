@@ -0,0 +1,62 @@
+//! Locating `xpui.spa` and keeping its backup
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where Spotify packages install `Apps/xpui.spa`
+const INSTALL_DIRS: &[&str] = &[
+    "/opt/spotify",
+    "/usr/share/spotify",
+    "/usr/lib/spotify",
+    "/var/lib/flatpak/app/com.spotify.Client/current/active/files/extra/share/spotify",
+];
+
+/// Per-user installs, relative to the home directory
+const USER_INSTALL_DIRS: &[&str] = &[
+    ".local/share/spotify-launcher/install/usr/share/spotify",
+    ".local/share/flatpak/app/com.spotify.Client/current/active/files/extra/share/spotify",
+];
+
+/// The first existing `xpui.spa` among the known install locations
+#[must_use]
+pub fn find_xpui_spa() -> Option<PathBuf> {
+    #[allow(deprecated)] // std::env::home_dir() is only broken on Windows
+    let home = env::home_dir().unwrap_or_default();
+    INSTALL_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .chain(USER_INSTALL_DIRS.iter().map(|dir| home.join(dir)))
+        .map(|dir| dir.join("Apps/xpui.spa"))
+        .find(|path| path.is_file())
+}
+
+/// Backup of the unpatched archive, kept next to it
+#[must_use]
+pub fn backup_path(spa: &Path) -> PathBuf {
+    spa.with_extension("spa.bak")
+}
+
+/// Replace `path` with `contents` without ever leaving a partial file
+///
+/// # Errors
+///
+/// Fails if the temporary file cannot be written or renamed.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("spa.tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_backups_next_to_the_archive() {
+        assert_eq!(backup_path(Path::new("/opt/spotify/Apps/xpui.spa")), Path::new("/opt/spotify/Apps/xpui.spa.bak"));
+    }
+}
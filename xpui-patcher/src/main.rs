@@ -0,0 +1,198 @@
+//! Offline patcher for Spotify's `xpui.spa`
+//!
+//! Some ad UI is decided inside xpui's bundled JavaScript and can only be
+//! switched off by editing it. This tool applies the `[[xpui_patches]]`
+//! from the adblock `config.toml` to the local install, keeping the
+//! unpatched archive as `xpui.spa.bak` so the change can be undone.
+// toml itself depends on two winnow versions
+#![allow(clippy::multiple_crate_versions)]
+
+mod archive;
+mod install;
+mod patches;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use adblock_rules::config_paths;
+
+use crate::archive::{applied_patches, patch_archive};
+use crate::install::{backup_path, find_xpui_spa, write_atomically};
+use crate::patches::{PatchConfig, XpuiPatch};
+
+const USAGE: &str = "Usage: xpui-patcher [--spa PATH] [--config PATH] [--spotify-version VERSION] <apply|check|restore|status>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// Patch the archive, backing up the original first
+    Apply,
+    /// Dry run of `Apply`
+    Check,
+    /// Put the backup back in place
+    Restore,
+    /// Show which patches the installed archive carries
+    Status,
+}
+
+#[derive(Debug)]
+struct Options {
+    command: Command,
+    spa: Option<PathBuf>,
+    config: Option<PathBuf>,
+    spotify_version: Option<String>,
+}
+
+fn parse_options(mut arguments: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut command = None;
+    let mut options = Options { command: Command::Status, spa: None, config: None, spotify_version: None };
+    while let Some(argument) = arguments.next() {
+        let mut value = || arguments.next().ok_or_else(|| format!("{argument} needs a value"));
+        match argument.as_str() {
+            "--spa" => options.spa = Some(PathBuf::from(value()?)),
+            "--config" => options.config = Some(PathBuf::from(value()?)),
+            "--spotify-version" => options.spotify_version = Some(value()?),
+            "apply" => command = Some(Command::Apply),
+            "check" => command = Some(Command::Check),
+            "restore" => command = Some(Command::Restore),
+            "status" => command = Some(Command::Status),
+            _ => return Err(format!("unknown argument {argument}")),
+        }
+    }
+    options.command = command.ok_or("no command given")?;
+    Ok(options)
+}
+
+fn load_patches(config: Option<PathBuf>) -> Result<Vec<XpuiPatch>, String> {
+    let path = config
+        .or_else(|| config_paths().into_iter().find(|path| path.exists()))
+        .ok_or("no config file found")?;
+    println!("[*] Config file: {}", path.display());
+    let config = fs::read_to_string(&path).map_err(|error| format!("read config file ({error})"))?;
+    let config: PatchConfig = toml::from_str(&config).map_err(|error| format!("parse config file ({error})"))?;
+    Ok(config.xpui_patches)
+}
+
+/// Patch (or, for `check`, test patching) the archive at `spa`
+fn apply(options: &Options, spa: &Path) -> Result<(), String> {
+    let patches = load_patches(options.config.clone())?;
+    let selected = patches
+        .iter()
+        .filter(|patch| patch.applies_to(options.spotify_version.as_deref()))
+        .collect::<Vec<_>>();
+    for patch in patches.iter().filter(|patch| !patch.applies_to(options.spotify_version.as_deref())) {
+        println!("[*] Skipped: {}", patch.meta.name);
+    }
+    if selected.is_empty() {
+        println!("[*] No patches to apply");
+        return Ok(());
+    }
+
+    // An unpatched archive is the original, including after a Spotify update
+    // replaced a patched one; otherwise the backup is
+    let backup = backup_path(spa);
+    let current = fs::read(spa).map_err(|error| format!("read {} ({error})", spa.display()))?;
+    let is_patched = applied_patches(&current).map_err(|error| error.to_string())?.is_some();
+    let original = if !is_patched {
+        current
+    } else if backup.is_file() {
+        fs::read(&backup).map_err(|error| format!("read {} ({error})", backup.display()))?
+    } else {
+        return Err(format!("{} is patched but {} is missing; reinstall Spotify", spa.display(), backup.display()));
+    };
+
+    let patched_archive = patch_archive(&original, &selected).map_err(|error| error.to_string())?;
+    if options.command == Command::Check {
+        println!("[*] All {} patches apply", selected.len());
+        return Ok(());
+    }
+
+    if !is_patched {
+        fs::write(&backup, &original).map_err(|error| format!("write {} ({error})", backup.display()))?;
+    }
+    write_atomically(spa, &patched_archive).map_err(|error| format!("write {} ({error})", spa.display()))?;
+    for patch in selected {
+        println!("[*] Applied: {} ({}, r{})", patch.meta.name, patch.meta.category.as_str(), patch.revision);
+    }
+    Ok(())
+}
+
+fn restore(spa: &Path) -> Result<(), String> {
+    let backup = backup_path(spa);
+    let current = fs::read(spa).map_err(|error| format!("read {} ({error})", spa.display()))?;
+    // After a Spotify update the backup is older than the installed archive
+    if applied_patches(&current).map_err(|error| error.to_string())?.is_none() {
+        println!("[*] {} is not patched", spa.display());
+        return Ok(());
+    }
+    fs::rename(&backup, spa).map_err(|error| format!("restore {} ({error})", backup.display()))?;
+    println!("[*] Restored {}", spa.display());
+    Ok(())
+}
+
+fn status(spa: &Path) -> Result<(), String> {
+    let current = fs::read(spa).map_err(|error| format!("read {} ({error})", spa.display()))?;
+    match applied_patches(&current).map_err(|error| error.to_string())? {
+        Some(applied) => {
+            println!("[*] {} is patched:", spa.display());
+            for patch in applied {
+                println!("    {patch}");
+            }
+        }
+        None => println!("[*] {} is not patched", spa.display()),
+    }
+    let backup = backup_path(spa);
+    if backup.is_file() {
+        println!("[*] Backup: {}", backup.display());
+    }
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let spa = options.spa.clone().or_else(find_xpui_spa).ok_or("no Spotify install found; pass --spa")?;
+    match options.command {
+        Command::Apply | Command::Check => apply(options, &spa),
+        Command::Restore => restore(&spa),
+        Command::Status => status(&spa),
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("[*] Error: {error}");
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("[*] Error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Options, String> {
+        parse_options(arguments.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    fn parses_commands_and_options() {
+        let options = parse(&["--spa", "/tmp/xpui.spa", "--spotify-version", "1.2.45.454", "apply"]).unwrap();
+        assert_eq!(options.command, Command::Apply);
+        assert_eq!(options.spa, Some(PathBuf::from("/tmp/xpui.spa")));
+        assert_eq!(options.spotify_version.as_deref(), Some("1.2.45.454"));
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--spa"]).is_err());
+        assert!(parse(&["patch"]).is_err());
+    }
+}
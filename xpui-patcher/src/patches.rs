@@ -0,0 +1,135 @@
+//! Declarative find/replace patches for files inside `xpui.spa`
+
+use std::fmt;
+
+use adblock_rules::RuleMeta;
+use regex::Regex;
+use serde::Deserialize;
+
+/// One literal find/replace edit of an archive entry
+#[derive(Deserialize, Debug)]
+pub struct XpuiPatch {
+    /// Name, category and whether the patch is enabled
+    #[serde(flatten)]
+    pub meta: RuleMeta,
+    /// Archive entry the patch edits, e.g. `xpui.js`
+    pub file: String,
+    /// Text that must occur exactly once in `file`
+    pub find: String,
+    pub replace: String,
+    /// Revision of the patch, recorded in patched archives; bump it when
+    /// `find` or `replace` change
+    #[serde(default = "first_revision")]
+    pub revision: u32,
+    /// Spotify versions the patch is written for; all when absent
+    #[serde(default, with = "serde_regex")]
+    pub spotify_versions: Option<Regex>,
+}
+
+const fn first_revision() -> u32 {
+    1
+}
+
+/// The part of `config.toml` the patcher reads
+#[derive(Deserialize, Debug, Default)]
+pub struct PatchConfig {
+    #[serde(default)]
+    pub xpui_patches: Vec<XpuiPatch>,
+}
+
+/// Why a patch could not be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// `file` is not in the archive
+    MissingFile { patch: String, file: String },
+    /// `file` is not UTF-8 text
+    NotText { patch: String, file: String },
+    /// `find` occurs `count` times instead of exactly once
+    MatchCount { patch: String, file: String, count: usize },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingFile { patch, file } => write!(formatter, "patch {patch}: {file} is not in the archive"),
+            Self::NotText { patch, file } => write!(formatter, "patch {patch}: {file} is not UTF-8 text"),
+            Self::MatchCount { patch, file, count } => {
+                write!(formatter, "patch {patch}: expected one match in {file}, found {count}")
+            }
+        }
+    }
+}
+
+impl XpuiPatch {
+    /// Whether the patch should run against the given Spotify version
+    ///
+    /// Version-specific patches are skipped when the version is unknown.
+    #[must_use]
+    pub fn applies_to(&self, spotify_version: Option<&str>) -> bool {
+        self.meta.enabled
+            && self
+                .spotify_versions
+                .as_ref()
+                .is_none_or(|versions| spotify_version.is_some_and(|version| versions.is_match(version)))
+    }
+
+    /// Apply the patch to the text of its file
+    ///
+    /// # Errors
+    ///
+    /// Fails unless `find` occurs exactly once, so a patch written for a
+    /// different build never edits the wrong spot or runs twice.
+    pub fn apply(&self, content: &str) -> Result<String, PatchError> {
+        let count = content.matches(&self.find).count();
+        if count != 1 {
+            return Err(PatchError::MatchCount { patch: self.meta.name.clone(), file: self.file.clone(), count });
+        }
+        Ok(content.replacen(&self.find, &self.replace, 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adblock_rules::RuleCategory;
+
+    use super::*;
+
+    fn patch(find: &str, spotify_versions: Option<&str>) -> XpuiPatch {
+        XpuiPatch {
+            meta: RuleMeta { name: "upgrade-button".to_string(), category: RuleCategory::Upsell, enabled: true },
+            file: "xpui.js".to_string(),
+            find: find.to_string(),
+            replace: "showUpgrade:!1".to_string(),
+            revision: 1,
+            spotify_versions: spotify_versions.map(|versions| Regex::new(versions).unwrap()),
+        }
+    }
+
+    #[test]
+    fn replaces_a_single_match() {
+        let patch = patch("showUpgrade:!0", None);
+        assert_eq!(patch.apply("a,showUpgrade:!0,b").unwrap(), "a,showUpgrade:!1,b");
+    }
+
+    #[test]
+    fn rejects_missing_and_repeated_matches() {
+        let patch = patch("showUpgrade:!0", None);
+        assert_eq!(
+            patch.apply("showUpgrade:!1"),
+            Err(PatchError::MatchCount {
+                patch: "upgrade-button".to_string(),
+                file: "xpui.js".to_string(),
+                count: 0
+            })
+        );
+        assert!(matches!(patch.apply("showUpgrade:!0 showUpgrade:!0"), Err(PatchError::MatchCount { count: 2, .. })));
+    }
+
+    #[test]
+    fn skips_patches_for_other_versions() {
+        assert!(patch("x", None).applies_to(None));
+        assert!(patch("x", Some(r"^1\.2\.(4\d|5\d)\.")).applies_to(Some("1.2.45.454")));
+        assert!(!patch("x", Some(r"^1\.2\.(4\d|5\d)\.")).applies_to(Some("1.2.31.1205")));
+        assert!(!patch("x", Some(r"^1\.2\.(4\d|5\d)\.")).applies_to(None));
+    }
+}